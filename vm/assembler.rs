@@ -0,0 +1,1011 @@
+//! Text assembler for the [Machine](crate::Machine) instruction set.
+//!
+//! A source file is made of one statement per line. A statement is an
+//! optional `label:` followed by an instruction or a directive. Comments
+//! start with `;` or `#` and run until the end of the line.
+//!
+//! ```text
+//!         loadimm r1, 10        ; count down from 10
+//!         loadimm r2, 1
+//! loop:   out number r1
+//!         sub     r1, r1, r2
+//!         jnz     r1, r3, loop  ; r3 is used as a scratch register
+//!         exit
+//! ```
+//!
+//! Instructions:
+//!   - `move if rA, rB, rC`, `store rA, rB`, `load rA, rB`,
+//!     `loadimm rA, imm`, `sub rA, rB, rC`, `out rA`, `exit`,
+//!     `out number rA`
+//!   - `storeb rA, rB`, `storeh rA, rB`: store the low byte or halfword
+//!   - `loadb`, `loadbs`, `loadh`, `loadhs` taking `rA, rB`: load a byte or
+//!     halfword, zero- or sign-extended (`s` suffix)
+//!   - `in rA`, `in number rA`, `eof rA`
+//!   - `add`, `mul`, `divu`, `divs`, `modu`, `mods`, `and`, `or`, `xor`,
+//!     `shl`, `shr`, `sar`, `rol`, `ror` taking `rA, rB, rC` like `sub`,
+//!     and `not rA, rB`
+//!   - `seq`, `slt`, `sltu`, `sle`, `sleu` taking `rA, rB, rC`: rA is set to
+//!     1 if the comparison of rB and rC holds, 0 otherwise
+//!   - `bz rA, target`, `bnz rA, target`: branch if rA is (not) zero
+//!   - `call target`, `ret`, `push rA`, `pop rA`, using `r15` (`sp`) as the
+//!     stack pointer, which the program sets before using the stack
+//!   - `ei`, `di`: enable or disable interrupts, `reti`: return from an
+//!     interrupt handler
+//!   - `trap n`: call the host handler with the trap number `n` (0 to 255)
+//!   - `cas rA, rB, rC`: atomic compare-and-swap of the word at the address
+//!     in rB, expecting rA and replacing it by rC; rA receives the previous
+//!     word
+//!   - `fadd rA, rB, rC`: atomic fetch-and-add of rC to the word at the
+//!     address in rB; rA receives the previous word
+//!
+//! Pseudo-instructions (the machine has no jump instruction, jumps are
+//! writes into register 0, the IP):
+//!   - `jmp target` expands to `loadimm r0, target`
+//!   - `jnz rC, rT, target` expands to `loadimm rT, target` followed by
+//!     `move if r0, rT, rC`: jump to `target` if `rC` is not zero, using
+//!     `rT` as a scratch register
+//!
+//! Directives:
+//!   - `.org addr`: move the location counter forward to `addr`
+//!   - `.align n`: pad with zeroes up to a multiple of `n`
+//!   - `.byte`, `.half`, `.word`: emit 1, 2 or 4 byte little-endian values
+//!   - `.ascii "str"`, `.asciz "str"`: emit a string, `.asciz` adds a zero
+//!   - `.zero n` (or `.space n`): emit `n` zero bytes
+//!   - `.equ name, value`: define a constant symbol
+//!
+//! Operands are registers `r0`..`r15`, or more with [assemble_for] (`ip` is
+//! an alias for `r0`, `sp` for `r15`) or
+//! expressions made of numbers (`42`, `-3`, `0x2a`, `0b101`), characters
+//! (`'a'`, `'\n'`) and symbols combined with `+` and `-`.
+
+use std::{collections::BTreeMap, error::Error, fmt};
+
+use crate::{
+    config::MachineConfig,
+    executable::Segment,
+    instruction::{ArithOp, Instruction},
+    machine::SP,
+};
+
+/// Result of a successful assembly.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Assembled {
+    /// Memory image to give to [Machine::try_new](crate::Machine::try_new),
+    /// from address 0 to the end of the program. It is left empty for a
+    /// [sparse](MachineConfig::with_sparse_memory) configuration: load the
+    /// segments instead, for example with
+    /// [Executable::from_assembled](crate::Executable::from_assembled).
+    pub image: Vec<u8>,
+    /// Bytes of the program, in increasing address order. A new segment
+    /// starts after every gap left by `.org`.
+    pub segments: Vec<Segment>,
+    /// Address or value of every label and constant defined in the source.
+    pub symbols: BTreeMap<String, u32>,
+    /// Address and 1-based source line of every instruction, by increasing
+    /// address.
+    pub lines: Vec<(u32, usize)>,
+}
+
+/// Error reported by the assembler, with the 1-based position of the
+/// offending token in the source.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AsmError {
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+}
+
+impl AsmError {
+    fn new(line: usize, column: usize, message: impl Into<String>) -> Self {
+        AsmError {
+            line,
+            column,
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.line, self.column, self.message)
+    }
+}
+
+impl Error for AsmError {}
+
+/// Assemble `source` into a memory image for a machine with the default
+/// configuration.
+pub fn assemble(source: &str) -> Result<Assembled, AsmError> {
+    assemble_for(source, &MachineConfig::default())
+}
+
+/// Assemble `source` into a memory image for a machine described by
+/// `config`, which bounds the image size and the register operands.
+pub fn assemble_for(source: &str, config: &MachineConfig) -> Result<Assembled, AsmError> {
+    let mut statements = Vec::new();
+    let mut symbols = BTreeMap::new();
+    // Kept on 64 bits: a program may end right at the end of a 4 GiB
+    // address space.
+    let mut pc: u64 = 0;
+
+    // First pass: parse every line, compute the size of every statement
+    // and the address of every label.
+    for (idx, text) in source.lines().enumerate() {
+        let line = idx + 1;
+        let tokens = tokenize(text, line)?;
+        let mut parser = Parser {
+            tokens,
+            pos: 0,
+            line,
+            registers: config.registers(),
+        };
+        while let Some((name, col)) = parser.label() {
+            let addr = u32::try_from(pc).map_err(|_| {
+                AsmError::new(
+                    line,
+                    col,
+                    format!("label `{name}` is past the end of the address space"),
+                )
+            })?;
+            define(&mut symbols, name, addr, line, col)?;
+        }
+        if parser.at_end() {
+            continue;
+        }
+        let stmt = parser.statement(&symbols)?;
+        if let Kind::Equ(name, value) = &stmt.kind {
+            define(&mut symbols, name.clone(), *value, line, stmt.column)?;
+            continue;
+        }
+        let size = match &stmt.kind {
+            Kind::Org(addr) if (*addr as u64) < pc => {
+                return Err(AsmError::new(
+                    line,
+                    stmt.column,
+                    format!(".org {addr:#x} is behind the current address {pc:#x}"),
+                ))
+            }
+            Kind::Org(addr) => *addr as u64 - pc,
+            Kind::Align(n) => (*n as u64 - pc % *n as u64) % *n as u64,
+            _ => stmt.size() as u64,
+        };
+        let end = pc + size;
+        if end > config.memory_size() {
+            return Err(AsmError::new(
+                line,
+                stmt.column,
+                format!(
+                    "program does not fit in the {} bytes of memory",
+                    config.memory_size()
+                ),
+            ));
+        }
+        // The memory ends at 4 GiB at most, so the address fits.
+        statements.push((pc as u32, stmt));
+        pc = end;
+    }
+
+    // Second pass: every symbol is known, encode the statements. `.org`
+    // gaps start a new segment, so that a sparse address space is never
+    // filled.
+    let mut segments: Vec<Segment> = Vec::new();
+    let mut lines = Vec::new();
+    for (addr, stmt) in &statements {
+        let bytes = stmt.encode(*addr, &symbols)?;
+        if bytes.is_empty() {
+            continue;
+        }
+        if stmt.is_instruction() {
+            // Pseudo-instructions expand to several instructions sharing a
+            // line.
+            let mut offset = 0;
+            while offset < bytes.len() {
+                let pc = *addr + offset as u32;
+                lines.push((pc, stmt.line));
+                offset += Instruction::decode_at(&bytes[offset..], pc, config.registers())
+                    .map_or(bytes.len() - offset, |(_, size)| size);
+            }
+        }
+        match segments.last_mut() {
+            Some(last) if last.addr as u64 + last.data.len() as u64 == *addr as u64 => {
+                last.data.extend_from_slice(&bytes)
+            }
+            _ => segments.push(Segment {
+                addr: *addr,
+                data: bytes,
+            }),
+        }
+    }
+    let mut image = Vec::new();
+    if !config.is_sparse() {
+        image = vec![0; pc as usize];
+        for segment in &segments {
+            let start = segment.addr as usize;
+            image[start..start + segment.data.len()].copy_from_slice(&segment.data);
+        }
+    }
+    Ok(Assembled {
+        image,
+        segments,
+        symbols,
+        lines,
+    })
+}
+
+fn define(
+    symbols: &mut BTreeMap<String, u32>,
+    name: String,
+    value: u32,
+    line: usize,
+    column: usize,
+) -> Result<(), AsmError> {
+    if symbols.contains_key(&name) {
+        return Err(AsmError::new(
+            line,
+            column,
+            format!("symbol `{name}` is already defined"),
+        ));
+    }
+    symbols.insert(name, value);
+    Ok(())
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Tok {
+    Ident(String),
+    Number(i64),
+    Str(Vec<u8>),
+    Comma,
+    Colon,
+    Plus,
+    Minus,
+}
+
+fn tokenize(text: &str, line: usize) -> Result<Vec<(Tok, usize)>, AsmError> {
+    let chars: Vec<char> = text.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        let col = i + 1;
+        match c {
+            ';' | '#' => break,
+            c if c.is_whitespace() => i += 1,
+            ',' => {
+                tokens.push((Tok::Comma, col));
+                i += 1;
+            }
+            ':' => {
+                tokens.push((Tok::Colon, col));
+                i += 1;
+            }
+            '+' => {
+                tokens.push((Tok::Plus, col));
+                i += 1;
+            }
+            '-' => {
+                tokens.push((Tok::Minus, col));
+                i += 1;
+            }
+            '\'' => {
+                let (value, next) = escaped_char(&chars, i + 1, line)?;
+                if chars.get(next) != Some(&'\'') {
+                    return Err(AsmError::new(line, col, "unterminated character literal"));
+                }
+                tokens.push((Tok::Number(value as i64), col));
+                i = next + 1;
+            }
+            '"' => {
+                let mut bytes = Vec::new();
+                let mut j = i + 1;
+                loop {
+                    match chars.get(j) {
+                        None => {
+                            return Err(AsmError::new(line, col, "unterminated string literal"))
+                        }
+                        Some('"') => break,
+                        Some(_) => {
+                            let (value, next) = escaped_char(&chars, j, line)?;
+                            let mut buf = [0; 4];
+                            bytes.extend_from_slice(value.encode_utf8(&mut buf).as_bytes());
+                            j = next;
+                        }
+                    }
+                }
+                tokens.push((Tok::Str(bytes), col));
+                i = j + 1;
+            }
+            c if c.is_ascii_digit() => {
+                let start = i;
+                while i < chars.len() && (chars[i].is_ascii_alphanumeric() || chars[i] == '_') {
+                    i += 1;
+                }
+                let word: String = chars[start..i].iter().filter(|&&c| c != '_').collect();
+                let parsed = if let Some(hex) = word.strip_prefix("0x") {
+                    i64::from_str_radix(hex, 16)
+                } else if let Some(bin) = word.strip_prefix("0b") {
+                    i64::from_str_radix(bin, 2)
+                } else {
+                    word.parse()
+                };
+                let value = parsed
+                    .map_err(|_| AsmError::new(line, col, format!("invalid number `{word}`")))?;
+                tokens.push((Tok::Number(value), col));
+            }
+            c if c.is_alphabetic() || c == '_' || c == '.' => {
+                let start = i;
+                while i < chars.len()
+                    && (chars[i].is_alphanumeric() || chars[i] == '_' || chars[i] == '.')
+                {
+                    i += 1;
+                }
+                tokens.push((Tok::Ident(chars[start..i].iter().collect()), col));
+            }
+            c => {
+                return Err(AsmError::new(
+                    line,
+                    col,
+                    format!("unexpected character `{c}`"),
+                ))
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+/// Decode one, possibly escaped, character starting at `chars[i]`. Returns
+/// the character and the index following it.
+fn escaped_char(chars: &[char], i: usize, line: usize) -> Result<(char, usize), AsmError> {
+    match chars.get(i) {
+        None => Err(AsmError::new(line, i + 1, "unexpected end of line")),
+        Some('\\') => {
+            let c = match chars.get(i + 1) {
+                Some('n') => '\n',
+                Some('t') => '\t',
+                Some('r') => '\r',
+                Some('0') => '\0',
+                Some('\\') => '\\',
+                Some('\'') => '\'',
+                Some('"') => '"',
+                Some(c) => {
+                    return Err(AsmError::new(
+                        line,
+                        i + 1,
+                        format!("unknown escape `\\{c}`"),
+                    ))
+                }
+                None => return Err(AsmError::new(line, i + 1, "unexpected end of line")),
+            };
+            Ok((c, i + 2))
+        }
+        Some(&c) => Ok((c, i + 1)),
+    }
+}
+
+/// An expression operand, evaluated once every symbol is known.
+#[derive(Debug, Clone)]
+struct Expr {
+    terms: Vec<(bool, Term)>,
+    column: usize,
+}
+
+#[derive(Debug, Clone)]
+enum Term {
+    Number(i64),
+    Symbol(String, usize),
+}
+
+impl Expr {
+    fn eval(&self, symbols: &BTreeMap<String, u32>, line: usize) -> Result<i64, AsmError> {
+        let mut value: i64 = 0;
+        for (negate, term) in &self.terms {
+            let v = match term {
+                Term::Number(n) => *n,
+                Term::Symbol(name, col) => match symbols.get(name) {
+                    Some(&v) => v as i64,
+                    None => {
+                        return Err(AsmError::new(
+                            line,
+                            *col,
+                            format!("undefined symbol `{name}`"),
+                        ))
+                    }
+                },
+            };
+            value = if *negate {
+                value.wrapping_sub(v)
+            } else {
+                value.wrapping_add(v)
+            };
+        }
+        Ok(value)
+    }
+
+    /// Evaluate the expression and check that it lies in `min..=max`.
+    fn eval_in(
+        &self,
+        symbols: &BTreeMap<String, u32>,
+        line: usize,
+        min: i64,
+        max: i64,
+        what: &str,
+    ) -> Result<i64, AsmError> {
+        let value = self.eval(symbols, line)?;
+        if value < min || value > max {
+            return Err(AsmError::new(
+                line,
+                self.column,
+                format!("{what} {value} is out of range [{min}, {max}]"),
+            ));
+        }
+        Ok(value)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Stmt {
+    kind: Kind,
+    line: usize,
+    column: usize,
+}
+
+#[derive(Debug, Clone)]
+enum Kind {
+    Instr(Instruction),
+    LoadImm(u8, Expr),
+    /// `bnz` if `true`, `bz` otherwise.
+    Branch(bool, u8, Expr),
+    Call(Expr),
+    Trap(Expr),
+    Jmp(Expr),
+    Jnz(u8, u8, Expr),
+    Org(u32),
+    Align(u32),
+    Data(usize, Vec<Expr>),
+    Bytes(Vec<u8>),
+    Zero(u32),
+    Equ(String, u32),
+}
+
+/// Range of values accepted by the signed 16-bit immediate of `loadimm`.
+const IMM_MIN: i64 = i16::MIN as i64;
+const IMM_MAX: i64 = i16::MAX as i64;
+
+impl Stmt {
+    /// `true` for instructions and pseudo-instructions, `false` for
+    /// directives.
+    fn is_instruction(&self) -> bool {
+        matches!(
+            self.kind,
+            Kind::Instr(_)
+                | Kind::LoadImm(..)
+                | Kind::Branch(..)
+                | Kind::Call(_)
+                | Kind::Trap(_)
+                | Kind::Jmp(_)
+                | Kind::Jnz(..)
+        )
+    }
+
+    /// Offset from the end of this statement, located at `addr`, to the
+    /// target address `e`, as encoded by relative jumps.
+    fn offset(
+        &self,
+        e: &Expr,
+        addr: u32,
+        symbols: &BTreeMap<String, u32>,
+    ) -> Result<i16, AsmError> {
+        let target = e.eval(symbols, self.line)?;
+        let offset = target - (addr as i64 + self.size() as i64);
+        i16::try_from(offset).map_err(|_| {
+            AsmError::new(
+                self.line,
+                e.column,
+                format!("jump target {target:#x} is too far ({offset} bytes)"),
+            )
+        })
+    }
+
+    fn size(&self) -> u32 {
+        match &self.kind {
+            Kind::Instr(instr) => instr.size() as u32,
+            Kind::LoadImm(..) | Kind::Jmp(_) | Kind::Branch(..) => 4,
+            Kind::Call(_) => 3,
+            Kind::Trap(_) => 2,
+            Kind::Jnz(..) => 8,
+            Kind::Data(width, values) => (width * values.len()) as u32,
+            Kind::Bytes(bytes) => bytes.len() as u32,
+            Kind::Zero(n) => *n,
+            Kind::Org(_) | Kind::Align(_) | Kind::Equ(..) => 0,
+        }
+    }
+
+    fn encode(&self, addr: u32, symbols: &BTreeMap<String, u32>) -> Result<Vec<u8>, AsmError> {
+        let line = self.line;
+        let imm = |e: &Expr| -> Result<i16, AsmError> {
+            Ok(e.eval_in(symbols, line, IMM_MIN, IMM_MAX, "immediate")? as i16)
+        };
+        Ok(match &self.kind {
+            Kind::Instr(instr) => instr.encode(),
+            Kind::LoadImm(a, e) => Instruction::LoadImm(*a, imm(e)?).encode(),
+            Kind::Jmp(e) => Instruction::LoadImm(0, imm(e)?).encode(),
+            Kind::Branch(nonzero, a, e) => {
+                let offset = self.offset(e, addr, symbols)?;
+                if *nonzero {
+                    Instruction::Bnz(*a, offset).encode()
+                } else {
+                    Instruction::Bz(*a, offset).encode()
+                }
+            }
+            Kind::Call(e) => Instruction::Call(self.offset(e, addr, symbols)?).encode(),
+            Kind::Trap(e) => {
+                Instruction::Trap(e.eval_in(symbols, line, 0, 255, "trap number")? as u8).encode()
+            }
+            Kind::Jnz(c, t, e) => {
+                let mut bytes = Instruction::LoadImm(*t, imm(e)?).encode();
+                bytes.extend(Instruction::MoveIf(0, *t, *c).encode());
+                bytes
+            }
+            Kind::Data(width, values) => {
+                let (min, max) = match width {
+                    1 => (i8::MIN as i64, u8::MAX as i64),
+                    2 => (i16::MIN as i64, u16::MAX as i64),
+                    _ => (i32::MIN as i64, u32::MAX as i64),
+                };
+                let mut bytes = Vec::with_capacity(width * values.len());
+                for e in values {
+                    let v = e.eval_in(symbols, line, min, max, "value")?;
+                    bytes.extend_from_slice(&(v as u32).to_le_bytes()[..*width]);
+                }
+                bytes
+            }
+            Kind::Bytes(bytes) => bytes.clone(),
+            Kind::Zero(n) => vec![0; *n as usize],
+            Kind::Org(_) | Kind::Align(_) | Kind::Equ(..) => Vec::new(),
+        })
+    }
+}
+
+struct Parser {
+    tokens: Vec<(Tok, usize)>,
+    pos: usize,
+    line: usize,
+    /// Number of registers of the target machine.
+    registers: usize,
+}
+
+impl Parser {
+    fn at_end(&self) -> bool {
+        self.pos >= self.tokens.len()
+    }
+
+    fn peek(&self) -> Option<&Tok> {
+        self.tokens.get(self.pos).map(|(t, _)| t)
+    }
+
+    /// Column of the current token, or just past the last one.
+    fn column(&self) -> usize {
+        match self.tokens.get(self.pos) {
+            Some((_, col)) => *col,
+            None => self.tokens.last().map_or(1, |(_, col)| col + 1),
+        }
+    }
+
+    fn error(&self, message: impl Into<String>) -> AsmError {
+        AsmError::new(self.line, self.column(), message)
+    }
+
+    /// Consume a `name:` label definition if there is one.
+    fn label(&mut self) -> Option<(String, usize)> {
+        match (self.tokens.get(self.pos), self.tokens.get(self.pos + 1)) {
+            (Some((Tok::Ident(name), col)), Some((Tok::Colon, _))) => {
+                let label = (name.clone(), *col);
+                self.pos += 2;
+                Some(label)
+            }
+            _ => None,
+        }
+    }
+
+    fn ident(&mut self) -> Result<String, AsmError> {
+        match self.peek() {
+            Some(Tok::Ident(name)) => {
+                let name = name.clone();
+                self.pos += 1;
+                Ok(name)
+            }
+            _ => Err(self.error("expected an identifier")),
+        }
+    }
+
+    fn eat_ident(&mut self, word: &str) -> bool {
+        if matches!(self.peek(), Some(Tok::Ident(w)) if w.eq_ignore_ascii_case(word)) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn comma(&mut self) -> Result<(), AsmError> {
+        match self.peek() {
+            Some(Tok::Comma) => {
+                self.pos += 1;
+                Ok(())
+            }
+            _ => Err(self.error("expected `,`")),
+        }
+    }
+
+    fn reg(&mut self) -> Result<u8, AsmError> {
+        let col = self.column();
+        let name = self
+            .ident()
+            .map_err(|_| self.error("expected a register"))?;
+        parse_reg(&name, self.registers).ok_or_else(|| {
+            AsmError::new(
+                self.line,
+                col,
+                format!("`{name}` is not a register (r0..r{})", self.registers - 1),
+            )
+        })
+    }
+
+    fn two_regs(&mut self) -> Result<(u8, u8), AsmError> {
+        let a = self.reg()?;
+        self.comma()?;
+        Ok((a, self.reg()?))
+    }
+
+    fn three_regs(&mut self) -> Result<(u8, u8, u8), AsmError> {
+        let (a, b) = self.two_regs()?;
+        self.comma()?;
+        Ok((a, b, self.reg()?))
+    }
+
+    fn expr(&mut self) -> Result<Expr, AsmError> {
+        let column = self.column();
+        let mut terms = Vec::new();
+        let mut negate = false;
+        loop {
+            if matches!(self.peek(), Some(Tok::Minus)) {
+                negate = !negate;
+                self.pos += 1;
+                continue;
+            }
+            let col = self.column();
+            let term = match self.peek() {
+                Some(Tok::Number(n)) => Term::Number(*n),
+                Some(Tok::Ident(name)) if parse_reg(name, self.registers).is_none() => {
+                    Term::Symbol(name.clone(), col)
+                }
+                _ => return Err(self.error("expected a number or a symbol")),
+            };
+            self.pos += 1;
+            terms.push((negate, term));
+            negate = match self.peek() {
+                Some(Tok::Plus) => false,
+                Some(Tok::Minus) => true,
+                _ => break,
+            };
+            self.pos += 1;
+        }
+        Ok(Expr { terms, column })
+    }
+
+    fn expr_list(&mut self) -> Result<Vec<Expr>, AsmError> {
+        let mut values = vec![self.expr()?];
+        while matches!(self.peek(), Some(Tok::Comma)) {
+            self.pos += 1;
+            values.push(self.expr()?);
+        }
+        Ok(values)
+    }
+
+    /// Parse an expression that must be computable right away, because it
+    /// changes the layout of the program.
+    fn const_expr(&mut self, symbols: &BTreeMap<String, u32>) -> Result<u32, AsmError> {
+        let e = self.expr()?;
+        let v = e.eval(symbols, self.line)?;
+        u32::try_from(v)
+            .map_err(|_| AsmError::new(self.line, e.column, format!("{v} must not be negative")))
+    }
+
+    fn string(&mut self) -> Result<Vec<u8>, AsmError> {
+        match self.peek() {
+            Some(Tok::Str(bytes)) => {
+                let bytes = bytes.clone();
+                self.pos += 1;
+                Ok(bytes)
+            }
+            _ => Err(self.error("expected a string literal")),
+        }
+    }
+
+    fn statement(&mut self, symbols: &BTreeMap<String, u32>) -> Result<Stmt, AsmError> {
+        let column = self.column();
+        let mnemonic = self.ident()?;
+        let lower = mnemonic.to_ascii_lowercase();
+        let kind = match lower.as_str() {
+            "move" if self.eat_ident("if") => {
+                let (a, b, c) = self.three_regs()?;
+                Kind::Instr(Instruction::MoveIf(a, b, c))
+            }
+            "store" => {
+                let (a, b) = self.two_regs()?;
+                Kind::Instr(Instruction::Store(a, b))
+            }
+            "load" => {
+                let (a, b) = self.two_regs()?;
+                Kind::Instr(Instruction::Load(a, b))
+            }
+            "storeb" | "storeh" | "loadb" | "loadbs" | "loadh" | "loadhs" => {
+                let (a, b) = self.two_regs()?;
+                Kind::Instr(match lower.as_str() {
+                    "storeb" => Instruction::StoreB(a, b),
+                    "storeh" => Instruction::StoreH(a, b),
+                    "loadb" => Instruction::LoadB(a, b),
+                    "loadbs" => Instruction::LoadBs(a, b),
+                    "loadh" => Instruction::LoadH(a, b),
+                    _ => Instruction::LoadHs(a, b),
+                })
+            }
+            "loadimm" => {
+                let a = self.reg()?;
+                self.comma()?;
+                Kind::LoadImm(a, self.expr()?)
+            }
+            "sub" => {
+                let (a, b, c) = self.three_regs()?;
+                Kind::Instr(Instruction::Sub(a, b, c))
+            }
+            "cas" => {
+                let (a, b, c) = self.three_regs()?;
+                Kind::Instr(Instruction::Cas(a, b, c))
+            }
+            "fadd" => {
+                let (a, b, c) = self.three_regs()?;
+                Kind::Instr(Instruction::Fadd(a, b, c))
+            }
+            "out" if self.eat_ident("number") => Kind::Instr(Instruction::OutNumber(self.reg()?)),
+            "out" => Kind::Instr(Instruction::Out(self.reg()?)),
+            "exit" => Kind::Instr(Instruction::Exit),
+            "in" if self.eat_ident("number") => Kind::Instr(Instruction::InNumber(self.reg()?)),
+            "in" => Kind::Instr(Instruction::In(self.reg()?)),
+            "eof" => Kind::Instr(Instruction::Eof(self.reg()?)),
+            "bz" | "bnz" => {
+                let a = self.reg()?;
+                self.comma()?;
+                Kind::Branch(lower == "bnz", a, self.expr()?)
+            }
+            "call" => Kind::Call(self.expr()?),
+            "ret" => Kind::Instr(Instruction::Ret),
+            "ei" => Kind::Instr(Instruction::Ei),
+            "di" => Kind::Instr(Instruction::Di),
+            "reti" => Kind::Instr(Instruction::Reti),
+            "trap" => Kind::Trap(self.expr()?),
+            "push" => Kind::Instr(Instruction::Push(self.reg()?)),
+            "pop" => Kind::Instr(Instruction::Pop(self.reg()?)),
+            "not" => {
+                let (a, b) = self.two_regs()?;
+                Kind::Instr(Instruction::Not(a, b))
+            }
+            "jmp" => Kind::Jmp(self.expr()?),
+            "jnz" => {
+                let c = self.reg()?;
+                self.comma()?;
+                let t = self.reg()?;
+                self.comma()?;
+                Kind::Jnz(c, t, self.expr()?)
+            }
+            ".org" => Kind::Org(self.const_expr(symbols)?),
+            ".align" => {
+                let col = self.column();
+                match self.const_expr(symbols)? {
+                    0 => return Err(AsmError::new(self.line, col, "alignment must not be 0")),
+                    n => Kind::Align(n),
+                }
+            }
+            ".byte" => Kind::Data(1, self.expr_list()?),
+            ".half" => Kind::Data(2, self.expr_list()?),
+            ".word" => Kind::Data(4, self.expr_list()?),
+            ".ascii" => Kind::Bytes(self.string()?),
+            ".asciz" => {
+                let mut bytes = self.string()?;
+                bytes.push(0);
+                Kind::Bytes(bytes)
+            }
+            ".zero" | ".space" => Kind::Zero(self.const_expr(symbols)?),
+            ".equ" => {
+                let name = self.ident()?;
+                self.comma()?;
+                Kind::Equ(name, self.const_expr(symbols)?)
+            }
+            other => match ArithOp::from_mnemonic(other) {
+                Some(op) => {
+                    let (a, b, c) = self.three_regs()?;
+                    Kind::Instr(Instruction::Arith(op, a, b, c))
+                }
+                None => {
+                    return Err(AsmError::new(
+                        self.line,
+                        column,
+                        format!("unknown instruction or directive `{mnemonic}`"),
+                    ))
+                }
+            },
+        };
+        if !self.at_end() {
+            return Err(self.error("unexpected token after the end of the statement"));
+        }
+        Ok(Stmt {
+            kind,
+            line: self.line,
+            column,
+        })
+    }
+}
+
+/// Parse a register name: `r0` to the last of the `registers` registers,
+/// `ip` for `r0` or `sp` for `r15`.
+fn parse_reg(name: &str, registers: usize) -> Option<u8> {
+    let name = name.to_ascii_lowercase();
+    match name.as_str() {
+        "ip" => return Some(0),
+        "sp" => return Some(SP as u8),
+        _ => {}
+    }
+    let n: usize = name.strip_prefix('r')?.parse().ok()?;
+    (n < registers).then_some(n as u8)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn words(source: &str) -> Vec<u8> {
+        assemble(source).unwrap().image
+    }
+
+    #[test]
+    fn move_if_is_case_insensitive() {
+        let expected = Instruction::MoveIf(1, 2, 3).encode();
+        assert_eq!(words("move if r1, r2, r3"), expected);
+        assert_eq!(words("MOVE IF r1, r2, r3"), expected);
+        assert_eq!(words("Move If R1, R2, R3"), expected);
+        assert_eq!(words("OUT NUMBER r4"), Instruction::OutNumber(4).encode());
+        assert_eq!(words("In Number r4"), Instruction::InNumber(4).encode());
+    }
+
+    #[test]
+    fn labels_and_jumps() {
+        let assembled = assemble(
+            "start:  loadimm r1, end\n\
+             \x20       jnz r1, r2, start\n\
+             \x20       jmp end\n\
+             end:    exit",
+        )
+        .unwrap();
+        let end = assembled.symbols["end"];
+        assert_eq!(end, 16);
+        let mut expected = Instruction::LoadImm(1, end as i16).encode();
+        expected.extend(Instruction::LoadImm(2, 0).encode());
+        expected.extend(Instruction::MoveIf(0, 2, 1).encode());
+        expected.extend(Instruction::LoadImm(0, end as i16).encode());
+        expected.extend(Instruction::Exit.encode());
+        assert_eq!(assembled.image, expected);
+        // Both instructions of `jnz` belong to line 2.
+        assert_eq!(assembled.lines, [(0, 1), (4, 2), (8, 2), (12, 3), (16, 4)]);
+    }
+
+    #[test]
+    fn relative_branches() {
+        let image = words("top: bnz r1, top\nbz r2, next\nnext: call top");
+        assert_eq!(&image[..4], Instruction::Bnz(1, -4).encode());
+        assert_eq!(&image[4..8], Instruction::Bz(2, 0).encode());
+        assert_eq!(&image[8..], Instruction::Call(-11).encode());
+    }
+
+    #[test]
+    fn literals_and_directives() {
+        let image = words(
+            ".equ BASE, 0x10\n\
+             loadimm r1, 'a'\n\
+             loadimm r2, -0b11 + BASE\n\
+             .byte 1, '\\n'\n\
+             .align 4\n\
+             .half 0x1234\n\
+             .word -1\n\
+             .ascii \"hi\"\n\
+             .asciz \"\"\n\
+             .zero 2",
+        );
+        let mut expected = Instruction::LoadImm(1, 'a' as i16).encode();
+        expected.extend(Instruction::LoadImm(2, 13).encode());
+        expected.extend([1, b'\n', 0, 0]);
+        expected.extend([0x34, 0x12]);
+        expected.extend([0xff; 4]);
+        expected.extend(b"hi\0");
+        expected.extend([0, 0]);
+        assert_eq!(image, expected);
+    }
+
+    #[test]
+    fn org_moves_forward() {
+        let image = words("exit\n.org 8\nexit");
+        assert_eq!(image, [7, 0, 0, 0, 0, 0, 0, 0, 7]);
+    }
+
+    fn error(source: &str) -> (usize, usize, String) {
+        let e = assemble(source).unwrap_err();
+        (e.line, e.column, e.message)
+    }
+
+    #[test]
+    fn errors_have_positions() {
+        let (line, column, message) = error("exit\n  frobnicate r1");
+        assert_eq!((line, column), (2, 3));
+        assert!(message.contains("frobnicate"));
+        assert_eq!(error("loadimm r16, 1").1, 9);
+        assert_eq!(error("loadimm r1, 40000").1, 13);
+        assert_eq!(error("jmp nowhere").1, 5);
+        assert_eq!(error("a: exit\na: exit").0, 2);
+        assert_eq!(error("exit r1").1, 6);
+        assert_eq!(error(".org 4\n.org 2").0, 2);
+        assert_eq!(error(".ascii \"open").1, 8);
+    }
+
+    #[test]
+    fn program_must_fit_in_memory() {
+        let config = MachineConfig::default().with_memory_size(8).unwrap();
+        assert!(assemble_for(".zero 8", &config).is_ok());
+        let e = assemble_for(".zero 8\nexit", &config).unwrap_err();
+        assert_eq!((e.line, e.column), (2, 1));
+    }
+
+    #[test]
+    fn sparse_programs_are_segments() {
+        let config = MachineConfig::new()
+            .with_memory_size(1 << 32)
+            .unwrap()
+            .with_sparse_memory(true);
+        let assembled = assemble_for(
+            "exit\n.org 0xf0000000\nhandler: exit\n.org 0xfffffffe\n.byte 1, 2",
+            &config,
+        )
+        .unwrap();
+        assert!(assembled.image.is_empty());
+        assert_eq!(
+            assembled.segments,
+            [
+                Segment {
+                    addr: 0,
+                    data: vec![7]
+                },
+                Segment {
+                    addr: 0xf000_0000,
+                    data: vec![7]
+                },
+                Segment {
+                    addr: 0xffff_fffe,
+                    data: vec![1, 2]
+                },
+            ]
+        );
+        assert_eq!(assembled.symbols["handler"], 0xf000_0000);
+        assert_eq!(assembled.lines, [(0, 1), (0xf000_0000, 3)]);
+
+        let error = assemble_for(".org 0xffffffff\n.byte 1\nend: exit", &config).unwrap_err();
+        assert_eq!((error.line, error.column), (3, 1));
+        let error = assemble_for(".org 0xffffffff\n.byte 1, 2", &config).unwrap_err();
+        assert_eq!(error.line, 2);
+    }
+
+    #[test]
+    fn register_count_comes_from_the_config() {
+        let config = MachineConfig::default().with_registers(32).unwrap();
+        let image = assemble_for("loadimm r20, 1", &config).unwrap().image;
+        assert_eq!(image, Instruction::LoadImm(20, 1).encode());
+        assert!(assemble("loadimm r20, 1").is_err());
+    }
+}
@@ -0,0 +1,61 @@
+use std::{fs, path::PathBuf, process::exit};
+
+use clap::Parser;
+use vm::Executable;
+
+#[derive(Parser, Debug)]
+#[clap(version = "0.1", about = "Assemble a program for the vm machine")]
+struct Args {
+    /// The assembler source file
+    input: PathBuf,
+    /// The file receiving the memory image (defaults to the input with a .bin
+    /// extension, or .vmx with --executable)
+    #[clap(short = 'o', long = "output")]
+    output: Option<PathBuf>,
+    /// Write an executable with the entry point, symbols and line table
+    /// instead of a raw memory image
+    #[clap(short = 'x', long = "executable")]
+    executable: bool,
+    /// Print the address of every symbol
+    #[clap(short = 's', long = "symbols")]
+    symbols: bool,
+}
+
+fn main() {
+    let args = Args::parse();
+    let source = match fs::read_to_string(&args.input) {
+        Ok(source) => source,
+        Err(e) => {
+            eprintln!("{}: {e}", args.input.display());
+            exit(1);
+        }
+    };
+    let assembled = match vm::assemble(&source) {
+        Ok(assembled) => assembled,
+        Err(e) => {
+            eprintln!("{}:{e}", args.input.display());
+            if let Some(text) = e.line.checked_sub(1).and_then(|i| source.lines().nth(i)) {
+                eprintln!("    {text}");
+                eprintln!("    {:>1$}", "^", e.column);
+            }
+            exit(1);
+        }
+    };
+    if args.symbols {
+        for (name, value) in &assembled.symbols {
+            println!("{value:#06x} {name}");
+        }
+    }
+    let (extension, bytes) = if args.executable {
+        ("vmx", Executable::from_assembled(&assembled).to_bytes())
+    } else {
+        ("bin", assembled.image)
+    };
+    let output = args
+        .output
+        .unwrap_or_else(|| args.input.with_extension(extension));
+    if let Err(e) = fs::write(&output, bytes) {
+        eprintln!("{}: {e}", output.display());
+        exit(1);
+    }
+}
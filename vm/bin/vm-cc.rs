@@ -0,0 +1,60 @@
+use std::{fs, path::PathBuf, process::exit};
+
+use clap::Parser;
+use vm::{Executable, MachineConfig};
+
+#[derive(Parser, Debug)]
+#[clap(version = "0.1", about = "Compile a program for the vm machine")]
+struct Args {
+    /// The source file
+    input: PathBuf,
+    /// The file receiving the memory image (defaults to the input with a .bin
+    /// extension, .vmx with --executable or .s with --assembly)
+    #[clap(short = 'o', long = "output")]
+    output: Option<PathBuf>,
+    /// Write an executable with the entry point and symbols instead of a raw
+    /// memory image, which also keeps the stack from overwriting the program
+    #[clap(short = 'x', long = "executable")]
+    executable: bool,
+    /// Write the generated assembler source instead of a memory image
+    #[clap(short = 'S', long = "assembly")]
+    assembly: bool,
+}
+
+fn main() {
+    let args = Args::parse();
+    let source = match fs::read_to_string(&args.input) {
+        Ok(source) => source,
+        Err(e) => {
+            eprintln!("{}: {e}", args.input.display());
+            exit(1);
+        }
+    };
+    let config = MachineConfig::default();
+    let result = if args.assembly {
+        vm::compile_to_assembly(&source, &config).map(|text| ("s", text.into_bytes()))
+    } else if args.executable {
+        vm::compile_for(&source, &config)
+            .map(|assembled| ("vmx", Executable::from_assembled(&assembled).to_bytes()))
+    } else {
+        vm::compile_for(&source, &config).map(|assembled| ("bin", assembled.image))
+    };
+    let (extension, bytes) = match result {
+        Ok(output) => output,
+        Err(e) => {
+            eprintln!("{}:{e}", args.input.display());
+            if let Some(text) = e.line.checked_sub(1).and_then(|i| source.lines().nth(i)) {
+                eprintln!("    {text}");
+                eprintln!("    {:>1$}", "^", e.column);
+            }
+            exit(1);
+        }
+    };
+    let output = args
+        .output
+        .unwrap_or_else(|| args.input.with_extension(extension));
+    if let Err(e) = fs::write(&output, bytes) {
+        eprintln!("{}: {e}", output.display());
+        exit(1);
+    }
+}
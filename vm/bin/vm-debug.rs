@@ -0,0 +1,377 @@
+use std::{
+    collections::BTreeMap,
+    io::{self, BufRead, Write},
+    path::PathBuf,
+    process::exit,
+};
+
+use clap::Parser;
+use vm::{disassemble_for, BreakpointId, Condition, Executable, Machine};
+
+#[derive(Parser, Debug)]
+#[clap(version = "0.1", about = "Interactive debugger for vm programs")]
+struct Args {
+    /// The program to debug: an executable, a raw memory image, or an
+    /// assembler source if its extension is .s or .asm
+    program: PathBuf,
+}
+
+const HELP: &str = "\
+commands:
+  s, step [N]          execute N instructions (default 1)
+  c, continue          run until a breakpoint, watchpoint, exit or error
+  sb, back [N]         undo the last N executed instructions (default 1)
+  rc, rcontinue        run backward until an address breakpoint or the
+                       oldest recorded instruction
+  b, break LOC         set a breakpoint at address or label LOC
+  w, watch ADDR [LEN]  stop when one of LEN bytes (default 4) at ADDR is
+                       written
+  rw, rwatch ADDR [LEN]
+                       stop when one of LEN bytes at ADDR is read
+  bc, bcond REG VALUE  stop when an instruction writes VALUE into REG
+  d, delete N          delete breakpoint N
+  bl, breakpoints      list breakpoints
+  r, regs              dump registers
+  x ADDR [LEN]         hex dump LEN bytes of memory (default 64)
+  l, list [ADDR] [N]   disassemble N instructions around IP or from ADDR
+  setr REG VALUE       write a register, e.g. `setr r3 0x10`
+  setm ADDR BYTE...    write bytes in memory
+  h, help              show this help
+  q, quit              leave the debugger";
+
+struct Debugger {
+    machine: Machine,
+    symbols: BTreeMap<String, u32>,
+    terminated: bool,
+}
+
+/// Number of instructions which can be undone.
+const HISTORY_LIMIT: usize = 100_000;
+
+/// Parse a number (decimal, `0x` hexadecimal or negative) or a label.
+fn parse_value(symbols: &BTreeMap<String, u32>, text: &str) -> Result<u32, String> {
+    if let Some(&v) = symbols.get(text) {
+        return Ok(v);
+    }
+    let parsed = if let Some(hex) = text.strip_prefix("0x") {
+        u32::from_str_radix(hex, 16).ok()
+    } else if text.starts_with('-') {
+        text.parse::<i32>().ok().map(|v| v as u32)
+    } else {
+        text.parse().ok()
+    };
+    parsed.ok_or_else(|| format!("invalid number or unknown label `{text}`"))
+}
+
+impl Debugger {
+    fn register(&self, text: &str) -> Result<usize, String> {
+        let n = if text == "ip" {
+            Some(0)
+        } else {
+            text.strip_prefix('r').and_then(|n| n.parse().ok())
+        };
+        match n {
+            Some(n) if n < self.machine.regs().len() => Ok(n),
+            _ => Err(format!("invalid register `{text}`")),
+        }
+    }
+
+    /// Execute one instruction, returning `false` if the execution cannot
+    /// go on.
+    fn step(&mut self) -> bool {
+        if self.terminated {
+            println!("the program has terminated");
+            return false;
+        }
+        let result = self.machine.step_on(&mut io::stdout().lock());
+        io::stdout().flush().ok();
+        match result {
+            Ok(false) => true,
+            Ok(true) => {
+                println!("program exited");
+                self.terminated = true;
+                false
+            }
+            Err(e) => {
+                println!("fault: {e}");
+                false
+            }
+        }
+    }
+
+    /// Undo one instruction, returning `false` if the history is empty.
+    fn step_back(&mut self) -> bool {
+        if !self.machine.step_back() {
+            println!("no more history");
+            return false;
+        }
+        self.terminated = false;
+        true
+    }
+
+    fn ip(&self) -> usize {
+        self.machine.regs()[0] as usize
+    }
+
+    /// `true` if an address breakpoint is set at `addr`.
+    fn breakpoint_at(&self, addr: usize) -> bool {
+        self.machine
+            .breakpoints()
+            .any(|(_, condition)| matches!(*condition, Condition::Address(a) if a as usize == addr))
+    }
+
+    fn dump_regs(&self) {
+        for (i, r) in self.machine.regs().iter().enumerate() {
+            print!("r{i:<2} = {r:#010x} {:>11}", *r as i32);
+            println!("{}", if i % 2 == 1 { "" } else { "    " });
+        }
+    }
+
+    fn dump_mem(&self, addr: usize, len: usize) {
+        let memory = self.machine.memory();
+        let end = addr.saturating_add(len).min(memory.len());
+        for start in (addr..end).step_by(16) {
+            let chunk = &memory[start..(start + 16).min(end)];
+            let hex: Vec<String> = chunk.iter().map(|b| format!("{b:02x}")).collect();
+            let ascii: String = chunk
+                .iter()
+                .map(|&b| {
+                    if b.is_ascii_graphic() || b == b' ' {
+                        b as char
+                    } else {
+                        '.'
+                    }
+                })
+                .collect();
+            println!("{start:#06x}:  {:<48} {ascii}", hex.join(" "));
+        }
+    }
+
+    /// Find an address before `ip` from which decoding falls exactly on
+    /// `ip`, so that the listing can show instructions preceding it.
+    fn sync_before(&self, ip: usize, back: usize) -> usize {
+        let memory = self.machine.memory();
+        (ip.saturating_sub(back)..ip)
+            .find(|&start| {
+                disassemble_for(memory, start, self.machine.config())
+                    .map(|line| line.addr)
+                    .take_while(|&addr| addr <= ip)
+                    .any(|addr| addr == ip)
+            })
+            .unwrap_or(ip)
+    }
+
+    fn list(&self, from: Option<usize>, count: usize) {
+        let ip = self.ip();
+        let start = from.unwrap_or_else(|| self.sync_before(ip, 12));
+        let listing = disassemble_for(self.machine.memory(), start, self.machine.config());
+        for line in listing.take(count) {
+            let marker = match (line.addr == ip, self.breakpoint_at(line.addr)) {
+                (true, _) => "=>",
+                (false, true) => " *",
+                _ => "  ",
+            };
+            let label = self
+                .symbols
+                .iter()
+                .find(|(_, &v)| v as usize == line.addr)
+                .map(|(name, _)| format!("<{name}>"))
+                .unwrap_or_default();
+            println!("{marker} {line} {label}");
+        }
+    }
+
+    /// Run a command, returning `false` when the user wants to quit.
+    fn command(&mut self, line: &str) -> Result<bool, String> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let Some((&cmd, args)) = words.split_first() else {
+            return Ok(true);
+        };
+        let symbols = self.symbols.clone();
+        let arg = |i: usize| -> Result<u32, String> {
+            args.get(i)
+                .ok_or_else(|| format!("`{cmd}` expects more arguments"))
+                .and_then(|a| parse_value(&symbols, a))
+        };
+        match cmd {
+            "s" | "step" => {
+                let n = if args.is_empty() { 1 } else { arg(0)? };
+                for _ in 0..n {
+                    if !self.step() {
+                        break;
+                    }
+                }
+                self.list(Some(self.ip()), 1);
+            }
+            "c" | "continue" => {
+                while self.step() {
+                    if let Some(hit) = self.machine.last_break() {
+                        match hit.addr {
+                            Some(addr) => println!(
+                                "watchpoint {} hit by {:#06x} accessing {addr:#06x}",
+                                hit.id, hit.ip
+                            ),
+                            None => println!("breakpoint {} hit by {:#06x}", hit.id, hit.ip),
+                        }
+                        break;
+                    }
+                }
+                self.list(Some(self.ip()), 1);
+            }
+            "sb" | "back" => {
+                let n = if args.is_empty() { 1 } else { arg(0)? };
+                for _ in 0..n {
+                    if !self.step_back() {
+                        break;
+                    }
+                }
+                self.list(Some(self.ip()), 1);
+            }
+            "rc" | "rcontinue" => {
+                while self.step_back() {
+                    if self.breakpoint_at(self.ip()) {
+                        println!("breakpoint at {:#06x}", self.ip());
+                        break;
+                    }
+                }
+                self.list(Some(self.ip()), 1);
+            }
+            "b" | "break" => {
+                let addr = arg(0)?;
+                let id = self.machine.add_breakpoint(Condition::Address(addr));
+                println!("breakpoint {id} set at {addr:#06x}");
+            }
+            "w" | "watch" | "rw" | "rwatch" => {
+                let addr = arg(0)?;
+                let len = if args.len() > 1 { arg(1)? } else { 4 };
+                let condition = if cmd.starts_with('r') {
+                    Condition::Read { addr, len }
+                } else {
+                    Condition::Write { addr, len }
+                };
+                let id = self.machine.add_breakpoint(condition);
+                println!("watchpoint {id} set on {len} bytes at {addr:#06x}");
+            }
+            "bc" | "bcond" => {
+                let reg = self.register(args.first().copied().unwrap_or_default())?;
+                let value = arg(1)?;
+                let id = self.machine.add_breakpoint(Condition::Register {
+                    reg: reg as u8,
+                    value,
+                });
+                println!("breakpoint {id} set on r{reg} = {value:#x}");
+            }
+            "d" | "delete" => {
+                let id = BreakpointId(arg(0)?);
+                if self.machine.remove_breakpoint(id).is_none() {
+                    return Err(format!("no breakpoint {id}"));
+                }
+            }
+            "bl" | "breakpoints" => {
+                for (id, condition) in self.machine.breakpoints() {
+                    let what = match *condition {
+                        Condition::Address(addr) => format!("at {addr:#06x}"),
+                        Condition::Read { addr, len } => {
+                            format!("on reads of {len} bytes at {addr:#06x}")
+                        }
+                        Condition::Write { addr, len } => {
+                            format!("on writes of {len} bytes at {addr:#06x}")
+                        }
+                        Condition::Register { reg, value } => format!("on r{reg} = {value:#x}"),
+                        Condition::Predicate(_) => "on a predicate".to_string(),
+                    };
+                    println!("{id} {what}");
+                }
+            }
+            "r" | "regs" => self.dump_regs(),
+            "x" => {
+                let len = if args.len() > 1 { arg(1)? } else { 64 };
+                self.dump_mem(arg(0)? as usize, len as usize);
+            }
+            "l" | "list" => {
+                let from = if args.is_empty() {
+                    None
+                } else {
+                    Some(arg(0)? as usize)
+                };
+                let count = if args.len() > 1 { arg(1)? } else { 10 };
+                self.list(from, count as usize);
+            }
+            "setr" => {
+                let reg = self.register(args.first().copied().unwrap_or_default())?;
+                let value = arg(1)?;
+                self.machine
+                    .set_reg(reg, value)
+                    .map_err(|e| e.to_string())?;
+                if reg == 0 {
+                    self.terminated = false;
+                }
+            }
+            "setm" => {
+                let addr = arg(0)? as usize;
+                let bytes = (1..args.len())
+                    .map(|i| {
+                        let v = arg(i)?;
+                        u8::try_from(v).map_err(|_| format!("{v} does not fit in a byte"))
+                    })
+                    .collect::<Result<Vec<u8>, String>>()?;
+                self.machine
+                    .set_memory(addr, &bytes)
+                    .map_err(|e| e.to_string())?;
+            }
+            "h" | "help" => println!("{HELP}"),
+            "q" | "quit" => return Ok(false),
+            _ => return Err(format!("unknown command `{cmd}`, try `help`")),
+        }
+        Ok(true)
+    }
+}
+
+fn main() {
+    let args = Args::parse();
+    let exe = match Executable::load(&args.program) {
+        Ok(exe) => exe,
+        Err(e) => {
+            eprintln!("{}: {e}", args.program.display());
+            exit(1);
+        }
+    };
+    let loaded = Machine::try_new(&[])
+        .map_err(|e| e.to_string())
+        .and_then(|mut machine| {
+            machine
+                .load_executable(&exe)
+                .map(|_| machine)
+                .map_err(|e| e.to_string())
+        });
+    let mut machine = match loaded {
+        Ok(machine) => machine,
+        Err(e) => {
+            eprintln!("{}: {e}", args.program.display());
+            exit(1);
+        }
+    };
+    // Only a multicore machine refuses a history.
+    let _ = machine.set_history_limit(HISTORY_LIMIT);
+    let mut debugger = Debugger {
+        machine,
+        symbols: exe.symbols,
+        terminated: false,
+    };
+    debugger.list(Some(exe.entry as usize), 1);
+    let stdin = io::stdin();
+    loop {
+        print!("(vm) ");
+        io::stdout().flush().ok();
+        let mut line = String::new();
+        match stdin.lock().read_line(&mut line) {
+            Ok(0) | Err(_) => break,
+            Ok(_) => {}
+        }
+        match debugger.command(&line) {
+            Ok(true) => {}
+            Ok(false) => break,
+            Err(e) => println!("error: {e}"),
+        }
+    }
+}
@@ -0,0 +1,202 @@
+use std::{
+    fmt::Write as _,
+    fs::File,
+    io::{self, BufReader, Read, Write},
+    path::PathBuf,
+    process::exit,
+};
+
+use clap::Parser;
+use vm::{json_string, Executable, Machine, RunStatus, TraceFormat, WriteTracer};
+
+#[derive(Parser, Debug)]
+#[clap(version = "0.1", about = "Run a vm program", after_help = EXIT_HELP)]
+struct Args {
+    /// The program to run: an executable, a raw memory image, or an
+    /// assembler source if its extension is .s or .asm
+    program: PathBuf,
+    /// Stop after executing this many instructions
+    #[clap(short = 'm', long = "max-steps")]
+    max_steps: Option<u64>,
+    /// Trace every executed instruction on the standard error
+    #[clap(short = 't', long = "trace")]
+    trace: bool,
+    /// Read the input of the program from this file instead of the standard
+    /// input
+    #[clap(short = 'i', long = "input")]
+    input: Option<PathBuf>,
+    /// Print the registers on the standard error once the program stops
+    #[clap(short = 'r', long = "dump-regs")]
+    dump_regs: bool,
+    /// Print the memory between START and END (excluded) on the standard
+    /// error once the program stops, as START..END; may be repeated
+    #[clap(short = 'd', long = "dump-mem")]
+    dump_mem: Vec<String>,
+    /// Print a JSON summary of the run on the standard output, on a line
+    /// of its own after the output of the program, including the requested
+    /// dumps
+    #[clap(short = 'j', long = "json")]
+    json: bool,
+}
+
+const EXIT_HELP: &str = "exit status: 0 when the program exits, 1 when it cannot be loaded, \
+                         3 when it faults, 4 when --max-steps is reached";
+
+const EXIT_LOAD: i32 = 1;
+const EXIT_FAULT: i32 = 3;
+const EXIT_TIMEOUT: i32 = 4;
+
+/// Parse a decimal or `0x` hexadecimal number.
+fn parse_number(text: &str) -> Option<u64> {
+    match text.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => text.parse().ok(),
+    }
+}
+
+/// Parse a `START..END` range.
+fn parse_range(text: &str) -> Result<(usize, usize), String> {
+    let invalid = || format!("invalid memory range `{text}`, expected START..END");
+    let (start, end) = text.split_once("..").ok_or_else(invalid)?;
+    match (parse_number(start), parse_number(end)) {
+        (Some(start), Some(end)) if start <= end => Ok((start as usize, end as usize)),
+        _ => Err(invalid()),
+    }
+}
+
+/// Writer remembering the last byte written, to know whether the output of
+/// the program ends a line.
+struct Tail<W: Write> {
+    inner: W,
+    last: Option<u8>,
+}
+
+impl<W: Write> Write for Tail<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        if written > 0 {
+            self.last = Some(buf[written - 1]);
+        }
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+fn hex_dump(start: usize, bytes: &[u8]) {
+    for (i, chunk) in bytes.chunks(16).enumerate() {
+        let hex: Vec<String> = chunk.iter().map(|b| format!("{b:02x}")).collect();
+        eprintln!("{:#06x}:  {}", start + 16 * i, hex.join(" "));
+    }
+}
+
+fn main() {
+    let args = Args::parse();
+    let fail = |what: &dyn std::fmt::Display, e: String| -> ! {
+        eprintln!("{what}: {e}");
+        exit(EXIT_LOAD);
+    };
+    let ranges = args
+        .dump_mem
+        .iter()
+        .map(|text| parse_range(text))
+        .collect::<Result<Vec<_>, String>>()
+        .unwrap_or_else(|e| fail(&"--dump-mem", e));
+    let exe = Executable::load(&args.program)
+        .unwrap_or_else(|e| fail(&args.program.display(), e.to_string()));
+    let mut machine =
+        Machine::try_new(&[]).unwrap_or_else(|e| fail(&args.program.display(), e.to_string()));
+    if let Err(e) = machine.load_executable(&exe) {
+        fail(&args.program.display(), e.to_string());
+    }
+    let memory_size = machine.config().memory_size();
+    if let Some(&(start, end)) = ranges.iter().find(|&&(_, end)| end as u64 > memory_size) {
+        fail(
+            &"--dump-mem",
+            format!("range {start:#x}..{end:#x} is outside of the {memory_size} bytes of memory"),
+        );
+    }
+    if args.trace {
+        machine.set_tracer(WriteTracer::new(io::stderr(), TraceFormat::Text));
+    }
+    let mut input: Box<dyn Read> = match &args.input {
+        Some(path) => match File::open(path) {
+            Ok(file) => Box::new(BufReader::new(file)),
+            Err(e) => fail(&path.display(), e.to_string()),
+        },
+        None => Box::new(io::stdin().lock()),
+    };
+
+    // Instructions cost one cycle, so the budget is a number of steps.
+    let budget = args.max_steps.unwrap_or(u64::MAX);
+    let mut output = Tail {
+        inner: io::stdout().lock(),
+        last: None,
+    };
+    let status = machine.run_for_io(budget, &mut input, &mut output);
+    output.flush().ok();
+    let ends_line = output.last.is_none_or(|byte| byte == b'\n');
+    drop(output);
+    let (name, code, fault) = match &status {
+        RunStatus::Exited => ("exited", 0, None),
+        RunStatus::Faulted(e) => ("fault", EXIT_FAULT, Some(e.to_string())),
+        // Paused, no breakpoint being set.
+        _ => ("timeout", EXIT_TIMEOUT, None),
+    };
+    // The ranges were checked against the memory size before running.
+    let dumps: Vec<(usize, Vec<u8>)> = ranges
+        .iter()
+        .map(|&(start, end)| {
+            (
+                start,
+                machine.read_memory(start, end - start).unwrap_or_default(),
+            )
+        })
+        .collect();
+
+    if args.json {
+        let mut summary = format!(
+            "{{\"status\":\"{name}\",\"steps\":{},\"ip\":{}",
+            machine.cycles(),
+            machine.regs()[0]
+        );
+        if let Some(fault) = &fault {
+            let _ = write!(summary, ",\"fault\":{}", json_string(fault));
+        }
+        if args.dump_regs {
+            let regs: Vec<String> = machine.regs().iter().map(|r| r.to_string()).collect();
+            let _ = write!(summary, ",\"regs\":[{}]", regs.join(","));
+        }
+        if !dumps.is_empty() {
+            let memory: Vec<String> = dumps
+                .iter()
+                .map(|(start, bytes)| {
+                    let hex: String = bytes.iter().map(|b| format!("{b:02x}")).collect();
+                    format!("{{\"addr\":{start},\"bytes\":\"{hex}\"}}")
+                })
+                .collect();
+            let _ = write!(summary, ",\"memory\":[{}]", memory.join(","));
+        }
+        if !ends_line {
+            println!();
+        }
+        println!("{summary}}}");
+    } else {
+        match (&status, &fault) {
+            (_, Some(fault)) => eprintln!("fault: {fault}"),
+            (RunStatus::Exited, _) => {}
+            _ => eprintln!("stopped after {} steps", machine.cycles()),
+        }
+        if args.dump_regs {
+            for (i, r) in machine.regs().iter().enumerate() {
+                eprintln!("r{i:<2} = {r:#010x} {:>11}", *r as i32);
+            }
+        }
+        for (start, bytes) in &dumps {
+            hex_dump(*start, bytes);
+        }
+    }
+    exit(code);
+}
@@ -0,0 +1,326 @@
+//! Breakpoints, watchpoints and conditional breakpoints.
+//!
+//! Conditions added with [Machine::add_breakpoint] are checked after every
+//! instruction executed by [Machine::step_io] and its variants. The first
+//! condition met is reported by [Machine::last_break], the run methods
+//! stopping there: [Machine::run_io] returns and [Machine::run_for_io]
+//! returns [RunStatus::Break](crate::RunStatus::Break). A single step
+//! never stops, so that running again goes on past the breakpoint.
+
+use std::fmt;
+
+use crate::machine::Machine;
+
+/// Identifier of a breakpoint, unique for the lifetime of a machine.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct BreakpointId(pub u32);
+
+impl fmt::Display for BreakpointId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "#{}", self.0)
+    }
+}
+
+/// What makes a breakpoint stop the execution.
+pub enum Condition {
+    /// The IP reaches `addr`: the next instruction to execute is located
+    /// there.
+    Address(u32),
+    /// An instruction reads some of the `len` bytes starting at `addr`,
+    /// in memory or in a device.
+    Read { addr: u32, len: u32 },
+    /// An instruction writes some of the `len` bytes starting at `addr`,
+    /// in memory or in a device.
+    Write { addr: u32, len: u32 },
+    /// An instruction writes `value` into register `reg`. The IP moving
+    /// past an instruction does not count, use [Condition::Address]
+    /// instead.
+    Register { reg: u8, value: u32 },
+    /// The predicate, called after every instruction with the machine,
+    /// returns `true`.
+    Predicate(Box<dyn FnMut(&Machine) -> bool>),
+}
+
+impl fmt::Debug for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Condition::Address(addr) => f.debug_tuple("Address").field(addr).finish(),
+            Condition::Read { addr, len } => f
+                .debug_struct("Read")
+                .field("addr", addr)
+                .field("len", len)
+                .finish(),
+            Condition::Write { addr, len } => f
+                .debug_struct("Write")
+                .field("addr", addr)
+                .field("len", len)
+                .finish(),
+            Condition::Register { reg, value } => f
+                .debug_struct("Register")
+                .field("reg", reg)
+                .field("value", value)
+                .finish(),
+            Condition::Predicate(_) => f.write_str("Predicate(..)"),
+        }
+    }
+}
+
+/// Breakpoint met by an instruction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BreakHit {
+    pub id: BreakpointId,
+    /// Address of the instruction which met the condition.
+    pub ip: u32,
+    /// For watchpoints, address of the access which met the condition.
+    pub addr: Option<u32>,
+}
+
+/// Breakpoints of a machine.
+#[derive(Default)]
+pub(crate) struct Breakpoints {
+    next_id: u32,
+    conditions: Vec<(BreakpointId, Condition)>,
+    /// Watchpoint or register condition met by the current instruction.
+    triggered: Option<(BreakpointId, Option<u32>)>,
+    last: Option<BreakHit>,
+}
+
+impl Breakpoints {
+    /// Forget the breakpoint met by the previous instruction.
+    pub(crate) fn begin_step(&mut self) {
+        self.triggered = None;
+        self.last = None;
+    }
+
+    /// Note an access of `len` bytes at `addr` by the current instruction.
+    pub(crate) fn access(&mut self, addr: u32, len: usize, write: bool) {
+        if self.triggered.is_some() {
+            return;
+        }
+        let end = addr as u64 + len as u64;
+        self.triggered = self.conditions.iter().find_map(|(id, condition)| {
+            let (start, size) = match *condition {
+                Condition::Read { addr, len } if !write => (addr, len),
+                Condition::Write { addr, len } if write => (addr, len),
+                _ => return None,
+            };
+            let overlaps = (addr as u64) < start as u64 + size as u64 && (start as u64) < end;
+            (len > 0 && overlaps).then_some((*id, Some(addr)))
+        });
+    }
+
+    /// Note the write of `value` into register `reg` by the current
+    /// instruction.
+    pub(crate) fn reg_write(&mut self, reg: u8, value: u32) {
+        if self.triggered.is_some() {
+            return;
+        }
+        self.triggered = self
+            .conditions
+            .iter()
+            .find(|(_, condition)| match *condition {
+                Condition::Register { reg: r, value: v } => (r, v) == (reg, value),
+                _ => false,
+            })
+            .map(|(id, _)| (*id, None));
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.conditions.is_empty()
+    }
+}
+
+impl Machine {
+    /// Check the breakpoints once the instruction located at `ip` has been
+    /// executed without terminating the program.
+    pub(crate) fn check_breakpoints(&mut self, ip: u32) {
+        if let Some((id, addr)) = self.breakpoints.triggered.take() {
+            self.breakpoints.last = Some(BreakHit { id, ip, addr });
+            return;
+        }
+        let mut conditions = std::mem::take(&mut self.breakpoints.conditions);
+        let met = conditions.iter_mut().find_map(|(id, condition)| {
+            let met = match condition {
+                Condition::Address(addr) => self.regs()[0] == *addr,
+                Condition::Predicate(predicate) => predicate(self),
+                _ => false,
+            };
+            met.then_some(*id)
+        });
+        self.breakpoints.conditions = conditions;
+        self.breakpoints.last = met.map(|id| BreakHit { id, ip, addr: None });
+    }
+
+    /// Stop the run methods when `condition` is met, and return the
+    /// identifier of the new breakpoint.
+    pub fn add_breakpoint(&mut self, condition: Condition) -> BreakpointId {
+        let id = BreakpointId(self.breakpoints.next_id);
+        self.breakpoints.next_id += 1;
+        self.breakpoints.conditions.push((id, condition));
+        id
+    }
+
+    /// Remove the breakpoint `id` and return its condition, `None` if it
+    /// does not exist.
+    pub fn remove_breakpoint(&mut self, id: BreakpointId) -> Option<Condition> {
+        let index = self
+            .breakpoints
+            .conditions
+            .iter()
+            .position(|(i, _)| *i == id)?;
+        Some(self.breakpoints.conditions.remove(index).1)
+    }
+
+    /// Remove every breakpoint.
+    pub fn clear_breakpoints(&mut self) {
+        self.breakpoints.conditions.clear();
+    }
+
+    /// Breakpoints in the order they were added.
+    pub fn breakpoints(&self) -> impl Iterator<Item = (BreakpointId, &Condition)> {
+        self.breakpoints
+            .conditions
+            .iter()
+            .map(|(id, condition)| (*id, condition))
+    }
+
+    /// Breakpoint met by the last executed instruction, if any.
+    pub fn last_break(&self) -> Option<BreakHit> {
+        self.breakpoints.last
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use super::*;
+    use crate::{assembler::assemble, machine::RunStatus};
+
+    const SOURCE: &str = "
+            loadimm r1, 0x100
+            loadimm r2, 3
+        loop:
+            store   r1, r2
+            load    r3, r1
+            loadimm r4, 1
+            sub     r2, r2, r4
+            bnz     r2, loop
+            exit
+    ";
+
+    fn machine() -> Machine {
+        Machine::try_new(&assemble(SOURCE).unwrap().image).unwrap()
+    }
+
+    #[test]
+    fn addresses_stop_before_the_instruction() {
+        let mut machine = machine();
+        let id = machine.add_breakpoint(Condition::Address(8));
+        machine.run_on(&mut io::sink()).unwrap();
+        assert_eq!(machine.regs()[0], 8);
+        assert_eq!(
+            machine.last_break(),
+            Some(BreakHit {
+                id,
+                ip: 4,
+                addr: None
+            })
+        );
+        // Running again goes past the breakpoint, back to it on the next
+        // iteration.
+        machine.run_on(&mut io::sink()).unwrap();
+        assert_eq!(machine.regs()[2], 2);
+        assert_eq!(machine.last_break().unwrap().ip, 22);
+    }
+
+    #[test]
+    fn watchpoints_stop_after_the_access() {
+        let mut machine = machine();
+        let write = machine.add_breakpoint(Condition::Write {
+            addr: 0x102,
+            len: 4,
+        });
+        let read = machine.add_breakpoint(Condition::Read { addr: 0xfe, len: 3 });
+        machine.run_on(&mut io::sink()).unwrap();
+        let hit = BreakHit {
+            id: write,
+            ip: 8,
+            addr: Some(0x100),
+        };
+        assert_eq!(machine.last_break(), Some(hit));
+        assert_eq!(machine.read_memory(0x100, 1).unwrap(), [3]);
+        machine.run_on(&mut io::sink()).unwrap();
+        let hit = BreakHit {
+            id: read,
+            ip: 11,
+            addr: Some(0x100),
+        };
+        assert_eq!(machine.last_break(), Some(hit));
+        assert_eq!(machine.regs()[3], 3);
+
+        // Accesses next to the watched bytes do not count.
+        let mut machine = self::machine();
+        machine.add_breakpoint(Condition::Write {
+            addr: 0x104,
+            len: 4,
+        });
+        machine.add_breakpoint(Condition::Read { addr: 0xfc, len: 4 });
+        machine.add_breakpoint(Condition::Read {
+            addr: 0x100,
+            len: 0,
+        });
+        machine.run_on(&mut io::sink()).unwrap();
+        assert_eq!(machine.last_break(), None);
+        assert_eq!(machine.regs()[2], 0);
+    }
+
+    #[test]
+    fn registers_stop_on_the_written_value() {
+        let mut machine = machine();
+        let id = machine.add_breakpoint(Condition::Register { reg: 2, value: 1 });
+        let status = machine.run_for_on(100, &mut io::sink());
+        let hit = BreakHit {
+            id,
+            ip: 18,
+            addr: None,
+        };
+        assert_eq!(status, RunStatus::Break(hit));
+        assert_eq!(machine.last_break(), Some(hit));
+        // The IP moving past an instruction is not a register write.
+        let mut machine = self::machine();
+        machine.add_breakpoint(Condition::Register { reg: 0, value: 4 });
+        assert_eq!(machine.run_for_on(100, &mut io::sink()), RunStatus::Exited);
+    }
+
+    #[test]
+    fn predicates_see_the_machine() {
+        let mut machine = machine();
+        let id = machine.add_breakpoint(Condition::Predicate(Box::new(|machine| {
+            machine.regs()[3] == 2
+        })));
+        assert_eq!(
+            machine.run_for_on(100, &mut io::sink()),
+            RunStatus::Break(BreakHit {
+                id,
+                ip: 11,
+                addr: None
+            })
+        );
+        assert!(machine.remove_breakpoint(id).is_some());
+        assert!(machine.remove_breakpoint(id).is_none());
+        assert_eq!(machine.run_for_on(100, &mut io::sink()), RunStatus::Exited);
+        assert_eq!(machine.last_break(), None);
+    }
+
+    #[test]
+    fn single_steps_do_not_stop() {
+        let mut machine = machine();
+        machine.add_breakpoint(Condition::Address(4));
+        assert!(!machine.step_on(&mut io::sink()).unwrap());
+        assert!(machine.last_break().is_some());
+        assert!(!machine.step_on(&mut io::sink()).unwrap());
+        assert_eq!(machine.last_break(), None);
+        assert_eq!(machine.regs()[0], 8);
+    }
+}
@@ -0,0 +1,1143 @@
+//! Compiler for a small structured language targeting the
+//! [Machine](crate::Machine).
+//!
+//! ```text
+//! // Print the first factorials.
+//! var count = 6;
+//!
+//! fn fact(n) {
+//!     if n < 2 { return 1; }
+//!     return n * fact(n - 1);
+//! }
+//!
+//! fn main() {
+//!     var i = 0;
+//!     while i < count {
+//!         print "fact", i, "=", fact(i);
+//!         i = i + 1;
+//!     }
+//! }
+//! ```
+//!
+//! A program is made of global variables and functions, and starts with the
+//! `main` function, which takes no parameter. Comments start with `//` and
+//! run until the end of the line. Values are 32-bit signed integers.
+//!
+//! Statements:
+//!   - `var name;` or `var name = expr;` declares a variable, initialized
+//!     to 0 by default. Global variables must be initialized with a
+//!     number. A local variable can be used from its declaration to the end
+//!     of its function.
+//!   - `name = expr;` assigns a variable
+//!   - `if expr { ... }`, optionally followed by `else { ... }` or
+//!     `else if ...`, and `while expr { ... }`: a condition holds when it is
+//!     not zero
+//!   - `return;` or `return expr;` leaves the function, returning 0 by
+//!     default
+//!   - `print item, ...;` prints strings (`"text"`) and numbers separated by
+//!     spaces, followed by a newline
+//!   - `expr;` evaluates an expression, usually a call, for its effects
+//!
+//! Expressions, by increasing precedence: `||`, `&&` (both short-circuit
+//! and give 0 or 1), comparisons `==`, `!=`, `<`, `<=`, `>`, `>=` (giving 0
+//! or 1), `+` and `-`, `*`, `/` and `%` (rounding toward zero), unary `-`
+//! and `!`, then numbers, variables, calls `name(expr, ...)`, `read()`
+//! which reads a number from the input (0 at its end) and parentheses.
+//!
+//! The program is translated into assembler source for the
+//! [assembler](crate::assemble). Expressions are evaluated in `r1`, with
+//! `r2` as second operand and the stack holding intermediate results.
+//! Arguments are pushed on the stack before `call`, and the result is
+//! returned in `r1`. Local variables and parameters live in the stack frame
+//! of their function, addressed from the frame pointer `r14`. Global
+//! variables live in memory after the code. `r12` and `r13` are scratch
+//! registers.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    error::Error,
+    fmt,
+    fmt::Write as _,
+};
+
+use crate::{
+    assembler::{assemble_for, Assembled},
+    config::MachineConfig,
+};
+
+/// Error reported by the compiler, with the 1-based position of the
+/// offending token in the source.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CompileError {
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+}
+
+impl CompileError {
+    fn new(pos: Pos, message: impl Into<String>) -> Self {
+        CompileError {
+            line: pos.line,
+            column: pos.column,
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.line, self.column, self.message)
+    }
+}
+
+impl Error for CompileError {}
+
+/// Compile `source` into a memory image for a machine with the default
+/// configuration.
+pub fn compile(source: &str) -> Result<Assembled, CompileError> {
+    compile_for(source, &MachineConfig::default())
+}
+
+/// Compile `source` into a memory image for a machine described by
+/// `config`. The line table of the result refers to the assembler source
+/// given by [compile_to_assembly]. The `_stack_limit` symbol marks the end
+/// of the program, which the stack must not overwrite: load the result
+/// with [Executable::from_assembled](crate::Executable::from_assembled)
+/// to turn deep recursions into
+/// [StackOverflow](crate::MachineError::StackOverflow) errors.
+///
+/// Generated code which does not assemble, for example because the program
+/// does not fit in memory, is reported at the position of the statement
+/// which generated the offending line.
+pub fn compile_for(source: &str, config: &MachineConfig) -> Result<Assembled, CompileError> {
+    let (assembly, positions) = generate(source, config)?;
+    assemble_for(&assembly, config).map_err(|e| {
+        let pos = e.line.checked_sub(1).and_then(|i| positions.get(i));
+        CompileError::new(
+            pos.copied().unwrap_or(Pos { line: 1, column: 1 }),
+            format!("generated code does not assemble: {}", e.message),
+        )
+    })
+}
+
+/// Translate `source` into assembler source for a machine described by
+/// `config`, whose stack starts at the end of the memory.
+pub fn compile_to_assembly(source: &str, config: &MachineConfig) -> Result<String, CompileError> {
+    generate(source, config).map(|(assembly, _)| assembly)
+}
+
+/// Translate `source` into assembler source, with the source position of
+/// every line.
+fn generate(source: &str, config: &MachineConfig) -> Result<(String, Vec<Pos>), CompileError> {
+    let tokens = tokenize(source)?;
+    let program = Parser { tokens, pos: 0 }.program()?;
+    Generator::new(&program).program(config.stack_top())
+}
+
+/// 1-based position in the source.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Pos {
+    line: usize,
+    column: usize,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Tok {
+    Ident(String),
+    Number(i64),
+    Str(Vec<u8>),
+    /// Punctuation and operators.
+    Sym(&'static str),
+}
+
+const SYMBOLS: [&str; 21] = [
+    "==", "!=", "<=", ">=", "&&", "||", "(", ")", "{", "}", ",", ";", "=", "<", ">", "+", "-", "*",
+    "/", "%", "!",
+];
+
+fn tokenize(source: &str) -> Result<Vec<(Tok, Pos)>, CompileError> {
+    let mut tokens = Vec::new();
+    for (idx, text) in source.lines().enumerate() {
+        let chars: Vec<char> = text.chars().collect();
+        let mut i = 0;
+        while i < chars.len() {
+            let pos = Pos {
+                line: idx + 1,
+                column: i + 1,
+            };
+            let c = chars[i];
+            if c.is_whitespace() {
+                i += 1;
+            } else if c == '/' && chars.get(i + 1) == Some(&'/') {
+                break;
+            } else if c == '"' {
+                let mut bytes = Vec::new();
+                i += 1;
+                loop {
+                    let c = match chars.get(i) {
+                        None => return Err(CompileError::new(pos, "unterminated string")),
+                        Some('"') => break,
+                        Some('\\') => {
+                            i += 1;
+                            match chars.get(i) {
+                                Some('n') => '\n',
+                                Some('t') => '\t',
+                                Some('\\') => '\\',
+                                Some('"') => '"',
+                                _ => return Err(CompileError::new(pos, "invalid escape sequence")),
+                            }
+                        }
+                        Some(&c) => c,
+                    };
+                    let mut buf = [0; 4];
+                    bytes.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+                    i += 1;
+                }
+                tokens.push((Tok::Str(bytes), pos));
+                i += 1;
+            } else if c.is_ascii_digit() {
+                let start = i;
+                while i < chars.len() && chars[i].is_ascii_alphanumeric() {
+                    i += 1;
+                }
+                let word: String = chars[start..i].iter().collect();
+                let parsed = match word.strip_prefix("0x") {
+                    Some(hex) => i64::from_str_radix(hex, 16),
+                    None => word.parse(),
+                };
+                match parsed {
+                    Ok(value) if value <= u32::MAX as i64 => tokens.push((Tok::Number(value), pos)),
+                    _ => return Err(CompileError::new(pos, format!("invalid number `{word}`"))),
+                }
+            } else if c.is_alphabetic() || c == '_' {
+                let start = i;
+                while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_') {
+                    i += 1;
+                }
+                tokens.push((Tok::Ident(chars[start..i].iter().collect()), pos));
+            } else {
+                let rest: String = chars[i..].iter().take(2).collect();
+                let Some(sym) = SYMBOLS.iter().find(|sym| rest.starts_with(*sym)) else {
+                    return Err(CompileError::new(
+                        pos,
+                        format!("unexpected character `{c}`"),
+                    ));
+                };
+                tokens.push((Tok::Sym(sym), pos));
+                i += sym.len();
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BinOp {
+    Or,
+    And,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Expr {
+    Number(i64),
+    Var(String, Pos),
+    Call(String, Vec<Expr>, Pos),
+    Read,
+    Neg(Box<Expr>),
+    Not(Box<Expr>),
+    Binary(BinOp, Box<Expr>, Box<Expr>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum PrintItem {
+    Str(Vec<u8>),
+    Expr(Expr),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Stmt {
+    Var(String, Option<Expr>, Pos),
+    Assign(String, Expr, Pos),
+    If(Expr, Block, Block),
+    While(Expr, Block),
+    Return(Option<Expr>),
+    Print(Vec<PrintItem>),
+    Expr(Expr),
+}
+
+/// Statements with the position of their first token.
+type Block = Vec<(Pos, Stmt)>;
+
+#[derive(Debug, Clone, PartialEq)]
+struct Function {
+    name: String,
+    params: Vec<String>,
+    body: Block,
+    pos: Pos,
+}
+
+#[derive(Debug, Default)]
+struct Program {
+    /// Initial value of the global variables.
+    globals: BTreeMap<String, i64>,
+    functions: Vec<Function>,
+}
+
+const KEYWORDS: [&str; 8] = [
+    "fn", "var", "if", "else", "while", "return", "print", "read",
+];
+
+struct Parser {
+    tokens: Vec<(Tok, Pos)>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Tok> {
+        self.tokens.get(self.pos).map(|(t, _)| t)
+    }
+
+    /// Position of the current token, or just past the last one.
+    fn position(&self) -> Pos {
+        match self.tokens.get(self.pos) {
+            Some((_, pos)) => *pos,
+            None => self
+                .tokens
+                .last()
+                .map_or(Pos { line: 1, column: 1 }, |(_, pos)| Pos {
+                    line: pos.line,
+                    column: pos.column + 1,
+                }),
+        }
+    }
+
+    fn error(&self, message: impl Into<String>) -> CompileError {
+        CompileError::new(self.position(), message)
+    }
+
+    fn is_sym(&self, sym: &str) -> bool {
+        matches!(self.peek(), Some(Tok::Sym(s)) if *s == sym)
+    }
+
+    fn is_keyword(&self, word: &str) -> bool {
+        matches!(self.peek(), Some(Tok::Ident(w)) if w == word)
+    }
+
+    fn eat_sym(&mut self, sym: &str) -> bool {
+        let found = self.is_sym(sym);
+        self.pos += found as usize;
+        found
+    }
+
+    fn eat_keyword(&mut self, word: &str) -> bool {
+        let found = self.is_keyword(word);
+        self.pos += found as usize;
+        found
+    }
+
+    fn expect(&mut self, sym: &str) -> Result<(), CompileError> {
+        if self.eat_sym(sym) {
+            Ok(())
+        } else {
+            Err(self.error(format!("expected `{sym}`")))
+        }
+    }
+
+    fn name(&mut self) -> Result<(String, Pos), CompileError> {
+        let pos = self.position();
+        match self.peek() {
+            Some(Tok::Ident(name)) if !KEYWORDS.contains(&name.as_str()) => {
+                let name = name.clone();
+                self.pos += 1;
+                Ok((name, pos))
+            }
+            _ => Err(self.error("expected a name")),
+        }
+    }
+
+    fn program(mut self) -> Result<Program, CompileError> {
+        let mut program = Program::default();
+        let mut functions = BTreeSet::new();
+        while self.peek().is_some() {
+            if self.eat_keyword("var") {
+                let (name, pos) = self.name()?;
+                let mut value = 0;
+                if self.eat_sym("=") {
+                    let negative = self.eat_sym("-");
+                    value = match self.peek() {
+                        Some(Tok::Number(n)) => *n,
+                        _ => return Err(self.error("expected a number")),
+                    };
+                    self.pos += 1;
+                    if negative {
+                        value = -value;
+                    }
+                }
+                self.expect(";")?;
+                if program.globals.insert(name.clone(), value).is_some() {
+                    return Err(CompileError::new(
+                        pos,
+                        format!("global variable `{name}` is already defined"),
+                    ));
+                }
+            } else if self.eat_keyword("fn") {
+                let (name, pos) = self.name()?;
+                self.expect("(")?;
+                let mut params = Vec::new();
+                if !self.eat_sym(")") {
+                    loop {
+                        let (param, pos) = self.name()?;
+                        if params.contains(&param) {
+                            return Err(CompileError::new(
+                                pos,
+                                format!("parameter `{param}` is already defined"),
+                            ));
+                        }
+                        params.push(param);
+                        if self.eat_sym(")") {
+                            break;
+                        }
+                        self.expect(",")?;
+                    }
+                }
+                if !functions.insert(name.clone()) {
+                    return Err(CompileError::new(
+                        pos,
+                        format!("function `{name}` is already defined"),
+                    ));
+                }
+                let body = self.block()?;
+                program.functions.push(Function {
+                    name,
+                    params,
+                    body,
+                    pos,
+                });
+            } else {
+                return Err(self.error("expected `fn` or `var`"));
+            }
+        }
+        Ok(program)
+    }
+
+    fn block(&mut self) -> Result<Block, CompileError> {
+        self.expect("{")?;
+        let mut stmts = Vec::new();
+        while !self.eat_sym("}") {
+            if self.peek().is_none() {
+                return Err(self.error("expected `}`"));
+            }
+            let pos = self.position();
+            stmts.push((pos, self.statement()?));
+        }
+        Ok(stmts)
+    }
+
+    fn statement(&mut self) -> Result<Stmt, CompileError> {
+        if self.eat_keyword("var") {
+            let (name, pos) = self.name()?;
+            let init = if self.eat_sym("=") {
+                Some(self.expr()?)
+            } else {
+                None
+            };
+            self.expect(";")?;
+            Ok(Stmt::Var(name, init, pos))
+        } else if self.eat_keyword("if") {
+            self.if_statement()
+        } else if self.eat_keyword("while") {
+            let cond = self.expr()?;
+            Ok(Stmt::While(cond, self.block()?))
+        } else if self.eat_keyword("return") {
+            let value = if self.is_sym(";") {
+                None
+            } else {
+                Some(self.expr()?)
+            };
+            self.expect(";")?;
+            Ok(Stmt::Return(value))
+        } else if self.eat_keyword("print") {
+            let mut items = Vec::new();
+            loop {
+                match self.peek() {
+                    Some(Tok::Str(bytes)) => {
+                        items.push(PrintItem::Str(bytes.clone()));
+                        self.pos += 1;
+                    }
+                    _ => items.push(PrintItem::Expr(self.expr()?)),
+                }
+                if !self.eat_sym(",") {
+                    break;
+                }
+            }
+            self.expect(";")?;
+            Ok(Stmt::Print(items))
+        } else if matches!(
+            (self.peek(), self.tokens.get(self.pos + 1)),
+            (Some(Tok::Ident(_)), Some((Tok::Sym("="), _)))
+        ) {
+            let (name, pos) = self.name()?;
+            self.expect("=")?;
+            let value = self.expr()?;
+            self.expect(";")?;
+            Ok(Stmt::Assign(name, value, pos))
+        } else {
+            let expr = self.expr()?;
+            self.expect(";")?;
+            Ok(Stmt::Expr(expr))
+        }
+    }
+
+    /// Parse what follows `if`.
+    fn if_statement(&mut self) -> Result<Stmt, CompileError> {
+        let cond = self.expr()?;
+        let then = self.block()?;
+        let otherwise = if !self.eat_keyword("else") {
+            Vec::new()
+        } else if self.eat_keyword("if") {
+            let pos = self.position();
+            vec![(pos, self.if_statement()?)]
+        } else {
+            self.block()?
+        };
+        Ok(Stmt::If(cond, then, otherwise))
+    }
+
+    fn expr(&mut self) -> Result<Expr, CompileError> {
+        self.binary(0)
+    }
+
+    /// Parse a sequence of operations of precedence `level` or higher.
+    fn binary(&mut self, level: usize) -> Result<Expr, CompileError> {
+        const LEVELS: [&[(&str, BinOp)]; 5] = [
+            &[("||", BinOp::Or)],
+            &[("&&", BinOp::And)],
+            &[
+                ("==", BinOp::Eq),
+                ("!=", BinOp::Ne),
+                ("<=", BinOp::Le),
+                (">=", BinOp::Ge),
+                ("<", BinOp::Lt),
+                (">", BinOp::Gt),
+            ],
+            &[("+", BinOp::Add), ("-", BinOp::Sub)],
+            &[("*", BinOp::Mul), ("/", BinOp::Div), ("%", BinOp::Mod)],
+        ];
+        if level == LEVELS.len() {
+            return self.unary();
+        }
+        let mut lhs = self.binary(level + 1)?;
+        while let Some(&(_, op)) = LEVELS[level].iter().find(|(sym, _)| self.is_sym(sym)) {
+            self.pos += 1;
+            let rhs = self.binary(level + 1)?;
+            lhs = Expr::Binary(op, Box::new(lhs), Box::new(rhs));
+        }
+        Ok(lhs)
+    }
+
+    fn unary(&mut self) -> Result<Expr, CompileError> {
+        if self.eat_sym("-") {
+            return Ok(Expr::Neg(Box::new(self.unary()?)));
+        }
+        if self.eat_sym("!") {
+            return Ok(Expr::Not(Box::new(self.unary()?)));
+        }
+        if self.eat_sym("(") {
+            let expr = self.expr()?;
+            self.expect(")")?;
+            return Ok(expr);
+        }
+        if let Some(Tok::Number(n)) = self.peek() {
+            let n = *n;
+            self.pos += 1;
+            return Ok(Expr::Number(n));
+        }
+        if self.eat_keyword("read") {
+            self.expect("(")?;
+            self.expect(")")?;
+            return Ok(Expr::Read);
+        }
+        let (name, pos) = self
+            .name()
+            .map_err(|_| self.error("expected an expression"))?;
+        if !self.eat_sym("(") {
+            return Ok(Expr::Var(name, pos));
+        }
+        let mut args = Vec::new();
+        if !self.eat_sym(")") {
+            loop {
+                args.push(self.expr()?);
+                if self.eat_sym(")") {
+                    break;
+                }
+                self.expect(",")?;
+            }
+        }
+        Ok(Expr::Call(name, args, pos))
+    }
+}
+
+/// Where a variable lives.
+#[derive(Debug, Clone, Copy)]
+enum Slot {
+    /// Offset from the frame pointer.
+    Frame(i32),
+    Global,
+}
+
+const FP: &str = "r14";
+
+struct Generator<'a> {
+    program: &'a Program,
+    /// Number of parameters of every function.
+    arities: BTreeMap<&'a str, usize>,
+    out: String,
+    labels: usize,
+    /// Variables of the function being generated, declared so far.
+    locals: BTreeMap<String, Slot>,
+    /// Number of local variables of the function being generated.
+    frame_size: i32,
+    /// Label of the epilogue of the function being generated.
+    ret_label: String,
+    /// Position of the statement being generated.
+    pos: Pos,
+    /// Source position of every line of the output.
+    positions: Vec<Pos>,
+}
+
+impl<'a> Generator<'a> {
+    fn new(program: &'a Program) -> Self {
+        Generator {
+            program,
+            arities: program
+                .functions
+                .iter()
+                .map(|f| (f.name.as_str(), f.params.len()))
+                .collect(),
+            out: String::new(),
+            labels: 0,
+            locals: BTreeMap::new(),
+            frame_size: 0,
+            ret_label: String::new(),
+            pos: Pos { line: 1, column: 1 },
+            positions: Vec::new(),
+        }
+    }
+
+    /// Output one line of assembler source, generated for the current
+    /// statement.
+    fn line(&mut self, text: fmt::Arguments) {
+        let _ = writeln!(self.out, "{text}");
+        self.positions.push(self.pos);
+    }
+
+    fn emit(&mut self, line: impl AsRef<str>) {
+        self.line(format_args!("        {}", line.as_ref()));
+    }
+
+    fn label(&mut self, label: &str) {
+        self.line(format_args!("{label}:"));
+    }
+
+    fn new_label(&mut self) -> String {
+        self.labels += 1;
+        format!(".L{}", self.labels)
+    }
+
+    /// Load `value` into `reg`, which must not be `r12` or `r13`.
+    fn load_const(&mut self, reg: &str, value: i64) {
+        let value = value as u32;
+        if value as i32 >= i16::MIN as i32 && value as i32 <= i16::MAX as i32 {
+            self.emit(format!("loadimm {reg}, {}", value as i32));
+            return;
+        }
+        self.emit(format!("loadimm {reg}, {}", (value >> 16) as u16 as i16));
+        self.emit("loadimm r13, 16");
+        self.emit(format!("shl {reg}, {reg}, r13"));
+        self.emit(format!("loadimm r12, {}", value as u16 as i16));
+        self.emit("shl r12, r12, r13");
+        self.emit("shr r12, r12, r13");
+        self.emit(format!("or {reg}, {reg}, r12"));
+    }
+
+    /// Generate the whole program, returning the assembler source and the
+    /// source position of each of its lines.
+    fn program(mut self, stack_top: u32) -> Result<(String, Vec<Pos>), CompileError> {
+        let main = self.program.functions.iter().find(|f| f.name == "main");
+        self.pos = main.map_or(Pos { line: 1, column: 1 }, |f| f.pos);
+        match self.arities.get("main") {
+            Some(0) => {}
+            Some(_) => {
+                return Err(CompileError::new(
+                    self.pos,
+                    "`main` must not take parameters",
+                ));
+            }
+            None => {
+                return Err(CompileError::new(
+                    Pos { line: 1, column: 1 },
+                    "the program has no `main` function",
+                ))
+            }
+        }
+        self.label("_start");
+        self.load_const("sp", stack_top as i64);
+        self.emit("call fn.main");
+        self.emit("exit");
+        for function in &self.program.functions {
+            self.pos = function.pos;
+            self.function(function)?;
+        }
+        self.emit(".align 4");
+        for (name, value) in &self.program.globals {
+            self.line(format_args!("var.{name}: .word {value}"));
+        }
+        // The stack may grow down to the end of the program, see
+        // Machine::load_executable.
+        self.label("_stack_limit");
+        Ok((self.out, self.positions))
+    }
+
+    fn function(&mut self, function: &Function) -> Result<(), CompileError> {
+        let n = function.params.len() as i32;
+        // Stack frame: parameters pushed by the caller, return address,
+        // saved frame pointer (where the frame pointer points) then local
+        // variables.
+        self.locals = function
+            .params
+            .iter()
+            .enumerate()
+            .map(|(i, param)| (param.clone(), Slot::Frame(8 + 4 * (n - 1 - i as i32))))
+            .collect();
+        self.frame_size = 0;
+        let mut locals = 0;
+        count_locals(&function.body, &mut locals);
+        self.ret_label = self.new_label();
+        self.label(&format!("fn.{}", function.name));
+        self.emit(format!("push {FP}"));
+        self.emit(format!("or {FP}, sp, sp"));
+        if locals > 0 {
+            self.emit(format!("loadimm r13, {}", 4 * locals));
+            self.emit("sub sp, sp, r13");
+        }
+        self.block(&function.body)?;
+        self.emit("loadimm r1, 0");
+        let ret = self.ret_label.clone();
+        self.label(&ret);
+        self.emit(format!("or sp, {FP}, {FP}"));
+        self.emit(format!("pop {FP}"));
+        self.emit("ret");
+        Ok(())
+    }
+
+    fn block(&mut self, stmts: &[(Pos, Stmt)]) -> Result<(), CompileError> {
+        for (pos, stmt) in stmts {
+            self.pos = *pos;
+            self.statement(stmt)?;
+        }
+        Ok(())
+    }
+
+    /// Put the address of variable `name` in `r13`.
+    fn address(&mut self, name: &str, pos: Pos) -> Result<(), CompileError> {
+        match self.locals.get(name).copied().or_else(|| {
+            self.program
+                .globals
+                .contains_key(name)
+                .then_some(Slot::Global)
+        }) {
+            Some(Slot::Frame(offset)) => {
+                self.emit(format!("loadimm r13, {offset}"));
+                self.emit(format!("add r13, {FP}, r13"));
+            }
+            Some(Slot::Global) => self.emit(format!("loadimm r13, var.{name}")),
+            None => return Err(CompileError::new(pos, format!("unknown variable `{name}`"))),
+        }
+        Ok(())
+    }
+
+    fn statement(&mut self, stmt: &Stmt) -> Result<(), CompileError> {
+        match stmt {
+            Stmt::Var(name, init, pos) => {
+                if self.locals.contains_key(name) {
+                    return Err(CompileError::new(
+                        *pos,
+                        format!("variable `{name}` is already defined"),
+                    ));
+                }
+                match init {
+                    Some(init) => self.expr(init)?,
+                    None => self.emit("loadimm r1, 0"),
+                }
+                self.frame_size += 1;
+                let slot = Slot::Frame(-4 * self.frame_size);
+                self.locals.insert(name.clone(), slot);
+                self.address(name, *pos)?;
+                self.emit("store r13, r1");
+            }
+            Stmt::Assign(name, value, pos) => {
+                self.expr(value)?;
+                self.address(name, *pos)?;
+                self.emit("store r13, r1");
+            }
+            Stmt::If(cond, then, otherwise) => {
+                let else_label = self.new_label();
+                let end_label = self.new_label();
+                self.expr(cond)?;
+                self.emit(format!("bz r1, {else_label}"));
+                self.block(then)?;
+                self.emit(format!("jmp {end_label}"));
+                self.label(&else_label);
+                self.block(otherwise)?;
+                self.label(&end_label);
+            }
+            Stmt::While(cond, body) => {
+                let top_label = self.new_label();
+                let end_label = self.new_label();
+                self.label(&top_label);
+                self.expr(cond)?;
+                self.emit(format!("bz r1, {end_label}"));
+                self.block(body)?;
+                self.emit(format!("jmp {top_label}"));
+                self.label(&end_label);
+            }
+            Stmt::Return(value) => {
+                match value {
+                    Some(value) => self.expr(value)?,
+                    None => self.emit("loadimm r1, 0"),
+                }
+                let ret = self.ret_label.clone();
+                self.emit(format!("jmp {ret}"));
+            }
+            Stmt::Print(items) => {
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        self.emit("loadimm r1, ' '");
+                        self.emit("out r1");
+                    }
+                    match item {
+                        PrintItem::Str(bytes) => {
+                            for &b in bytes {
+                                self.emit(format!("loadimm r1, {b}"));
+                                self.emit("out r1");
+                            }
+                        }
+                        PrintItem::Expr(expr) => {
+                            self.expr(expr)?;
+                            self.emit("out number r1");
+                        }
+                    }
+                }
+                self.emit("loadimm r1, '\\n'");
+                self.emit("out r1");
+            }
+            Stmt::Expr(expr) => self.expr(expr)?,
+        }
+        Ok(())
+    }
+
+    /// Evaluate `expr` into `r1`.
+    fn expr(&mut self, expr: &Expr) -> Result<(), CompileError> {
+        match expr {
+            Expr::Number(n) => self.load_const("r1", *n),
+            Expr::Var(name, pos) => {
+                self.address(name, *pos)?;
+                self.emit("load r1, r13");
+            }
+            Expr::Read => self.emit("in number r1"),
+            Expr::Call(name, args, pos) => {
+                match self.arities.get(name.as_str()) {
+                    Some(&n) if n == args.len() => {}
+                    Some(&n) => {
+                        return Err(CompileError::new(
+                            *pos,
+                            format!("`{name}` takes {n} arguments, not {}", args.len()),
+                        ))
+                    }
+                    None => {
+                        return Err(CompileError::new(
+                            *pos,
+                            format!("unknown function `{name}`"),
+                        ))
+                    }
+                }
+                for arg in args {
+                    self.expr(arg)?;
+                    self.emit("push r1");
+                }
+                self.emit(format!("call fn.{name}"));
+                if !args.is_empty() {
+                    self.emit(format!("loadimm r13, {}", 4 * args.len()));
+                    self.emit("add sp, sp, r13");
+                }
+            }
+            Expr::Neg(e) => {
+                self.expr(e)?;
+                self.emit("loadimm r2, 0");
+                self.emit("sub r1, r2, r1");
+            }
+            Expr::Not(e) => {
+                self.expr(e)?;
+                self.emit("loadimm r2, 0");
+                self.emit("seq r1, r1, r2");
+            }
+            Expr::Binary(op @ (BinOp::And | BinOp::Or), lhs, rhs) => {
+                // Jump to `short` as soon as the result is known.
+                let (branch, short, other) = match op {
+                    BinOp::And => ("bz", 0, 1),
+                    _ => ("bnz", 1, 0),
+                };
+                let short_label = self.new_label();
+                let end_label = self.new_label();
+                self.expr(lhs)?;
+                self.emit(format!("{branch} r1, {short_label}"));
+                self.expr(rhs)?;
+                self.emit(format!("{branch} r1, {short_label}"));
+                self.emit(format!("loadimm r1, {other}"));
+                self.emit(format!("jmp {end_label}"));
+                self.label(&short_label);
+                self.emit(format!("loadimm r1, {short}"));
+                self.label(&end_label);
+            }
+            Expr::Binary(op, lhs, rhs) => {
+                self.expr(lhs)?;
+                if let Expr::Number(n) = **rhs {
+                    self.load_const("r2", n);
+                } else {
+                    self.emit("push r1");
+                    self.expr(rhs)?;
+                    self.emit("or r2, r1, r1");
+                    self.emit("pop r1");
+                }
+                match op {
+                    BinOp::Add => self.emit("add r1, r1, r2"),
+                    BinOp::Sub => self.emit("sub r1, r1, r2"),
+                    BinOp::Mul => self.emit("mul r1, r1, r2"),
+                    BinOp::Div => self.emit("divs r1, r1, r2"),
+                    BinOp::Mod => self.emit("mods r1, r1, r2"),
+                    BinOp::Eq => self.emit("seq r1, r1, r2"),
+                    BinOp::Ne => {
+                        self.emit("seq r1, r1, r2");
+                        self.emit("loadimm r2, 1");
+                        self.emit("xor r1, r1, r2");
+                    }
+                    BinOp::Lt => self.emit("slt r1, r1, r2"),
+                    BinOp::Le => self.emit("sle r1, r1, r2"),
+                    BinOp::Gt => self.emit("slt r1, r2, r1"),
+                    BinOp::Ge => self.emit("sle r1, r2, r1"),
+                    BinOp::And | BinOp::Or => unreachable!(),
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Count the local variables declared in `stmts`.
+fn count_locals(stmts: &[(Pos, Stmt)], count: &mut usize) {
+    for (_, stmt) in stmts {
+        match stmt {
+            Stmt::Var(..) => *count += 1,
+            Stmt::If(_, then, otherwise) => {
+                count_locals(then, count);
+                count_locals(otherwise, count);
+            }
+            Stmt::While(_, body) => count_locals(body, count),
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        executable::Executable,
+        machine::{Machine, MachineError},
+    };
+
+    /// Compile and run `source` with `input`, returning its output and how
+    /// it stopped.
+    fn run(source: &str, input: &str) -> (String, Result<(), MachineError>) {
+        let assembled = compile(source).unwrap();
+        let mut machine = Machine::try_new(&[]).unwrap();
+        machine
+            .load_executable(&Executable::from_assembled(&assembled))
+            .unwrap();
+        let mut output = Vec::new();
+        let result = machine.run_io(&mut input.as_bytes(), &mut output);
+        (String::from_utf8(output).unwrap(), result)
+    }
+
+    fn output(source: &str) -> String {
+        let (output, result) = run(source, "");
+        result.unwrap();
+        output
+    }
+
+    fn error(source: &str) -> (usize, usize, String) {
+        let e = compile(source).unwrap_err();
+        (e.line, e.column, e.message)
+    }
+
+    #[test]
+    fn recursion() {
+        let source = "
+            fn fib(n) {
+                if n < 2 { return n; }
+                return fib(n - 1) + fib(n - 2);
+            }
+            fn main() { print fib(0), fib(1), fib(10), fib(20); }
+        ";
+        assert_eq!(output(source), "0 1 55 6765\n");
+    }
+
+    #[test]
+    fn nested_calls_as_arguments() {
+        let source = "
+            fn sub(a, b) { return a - b; }
+            fn twice(x) { return 2 * x; }
+            fn main() { print sub(twice(sub(10, 3)), sub(twice(2), 1)); }
+        ";
+        assert_eq!(output(source), "11\n");
+    }
+
+    #[test]
+    fn globals() {
+        let source = "
+            var total = -5;
+            var calls;
+            fn add(n) { total = total + n; calls = calls + 1; }
+            fn main() {
+                add(10);
+                add(7);
+                print \"total\", total, \"calls\", calls;
+            }
+        ";
+        assert_eq!(output(source), "total 12 calls 2\n");
+    }
+
+    #[test]
+    fn while_and_return() {
+        let source = "
+            fn first_square_above(limit) {
+                var i = 0;
+                while 1 {
+                    if i * i > limit { return i; }
+                    i = i + 1;
+                }
+            }
+            fn nothing() { return; }
+            fn main() {
+                var n = 3;
+                while n > 0 { print n; n = n - 1; }
+                print first_square_above(50), nothing();
+            }
+        ";
+        assert_eq!(output(source), "3\n2\n1\n8 0\n");
+    }
+
+    #[test]
+    fn short_circuit_operators() {
+        let source = "
+            var calls;
+            fn touch(v) { calls = calls + 1; return v; }
+            fn main() {
+                print 0 && touch(1), 2 && touch(3), 4 || touch(5), 0 || touch(0);
+                print calls, !7, !0, -(3 - 5);
+                print 0 && 1 / 0;
+            }
+        ";
+        assert_eq!(output(source), "0 1 1 0\n2 0 1 2\n0\n");
+    }
+
+    #[test]
+    fn read_numbers() {
+        let source = "
+            fn main() {
+                var sum = 0;
+                var n = read();
+                while n != 0 { sum = sum + n; n = read(); }
+                print sum;
+            }
+        ";
+        let (output, result) = run(source, "4 -1\n10");
+        result.unwrap();
+        assert_eq!(output, "13\n");
+        let (output, result) = run(source, "");
+        result.unwrap();
+        assert_eq!(output, "0\n");
+    }
+
+    #[test]
+    fn division_rounds_toward_zero() {
+        let source = "
+            fn main() {
+                print 7 / 2, -7 / 2, 7 / -2, -7 / -2;
+                print 7 % 2, -7 % 2, 7 % -2, -7 % -2;
+            }
+        ";
+        assert_eq!(output(source), "3 -3 -3 3\n1 -1 1 -1\n");
+        let (output, result) = run("fn main() { print 1; print 1 / (2 - 2); }", "");
+        assert_eq!(output, "1\n");
+        assert!(matches!(result, Err(MachineError::DivisionByZero { .. })));
+    }
+
+    #[test]
+    fn deep_recursion_overflows_the_stack() {
+        let source = "
+            fn r(n) { if n == 0 { return 0; } return r(n - 1); }
+            fn main() { print r(20); print r(2000); }
+        ";
+        let (output, result) = run(source, "");
+        assert_eq!(output, "0\n");
+        assert!(matches!(result, Err(MachineError::StackOverflow { .. })));
+    }
+
+    #[test]
+    fn errors_have_positions() {
+        assert_eq!(
+            error("fn main() {\n    print x;\n}"),
+            (2, 11, "unknown variable `x`".to_string())
+        );
+        assert_eq!(
+            error("fn f(a) { return a; }\nfn main() { f(1, 2); }"),
+            (2, 13, "`f` takes 1 arguments, not 2".to_string())
+        );
+        assert_eq!(
+            error("fn main() {\n  var a = 1\n}"),
+            (3, 1, "expected `;`".to_string())
+        );
+        assert_eq!(
+            error("fn main(x) {}"),
+            (1, 4, "`main` must not take parameters".to_string())
+        );
+        assert_eq!(
+            error("var x = 1;"),
+            (1, 1, "the program has no `main` function".to_string())
+        );
+        assert_eq!(error("fn main() { print \"a; }").0, 1);
+    }
+
+    #[test]
+    fn assembler_errors_point_at_the_statement() {
+        // Every character printed takes two instructions: the second print
+        // statement does not fit in the memory.
+        let long = "x".repeat(1000);
+        let source = format!("fn main() {{\n    print 1;\n    print \"{long}\";\n}}");
+        let (line, column, message) = error(&source);
+        assert_eq!((line, column), (3, 5));
+        assert!(message.starts_with("generated code does not assemble"));
+    }
+}
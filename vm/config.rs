@@ -0,0 +1,111 @@
+//! Machine configuration.
+//!
+//! ```ignore
+//! let machine = MachineConfig::new()
+//!     .with_memory_size(1 << 32)?
+//!     .with_sparse_memory(true)
+//!     .build(&image)?;
+//! ```
+
+use crate::machine::{Machine, MachineError, SP};
+
+/// Memory size of a machine built with the default configuration.
+pub const DEFAULT_MEMORY_SIZE: u64 = 4096;
+/// Number of registers of a machine built with the default configuration.
+pub const DEFAULT_REGISTERS: usize = 16;
+/// Largest memory size: the whole 32-bit address space.
+pub const MAX_MEMORY_SIZE: u64 = 1 << 32;
+/// Largest memory size allocated up front. Larger memories must be
+/// [sparse](MachineConfig::with_sparse_memory).
+pub const MAX_DENSE_MEMORY_SIZE: u64 = 1 << 28;
+
+/// Builder describing the memory and registers of a [Machine].
+///
+/// The default configuration is 4096 bytes of dense memory and 16
+/// registers, as given by [Machine::try_new].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MachineConfig {
+    memory_size: u64,
+    registers: usize,
+    sparse: bool,
+}
+
+impl Default for MachineConfig {
+    fn default() -> Self {
+        MachineConfig {
+            memory_size: DEFAULT_MEMORY_SIZE,
+            registers: DEFAULT_REGISTERS,
+            sparse: false,
+        }
+    }
+}
+
+impl MachineConfig {
+    /// The default configuration.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the memory size in bytes. An error is returned when `size` is 0
+    /// or larger than [MAX_MEMORY_SIZE].
+    pub fn with_memory_size(mut self, size: u64) -> Result<Self, MachineError> {
+        if !(1..=MAX_MEMORY_SIZE).contains(&size) {
+            return Err(MachineError::InvalidMemorySize { size });
+        }
+        self.memory_size = size;
+        Ok(self)
+    }
+
+    /// Set the number of registers. Register 0 is the IP and register 15
+    /// the stack pointer, so at least 16 registers are needed; register
+    /// operands are encoded on one byte, so at most 256 can be used. An
+    /// error is returned when `count` is outside of `16..=256`.
+    pub fn with_registers(mut self, count: usize) -> Result<Self, MachineError> {
+        if !(SP + 1..=256).contains(&count) {
+            return Err(MachineError::InvalidRegisterCount { count });
+        }
+        self.registers = count;
+        Ok(self)
+    }
+
+    /// Allocate memory pages on their first write instead of up front,
+    /// which makes large address spaces affordable. A sparse memory cannot
+    /// be borrowed as a whole with [Machine::memory]: use
+    /// [Machine::read_memory] instead.
+    pub fn with_sparse_memory(mut self, sparse: bool) -> Self {
+        self.sparse = sparse;
+        self
+    }
+
+    /// Memory size in bytes.
+    pub fn memory_size(&self) -> u64 {
+        self.memory_size
+    }
+
+    /// Number of registers.
+    pub fn registers(&self) -> usize {
+        self.registers
+    }
+
+    /// Stack pointer set by
+    /// [Machine::load_executable](crate::Machine::load_executable): the end
+    /// of the memory, rounded down to a multiple of 4. The end of a 4 GiB
+    /// address space does not fit in a register, so the stack then starts
+    /// at its last word.
+    pub fn stack_top(&self) -> u32 {
+        (self.memory_size.min(MAX_MEMORY_SIZE - 4) & !3) as u32
+    }
+
+    /// `true` if memory pages are allocated on demand.
+    pub fn is_sparse(&self) -> bool {
+        self.sparse
+    }
+
+    /// Create a machine with this configuration in its reset state,
+    /// `program` being copied at the beginning of its memory. An error is
+    /// returned when `program` is larger than the memory, or when a dense
+    /// memory is larger than [MAX_DENSE_MEMORY_SIZE].
+    pub fn build(self, program: &[u8]) -> Result<Machine, MachineError> {
+        Machine::with_config(self, program)
+    }
+}
@@ -0,0 +1,533 @@
+//! Memory-mapped devices.
+//!
+//! A [Device] attached with [Machine::map_device](crate::Machine::map_device)
+//! owns a range of addresses: `load` and `store` instructions (and their
+//! byte and halfword variants) touching that range are handed to the
+//! device instead of the memory. Instructions are always fetched from the
+//! memory, and the stack always lives in it.
+
+use std::io::{self, Read, Write};
+
+use crate::machine::{Effects, MachineError};
+
+/// A peripheral answering to the loads and stores in its address range.
+///
+/// Offsets are relative to the address the device is mapped at, and
+/// `width` is 1, 2 or 4 bytes. Values are little-endian, like in memory.
+/// An I/O error makes the faulting instruction raise
+/// [MachineError::DeviceFault](crate::MachineError::DeviceFault).
+pub trait Device {
+    /// Number of bytes of address space used by the device.
+    fn size(&self) -> u32;
+
+    /// Read `width` bytes at `offset`.
+    fn read(&mut self, offset: u32, width: usize) -> io::Result<u32>;
+
+    /// Write the `width` low bytes of `value` at `offset`.
+    fn write(&mut self, offset: u32, width: usize, value: u32) -> io::Result<()>;
+
+    /// Called after every instruction the machine completes, before the
+    /// next one is fetched. Instructions that fault do not tick.
+    fn tick(&mut self) {}
+
+    /// Whether [tick](Device::tick) may change the state of the device.
+    /// The [undo history](crate::Machine::set_history_limit) saves the
+    /// state of such a device before every instruction, and the state of
+    /// the other devices only when the program accesses them, so devices
+    /// overriding `tick` must return `true`.
+    fn changes_on_tick(&self) -> bool {
+        false
+    }
+
+    /// Interrupt line (0 to 31) the device requests, polled after every
+    /// [tick](Device::tick). The request is forgotten once returned. A
+    /// line above 31 makes the instruction raise
+    /// [MachineError::UnknownInterrupt].
+    fn take_interrupt(&mut self) -> Option<u8> {
+        None
+    }
+
+    /// Interrupt line the device requests, if any, checked by
+    /// [Machine::map_device](crate::Machine::map_device).
+    fn interrupt_line(&self) -> Option<u8> {
+        None
+    }
+
+    /// State of the device to store in a
+    /// [snapshot](crate::Machine::snapshot). Devices without state keep the
+    /// default, empty, one.
+    fn save_state(&self) -> Vec<u8> {
+        Vec::new()
+    }
+
+    /// Restore a state produced by [save_state](Device::save_state).
+    fn restore_state(&mut self, _state: &[u8]) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Error returned by [Device::restore_state] for a state of the wrong size.
+pub(crate) fn invalid_state() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "invalid device state")
+}
+
+struct Mapping {
+    base: u32,
+    size: u32,
+    device: Box<dyn Device>,
+}
+
+/// Address decoder dispatching accesses to the mapped devices.
+#[derive(Default)]
+pub(crate) struct Bus {
+    mappings: Vec<Mapping>,
+}
+
+impl Bus {
+    /// Map `device` at `base`, returning it back if its range does not fit
+    /// in the address space or overlaps another device.
+    pub(crate) fn map(
+        &mut self,
+        base: u32,
+        device: Box<dyn Device>,
+    ) -> Result<(), Box<dyn Device>> {
+        let size = device.size();
+        if size == 0 || base as u64 + size as u64 > 1 << 32 || self.overlaps(base, size as u64) {
+            return Err(device);
+        }
+        self.mappings.push(Mapping { base, size, device });
+        Ok(())
+    }
+
+    /// Remove and return the device mapped at `base`.
+    pub(crate) fn unmap(&mut self, base: u32) -> Option<Box<dyn Device>> {
+        let index = self.mappings.iter().position(|m| m.base == base)?;
+        Some(self.mappings.remove(index).device)
+    }
+
+    /// `true` if a device is mapped in the `size` bytes starting at `base`.
+    pub(crate) fn overlaps(&self, base: u32, size: u64) -> bool {
+        let end = base as u64 + size;
+        self.mappings
+            .iter()
+            .any(|m| (base as u64) < m.base as u64 + m.size as u64 && (m.base as u64) < end)
+    }
+
+    /// The device whose range contains `addr`, with the offset of `addr`
+    /// in it and the number of bytes left in the range.
+    pub(crate) fn find(&mut self, addr: u32) -> Option<(&mut Box<dyn Device>, u32, u32)> {
+        self.mappings
+            .iter_mut()
+            .find(|m| addr >= m.base && addr - m.base < m.size)
+            .map(|m| (&mut m.device, addr - m.base, m.size - (addr - m.base)))
+    }
+
+    /// Address, size and state of every mapped device.
+    pub(crate) fn states(&self) -> Vec<(u32, u32, Vec<u8>)> {
+        self.mappings
+            .iter()
+            .map(|m| (m.base, m.size, m.device.save_state()))
+            .collect()
+    }
+
+    /// `true` if a device of `size` bytes is mapped at `base`.
+    pub(crate) fn is_mapped(&self, base: u32, size: u32) -> bool {
+        self.mappings
+            .iter()
+            .any(|m| m.base == base && m.size == size)
+    }
+
+    /// Restore the state of the device mapped at `base`.
+    pub(crate) fn restore_state(&mut self, base: u32, state: &[u8]) -> io::Result<()> {
+        match self.mappings.iter_mut().find(|m| m.base == base) {
+            Some(m) => m.device.restore_state(state),
+            None => Err(invalid_state()),
+        }
+    }
+
+    /// Restore the states of the devices mapped at the given bases. If one
+    /// of them fails, the devices restored before it get their previous
+    /// state back and its base is returned.
+    pub(crate) fn restore_states(&mut self, states: &[(u32, &[u8])]) -> Result<(), u32> {
+        let mut previous = Vec::new();
+        for &(base, state) in states {
+            let saved = self
+                .mappings
+                .iter()
+                .find(|m| m.base == base)
+                .map(|m| m.device.save_state());
+            match saved {
+                Some(saved) if self.restore_state(base, state).is_ok() => {
+                    previous.push((base, saved))
+                }
+                _ => {
+                    for (base, saved) in previous {
+                        // A state the device saved itself is accepted back.
+                        let _ = self.restore_state(base, &saved);
+                    }
+                    return Err(base);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Tick every device, returning the mask of the interrupt lines they
+    /// request. The states of the devices changing on ticks are saved in
+    /// `effects` first.
+    pub(crate) fn tick(&mut self, mut effects: Option<&mut Effects>) -> Result<u32, MachineError> {
+        let mut lines = 0;
+        for m in &mut self.mappings {
+            if let Some(effects) = effects
+                .as_deref_mut()
+                .filter(|_| m.device.changes_on_tick())
+            {
+                effects.save_device(m.base, m.device.as_ref());
+            }
+            m.device.tick();
+            match m.device.take_interrupt() {
+                Some(line) if line >= 32 => return Err(MachineError::UnknownInterrupt { line }),
+                Some(line) => lines |= 1 << line,
+                None => {}
+            }
+        }
+        Ok(lines)
+    }
+}
+
+/// Character console: storing to offset 0 prints the low byte, loading from
+/// offset 0 reads the next input byte, or all ones at the access width at
+/// the end of the input (`0xff` for `loadb`, `0xffffffff` for `load`).
+pub struct Console<R: Read, W: Write> {
+    input: R,
+    output: W,
+}
+
+impl<R: Read, W: Write> Console<R, W> {
+    pub fn new(input: R, output: W) -> Self {
+        Console { input, output }
+    }
+
+    /// Give back the underlying input and output.
+    pub fn into_inner(self) -> (R, W) {
+        (self.input, self.output)
+    }
+}
+
+impl<R: Read, W: Write> Device for Console<R, W> {
+    fn size(&self) -> u32 {
+        4
+    }
+
+    fn read(&mut self, _offset: u32, width: usize) -> io::Result<u32> {
+        let mut buf = [0];
+        loop {
+            match self.input.read(&mut buf) {
+                Ok(0) => return Ok(u32::MAX >> (32 - 8 * width)),
+                Ok(_) => return Ok(buf[0] as u32),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    fn write(&mut self, _offset: u32, _width: usize, value: u32) -> io::Result<()> {
+        self.output.write_all(&[value as u8])
+    }
+}
+
+/// Counter of the instructions executed since it was mapped, as a 64-bit
+/// value: low word at offset 0, high word at offset 4. Storing anything
+/// resets it to 0.
+#[derive(Debug, Default)]
+pub struct CycleCounter {
+    cycles: u64,
+}
+
+impl CycleCounter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Number of instructions counted so far.
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+}
+
+impl Device for CycleCounter {
+    fn size(&self) -> u32 {
+        8
+    }
+
+    fn read(&mut self, offset: u32, width: usize) -> io::Result<u32> {
+        let bytes = self.cycles.to_le_bytes();
+        let mut word = [0; 4];
+        word[..width].copy_from_slice(&bytes[offset as usize..offset as usize + width]);
+        Ok(u32::from_le_bytes(word))
+    }
+
+    fn write(&mut self, _offset: u32, _width: usize, _value: u32) -> io::Result<()> {
+        self.cycles = 0;
+        Ok(())
+    }
+
+    fn tick(&mut self) {
+        self.cycles += 1;
+    }
+
+    fn changes_on_tick(&self) -> bool {
+        true
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        self.cycles.to_le_bytes().to_vec()
+    }
+
+    fn restore_state(&mut self, state: &[u8]) -> io::Result<()> {
+        self.cycles = u64::from_le_bytes(state.try_into().map_err(|_| invalid_state())?);
+        Ok(())
+    }
+}
+
+/// Pseudo-random generator (xorshift32): every load from offset 0 returns
+/// the next number of the sequence, storing a value reseeds it. The
+/// sequence only depends on the seed, so runs are reproducible.
+#[derive(Debug, Clone)]
+pub struct Random {
+    state: u32,
+}
+
+impl Random {
+    pub fn new(seed: u32) -> Self {
+        let mut random = Random { state: 0 };
+        random.seed(seed);
+        random
+    }
+
+    fn seed(&mut self, seed: u32) {
+        // xorshift never leaves the all-zero state, so 0 cannot be a seed.
+        self.state = if seed == 0 { 0x2545_f491 } else { seed };
+    }
+}
+
+impl Device for Random {
+    fn size(&self) -> u32 {
+        4
+    }
+
+    fn read(&mut self, _offset: u32, width: usize) -> io::Result<u32> {
+        let mut x = self.state;
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.state = x;
+        Ok(if width == 4 {
+            x
+        } else {
+            x & ((1 << (8 * width)) - 1)
+        })
+    }
+
+    fn write(&mut self, _offset: u32, _width: usize, value: u32) -> io::Result<()> {
+        self.seed(value);
+        Ok(())
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        self.state.to_le_bytes().to_vec()
+    }
+
+    fn restore_state(&mut self, state: &[u8]) -> io::Result<()> {
+        self.seed(u32::from_le_bytes(
+            state.try_into().map_err(|_| invalid_state())?,
+        ));
+        Ok(())
+    }
+}
+
+/// Programmable timer requesting an interrupt on its line every `period`
+/// instructions. The period is read and written at offset 0, 0 stopping
+/// the timer; offset 4 holds the number of instructions since the last
+/// interrupt. Writing the period restarts the count.
+#[derive(Debug, Clone)]
+pub struct Timer {
+    line: u8,
+    period: u32,
+    count: u32,
+    fired: bool,
+}
+
+impl Timer {
+    /// Timer requesting the interrupt `line`, from 0 to 31, or
+    /// [MachineError::UnknownInterrupt] for another line.
+    pub fn new(line: u8, period: u32) -> Result<Self, MachineError> {
+        if line >= 32 {
+            return Err(MachineError::UnknownInterrupt { line });
+        }
+        Ok(Timer {
+            line,
+            period,
+            count: 0,
+            fired: false,
+        })
+    }
+}
+
+impl Device for Timer {
+    fn size(&self) -> u32 {
+        8
+    }
+
+    fn read(&mut self, offset: u32, _width: usize) -> io::Result<u32> {
+        Ok(if offset < 4 { self.period } else { self.count })
+    }
+
+    fn write(&mut self, offset: u32, _width: usize, value: u32) -> io::Result<()> {
+        if offset < 4 {
+            self.period = value;
+            self.count = 0;
+        }
+        Ok(())
+    }
+
+    fn tick(&mut self) {
+        if self.period == 0 {
+            return;
+        }
+        self.count += 1;
+        if self.count >= self.period {
+            self.count = 0;
+            self.fired = true;
+        }
+    }
+
+    fn changes_on_tick(&self) -> bool {
+        self.period != 0
+    }
+
+    fn take_interrupt(&mut self) -> Option<u8> {
+        std::mem::take(&mut self.fired).then_some(self.line)
+    }
+
+    fn interrupt_line(&self) -> Option<u8> {
+        Some(self.line)
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        let mut state = self.period.to_le_bytes().to_vec();
+        state.extend_from_slice(&self.count.to_le_bytes());
+        state.push(self.fired as u8);
+        state
+    }
+
+    fn restore_state(&mut self, state: &[u8]) -> io::Result<()> {
+        if state.len() != 9 {
+            return Err(invalid_state());
+        }
+        self.period = u32::from_le_bytes([state[0], state[1], state[2], state[3]]);
+        self.count = u32::from_le_bytes([state[4], state[5], state[6], state[7]]);
+        self.fired = state[8] != 0;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{assembler::assemble, machine::Machine};
+
+    fn machine(source: &str) -> Machine {
+        Machine::try_new(&assemble(source).unwrap().image).unwrap()
+    }
+
+    #[test]
+    fn overlapping_devices_are_errors() {
+        let mut machine = machine("exit");
+        machine.map_device(0x800, CycleCounter::new()).unwrap();
+        assert_eq!(
+            machine.map_device(0x804, Random::new(1)),
+            Err(MachineError::InvalidDeviceRange {
+                base: 0x804,
+                size: 4
+            })
+        );
+        assert_eq!(
+            machine.map_device(0x7fe, Random::new(1)),
+            Err(MachineError::InvalidDeviceRange {
+                base: 0x7fe,
+                size: 4
+            })
+        );
+        machine.map_device(0x808, Random::new(1)).unwrap();
+    }
+
+    #[test]
+    fn accesses_crossing_a_device_boundary_fault() {
+        let run = |source: String| {
+            let mut machine = machine(&source);
+            machine.map_device(0x800, Random::new(1)).unwrap();
+            machine.run_on(&mut io::sink())
+        };
+        // A word starting inside a device and one ending inside it.
+        for addr in [0x802, 0x7fe] {
+            let fault = |ip, opcode| MachineError::DeviceFault { ip, opcode, addr };
+            assert_eq!(
+                run(format!("loadimm r1, {addr}\nload r2, r1\nexit")),
+                Err(fault(4, 3))
+            );
+            assert_eq!(
+                run(format!(
+                    "loadimm r1, {addr}\nstoreh r1, r1\nstore r1, r1\nexit"
+                )),
+                Err(fault(7, 2))
+            );
+        }
+    }
+
+    #[test]
+    fn random_sequences_depend_on_the_seed() {
+        let sequence = |seed| {
+            let mut random = Random::new(seed);
+            (0..4)
+                .map(|_| random.read(0, 4).unwrap())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(sequence(7), sequence(7));
+        assert_ne!(sequence(7), sequence(8));
+        // Storing reseeds the generator.
+        let mut random = Random::new(8);
+        random.read(0, 4).unwrap();
+        random.write(0, 4, 7).unwrap();
+        assert_eq!(random.read(0, 4).unwrap(), sequence(7)[0]);
+        assert_eq!(Random::new(7).read(0, 1).unwrap(), sequence(7)[0] & 0xff);
+    }
+
+    #[test]
+    fn cycle_counters_count_completed_instructions() {
+        let mut machine = machine(
+            "loadimm r1, 0x800\nload r2, r1\nload r3, r1\n\
+             store r1, r1\nload r4, r1\nloadimm r5, 0x804\nload r5, r5\nexit",
+        );
+        machine.map_device(0x800, CycleCounter::new()).unwrap();
+        machine.run_on(&mut io::sink()).unwrap();
+        assert_eq!(machine.regs()[2..6], [1, 2, 1, 0]);
+        let counter = machine.unmap_device(0x800).unwrap();
+        assert_eq!(counter.save_state(), 5u64.to_le_bytes());
+    }
+
+    #[test]
+    fn console_end_of_input_fills_the_access_width() {
+        let mut machine = machine(
+            "loadimm r1, 0x800\nloadb r2, r1\nloadb r3, r1\nloadh r4, r1\n\
+             load r5, r1\nloadbs r6, r1\nexit",
+        );
+        let console = Console::new(io::Cursor::new(b"a".to_vec()), io::sink());
+        machine.map_device(0x800, console).unwrap();
+        machine.run_on(&mut io::sink()).unwrap();
+        assert_eq!(
+            machine.regs()[2..7],
+            [b'a' as u32, 0xff, 0xffff, 0xffff_ffff, 0xffff_ffff]
+        );
+    }
+}
@@ -0,0 +1,200 @@
+//! Disassembler for the [Machine](crate::Machine) instruction set.
+//!
+//! Decoding relies on [Instruction::decode_at], like
+//! [Machine::step_on](crate::Machine::step_on) does, with the same number
+//! of registers as the machine: a byte sequence is
+//! listed as an instruction exactly when the machine would execute it as
+//! one. Bytes the machine would reject (unknown opcode, register out of
+//! range or instruction cut by the end of the slice) are listed as `.byte`
+//! data, so that a listing can always be fed back to the
+//! [assembler](crate::assemble).
+
+use std::fmt;
+
+use crate::{
+    config::{MachineConfig, DEFAULT_REGISTERS},
+    instruction::Instruction,
+};
+
+/// Operand of a disassembled instruction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operand {
+    Reg(u8),
+    Imm(i32),
+}
+
+impl fmt::Display for Operand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Operand::Reg(r) => write!(f, "r{r}"),
+            Operand::Imm(v) => write!(f, "{v}"),
+        }
+    }
+}
+
+/// One line of a listing: a decoded instruction or a single data byte.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DisasmLine<'a> {
+    pub addr: usize,
+    pub bytes: &'a [u8],
+    /// Instruction mnemonic, or `.byte` for data.
+    pub mnemonic: &'static str,
+    pub operands: Vec<Operand>,
+    /// Decoded instruction, `None` for data.
+    pub instruction: Option<Instruction>,
+}
+
+impl DisasmLine<'_> {
+    /// `true` if the line holds a data byte rather than an instruction.
+    pub fn is_data(&self) -> bool {
+        self.instruction.is_none()
+    }
+}
+
+impl fmt::Display for DisasmLine<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let hex: Vec<String> = self.bytes.iter().map(|b| format!("{b:02x}")).collect();
+        write!(
+            f,
+            "{:#06x}:  {:<12} {}",
+            self.addr,
+            hex.join(" "),
+            self.mnemonic
+        )?;
+        for (i, op) in self.operands.iter().enumerate() {
+            write!(f, "{}{op}", if i == 0 { " " } else { ", " })?;
+        }
+        Ok(())
+    }
+}
+
+/// Iterator over the lines of a listing, created by [disassemble].
+pub struct Disassembler<'a> {
+    memory: &'a [u8],
+    addr: usize,
+    registers: usize,
+}
+
+/// Disassemble `memory` starting at address `start`, until its end, for a
+/// machine with the default configuration.
+pub fn disassemble(memory: &[u8], start: usize) -> Disassembler<'_> {
+    Disassembler {
+        memory,
+        addr: start,
+        registers: DEFAULT_REGISTERS,
+    }
+}
+
+/// Disassemble `memory` starting at address `start`, until its end, for a
+/// machine described by `config`, which bounds the register operands.
+pub fn disassemble_for<'a>(
+    memory: &'a [u8],
+    start: usize,
+    config: &MachineConfig,
+) -> Disassembler<'a> {
+    Disassembler {
+        memory,
+        addr: start,
+        registers: config.registers(),
+    }
+}
+
+/// Decode the line located at `addr` in `memory`, which must be in bounds,
+/// for a machine having `registers` registers.
+fn decode_line(memory: &[u8], addr: usize, registers: usize) -> DisasmLine<'_> {
+    match Instruction::decode_at(&memory[addr..], addr as u32, registers) {
+        Ok((instr, size)) => {
+            let mut operands: Vec<Operand> =
+                instr.registers().into_iter().map(Operand::Reg).collect();
+            // Show the absolute target of relative jumps, as the assembler
+            // expects it.
+            let next = (addr + size) as i32;
+            operands.extend(match instr.branch_offset() {
+                Some(offset) => Some(Operand::Imm(next + offset as i32)),
+                None => instr.immediate().map(Operand::Imm),
+            });
+            DisasmLine {
+                addr,
+                bytes: &memory[addr..addr + size],
+                mnemonic: instr.mnemonic(),
+                operands,
+                instruction: Some(instr),
+            }
+        }
+        Err(_) => DisasmLine {
+            addr,
+            bytes: &memory[addr..addr + 1],
+            mnemonic: ".byte",
+            operands: vec![Operand::Imm(memory[addr] as i32)],
+            instruction: None,
+        },
+    }
+}
+
+impl<'a> Iterator for Disassembler<'a> {
+    type Item = DisasmLine<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.addr >= self.memory.len() {
+            return None;
+        }
+        let line = decode_line(self.memory, self.addr, self.registers);
+        self.addr += line.bytes.len();
+        Some(line)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::{assemble, assemble_for};
+
+    fn listing(lines: Disassembler<'_>) -> Vec<String> {
+        lines
+            .map(|line| {
+                let operands: Vec<String> = line.operands.iter().map(|o| o.to_string()).collect();
+                format!("{} {}", line.mnemonic, operands.join(", "))
+                    .trim_end()
+                    .to_string()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn lists_instructions_and_data() {
+        let image = assemble("loop: loadimm r1, -2\nbnz r1, loop\nout number r1\n.byte 0xff")
+            .unwrap()
+            .image;
+        assert_eq!(
+            listing(disassemble(&image, 0)),
+            ["loadimm r1, -2", "bnz r1, 0", "out number r1", ".byte 255"]
+        );
+    }
+
+    #[test]
+    fn cut_instructions_are_data() {
+        let image = Instruction::LoadImm(1, 5).encode();
+        assert_eq!(
+            listing(disassemble(&image[..3], 0)),
+            [".byte 4", ".byte 1", ".byte 5"]
+        );
+    }
+
+    #[test]
+    fn register_count_comes_from_the_config() {
+        let config = MachineConfig::default().with_registers(32).unwrap();
+        let image = assemble_for("loadimm r20, 1\nexit", &config).unwrap().image;
+        assert_eq!(
+            listing(disassemble_for(&image, 0, &config)),
+            ["loadimm r20, 1", "exit"]
+        );
+        assert_eq!(listing(disassemble(&image, 0))[0], ".byte 4");
+    }
+
+    #[test]
+    fn display_shows_address_and_bytes() {
+        let image = Instruction::Sub(1, 2, 3).encode();
+        let line = disassemble(&image, 0).next().unwrap();
+        assert_eq!(line.to_string(), "0x0000:  05 01 02 03  sub r1, r2, r3");
+    }
+}
@@ -0,0 +1,495 @@
+//! Executable file format.
+//!
+//! An executable starts with the `VMEXEC` magic, a 16-bit format version,
+//! the 32-bit entry point and the 32-bit number of sections. Every section
+//! is made of a kind (`u8`), an address (`u32`), the length of its payload
+//! (`u32`) and its payload. All numbers are little-endian. Version 1 knows
+//! three kinds of sections:
+//!   - 1, load segment: bytes to copy in memory at the section address
+//!   - 2, symbol table: for every symbol, the length of its name (`u16`),
+//!     its UTF-8 name and its value (`u32`); the section address is 0
+//!   - 3, line table: for every instruction, its address (`u32`) and its
+//!     1-based source line (`u32`); the section address is 0
+//!
+//! Sections of other kinds are skipped, so that later versions may add
+//! optional sections.
+
+use std::{collections::BTreeMap, error::Error, fmt, fs, io, path::Path};
+
+use crate::{
+    assembler::{assemble, AsmError, Assembled},
+    machine::{Machine, SP},
+};
+
+const MAGIC: &[u8; 6] = b"VMEXEC";
+const VERSION: u16 = 1;
+const SEGMENT: u8 = 1;
+const SYMBOLS: u8 = 2;
+const LINES: u8 = 3;
+
+/// Bytes placed at `addr` when the executable is loaded.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Segment {
+    pub addr: u32,
+    pub data: Vec<u8>,
+}
+
+/// A program with its load layout, entry point and optional debug
+/// information.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Executable {
+    /// Initial IP.
+    pub entry: u32,
+    pub segments: Vec<Segment>,
+    /// Address or value of the symbols, empty if unknown.
+    pub symbols: BTreeMap<String, u32>,
+    /// Address and 1-based source line of every instruction, empty if
+    /// unknown.
+    pub lines: Vec<(u32, usize)>,
+}
+
+/// Error raised while decoding or loading an [Executable].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ExecutableError {
+    /// The data does not start with the executable magic.
+    BadMagic,
+    /// The executable was written in a format version this crate does not
+    /// know.
+    UnsupportedVersion(u16),
+    /// The data ends in the middle of a section.
+    Truncated,
+    /// A section has an invalid content.
+    Corrupted(&'static str),
+    /// A segment does not fit in the memory of the machine.
+    SegmentOutOfMemory { addr: u32, len: usize },
+    /// Two segments share some addresses.
+    OverlappingSegments { addr: u32 },
+    /// The entry point is outside of the memory of the machine.
+    EntryOutOfMemory { entry: u32 },
+}
+
+impl fmt::Display for ExecutableError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            ExecutableError::BadMagic => write!(f, "not an executable"),
+            ExecutableError::UnsupportedVersion(version) => {
+                write!(f, "unsupported executable version {version}")
+            }
+            ExecutableError::Truncated => write!(f, "truncated executable"),
+            ExecutableError::Corrupted(what) => write!(f, "corrupted executable: {what}"),
+            ExecutableError::SegmentOutOfMemory { addr, len } => write!(
+                f,
+                "segment of {len} bytes at {addr:#x} does not fit in the memory"
+            ),
+            ExecutableError::OverlappingSegments { addr } => {
+                write!(f, "segment at {addr:#x} overlaps another segment")
+            }
+            ExecutableError::EntryOutOfMemory { entry } => {
+                write!(f, "entry point {entry:#x} is outside of the memory")
+            }
+        }
+    }
+}
+
+impl Error for ExecutableError {}
+
+/// Error raised by [Executable::load].
+#[derive(Debug)]
+pub enum LoadError {
+    /// The file cannot be read.
+    Io(io::Error),
+    /// The assembler source does not assemble.
+    Asm(AsmError),
+    /// The executable cannot be decoded.
+    Executable(ExecutableError),
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoadError::Io(e) => write!(f, "{e}"),
+            LoadError::Asm(e) => write!(f, "{e}"),
+            LoadError::Executable(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl Error for LoadError {}
+
+/// Little-endian reader over an executable.
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], ExecutableError> {
+        if len > self.data.len() {
+            return Err(ExecutableError::Truncated);
+        }
+        let (bytes, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, ExecutableError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, ExecutableError> {
+        Ok(u16::from_le_bytes([self.u8()?, self.u8()?]))
+    }
+
+    fn u32(&mut self) -> Result<u32, ExecutableError> {
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(self.bytes(4)?);
+        Ok(u32::from_le_bytes(bytes))
+    }
+}
+
+impl Executable {
+    /// Executable loading the segments of `assembled`, with its symbols
+    /// and line table. The entry point is the `_start` label if
+    /// the source defines it, 0 otherwise.
+    pub fn from_assembled(assembled: &Assembled) -> Self {
+        Executable {
+            entry: assembled.symbols.get("_start").copied().unwrap_or(0),
+            segments: assembled.segments.clone(),
+            symbols: assembled.symbols.clone(),
+            lines: assembled.lines.clone(),
+        }
+    }
+
+    /// `true` if `data` starts like an executable.
+    pub fn is_executable(data: &[u8]) -> bool {
+        data.starts_with(MAGIC)
+    }
+
+    /// Decode `data` if it is an executable, or else take it as a raw
+    /// memory image loaded at address 0 and starting there.
+    pub fn from_bytes(data: Vec<u8>) -> Result<Self, ExecutableError> {
+        if Executable::is_executable(&data) {
+            return Executable::parse(&data);
+        }
+        Ok(Executable {
+            segments: vec![Segment { addr: 0, data }],
+            ..Executable::default()
+        })
+    }
+
+    /// Read the program stored at `path`: an assembler source if its
+    /// extension is `.s` or `.asm`, assembled for the default
+    /// configuration, an executable if it is `.vmx`, or else an executable
+    /// or a raw memory image, see [from_bytes](Executable::from_bytes).
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, LoadError> {
+        let path = path.as_ref();
+        let extension = path.extension().and_then(|e| e.to_str());
+        if let Some("s") | Some("asm") = extension {
+            let source = fs::read_to_string(path).map_err(LoadError::Io)?;
+            let assembled = assemble(&source).map_err(LoadError::Asm)?;
+            return Ok(Executable::from_assembled(&assembled));
+        }
+        let data = fs::read(path).map_err(LoadError::Io)?;
+        let exe = if extension == Some("vmx") {
+            Executable::parse(&data)
+        } else {
+            Executable::from_bytes(data)
+        };
+        exe.map_err(LoadError::Executable)
+    }
+
+    /// Encode the executable. Empty symbol and line tables are omitted.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut sections = Vec::new();
+        for segment in &self.segments {
+            sections.push((SEGMENT, segment.addr, segment.data.clone()));
+        }
+        if !self.symbols.is_empty() {
+            let mut table = Vec::new();
+            for (name, value) in &self.symbols {
+                table.extend_from_slice(&(name.len() as u16).to_le_bytes());
+                table.extend_from_slice(name.as_bytes());
+                table.extend_from_slice(&value.to_le_bytes());
+            }
+            sections.push((SYMBOLS, 0, table));
+        }
+        if !self.lines.is_empty() {
+            let mut table = Vec::new();
+            for &(addr, line) in &self.lines {
+                table.extend_from_slice(&addr.to_le_bytes());
+                table.extend_from_slice(&(line as u32).to_le_bytes());
+            }
+            sections.push((LINES, 0, table));
+        }
+
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(MAGIC);
+        bytes.extend_from_slice(&VERSION.to_le_bytes());
+        bytes.extend_from_slice(&self.entry.to_le_bytes());
+        bytes.extend_from_slice(&(sections.len() as u32).to_le_bytes());
+        for (kind, addr, payload) in sections {
+            bytes.push(kind);
+            bytes.extend_from_slice(&addr.to_le_bytes());
+            bytes.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+            bytes.extend_from_slice(&payload);
+        }
+        bytes
+    }
+
+    /// Decode an executable produced by [to_bytes](Executable::to_bytes).
+    pub fn parse(data: &[u8]) -> Result<Self, ExecutableError> {
+        let mut reader = Reader { data };
+        if reader.bytes(MAGIC.len()).ok() != Some(MAGIC.as_slice()) {
+            return Err(ExecutableError::BadMagic);
+        }
+        let version = reader.u16()?;
+        if version != VERSION {
+            return Err(ExecutableError::UnsupportedVersion(version));
+        }
+        let mut exe = Executable {
+            entry: reader.u32()?,
+            ..Executable::default()
+        };
+        let count = reader.u32()?;
+        for _ in 0..count {
+            let kind = reader.u8()?;
+            let addr = reader.u32()?;
+            let len = reader.u32()? as usize;
+            let mut payload = Reader {
+                data: reader.bytes(len)?,
+            };
+            match kind {
+                SEGMENT => exe.segments.push(Segment {
+                    addr,
+                    data: payload.data.to_vec(),
+                }),
+                SYMBOLS => {
+                    while !payload.data.is_empty() {
+                        let len = payload.u16()? as usize;
+                        let name = std::str::from_utf8(payload.bytes(len)?)
+                            .map_err(|_| ExecutableError::Corrupted("invalid symbol name"))?;
+                        exe.symbols.insert(name.to_string(), payload.u32()?);
+                    }
+                }
+                LINES => {
+                    while !payload.data.is_empty() {
+                        let addr = payload.u32()?;
+                        exe.lines.push((addr, payload.u32()? as usize));
+                    }
+                }
+                _ => {}
+            }
+        }
+        if !reader.data.is_empty() {
+            return Err(ExecutableError::Corrupted(
+                "trailing bytes after the sections",
+            ));
+        }
+        Ok(exe)
+    }
+}
+
+impl Machine {
+    /// Copy the segments of `exe` in memory, set the IP to its entry point
+    /// and the stack pointer to the end of the memory. If `exe` defines a
+    /// `_stack_limit` symbol, it becomes the
+    /// [stack limit](Machine::set_stack_limit). Nothing is changed
+    /// if a segment does not fit in memory, if segments overlap or if the
+    /// entry point is outside of the memory.
+    pub fn load_executable(&mut self, exe: &Executable) -> Result<(), ExecutableError> {
+        let size = self.config.memory_size();
+        let mut ranges = Vec::new();
+        for segment in &exe.segments {
+            let end = segment.addr as u64 + segment.data.len() as u64;
+            if end > size {
+                return Err(ExecutableError::SegmentOutOfMemory {
+                    addr: segment.addr,
+                    len: segment.data.len(),
+                });
+            }
+            ranges.push((segment.addr as u64, end));
+        }
+        ranges.sort_unstable();
+        if let Some(pair) = ranges.windows(2).find(|pair| pair[1].0 < pair[0].1) {
+            return Err(ExecutableError::OverlappingSegments {
+                addr: pair[1].0 as u32,
+            });
+        }
+        if exe.entry as u64 >= size {
+            return Err(ExecutableError::EntryOutOfMemory { entry: exe.entry });
+        }
+        for segment in &exe.segments {
+            self.mach_mem.write(segment.addr, &segment.data);
+        }
+        self.regs[0] = exe.entry;
+        self.regs[SP] = self.config.stack_top();
+        if let Some(&limit) = exe.symbols.get("_stack_limit") {
+            self.stack_limit = limit;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::MachineConfig, machine::MachineError};
+
+    #[test]
+    fn bytes_are_an_executable_or_a_raw_image() {
+        let assembled = assemble("nop: exit\n_start: jmp nop").unwrap();
+        let exe = Executable::from_assembled(&assembled);
+        assert_eq!(exe.entry, 1);
+        assert_eq!(Executable::from_bytes(exe.to_bytes()), Ok(exe));
+
+        let raw = Executable::from_bytes(vec![7]).unwrap();
+        assert_eq!(raw.entry, 0);
+        assert_eq!(
+            raw.segments,
+            [Segment {
+                addr: 0,
+                data: vec![7]
+            }]
+        );
+        let mut truncated = Executable::from_assembled(&assembled).to_bytes();
+        truncated.pop();
+        assert_eq!(
+            Executable::from_bytes(truncated),
+            Err(ExecutableError::Truncated)
+        );
+    }
+
+    fn segment(addr: u32, data: &[u8]) -> Segment {
+        Segment {
+            addr,
+            data: data.to_vec(),
+        }
+    }
+
+    #[test]
+    fn invalid_layouts_leave_the_machine_alone() {
+        let cases = [
+            (
+                vec![segment(0, &[7]), segment(4094, &[1, 2, 3])],
+                0,
+                ExecutableError::SegmentOutOfMemory { addr: 4094, len: 3 },
+            ),
+            (
+                vec![segment(0x10, &[1, 2, 3, 4]), segment(0x12, &[5])],
+                0,
+                ExecutableError::OverlappingSegments { addr: 0x12 },
+            ),
+            (
+                vec![segment(0, &[7])],
+                4096,
+                ExecutableError::EntryOutOfMemory { entry: 4096 },
+            ),
+        ];
+        for (segments, entry, error) in cases {
+            let mut machine = Machine::try_new(&[9]).unwrap();
+            let exe = Executable {
+                entry,
+                segments,
+                ..Executable::default()
+            };
+            assert_eq!(machine.load_executable(&exe), Err(error));
+            assert_eq!(machine.memory()[0], 9);
+            assert!(machine.regs().iter().all(|&r| r == 0));
+        }
+    }
+
+    #[test]
+    fn loading_sets_the_ip_and_the_stack() {
+        let assembled =
+            assemble("loop: push r1\n_start: jmp loop\n.org 0xff0\n_stack_limit:").unwrap();
+        let exe = Executable::from_assembled(&assembled);
+        let mut machine = Machine::try_new(&[]).unwrap();
+        machine.set_reg(SP, 12).unwrap();
+        machine.load_executable(&exe).unwrap();
+        assert_eq!(machine.regs()[0], 2);
+        assert_eq!(machine.regs()[SP], 4096);
+        // Four words fit above `_stack_limit`.
+        assert_eq!(
+            machine.run_on(&mut io::sink()),
+            Err(MachineError::StackOverflow {
+                ip: 0,
+                opcode: 36,
+                sp: 0xff0
+            })
+        );
+        assert_eq!(machine.regs()[SP], 0xff0);
+
+        let mut machine = MachineConfig::new()
+            .with_memory_size(8190)
+            .unwrap()
+            .build(&[])
+            .unwrap();
+        machine.load_executable(&exe).unwrap();
+        assert_eq!(machine.regs()[SP], 8188);
+    }
+
+    #[test]
+    fn malformed_executables_are_errors() {
+        let bytes = Executable::from_assembled(&assemble("_start: exit").unwrap()).to_bytes();
+        let mut other_version = bytes.clone();
+        other_version[6] = 2;
+        assert_eq!(
+            Executable::parse(b"VMEXEX\x01\x00"),
+            Err(ExecutableError::BadMagic)
+        );
+        assert_eq!(
+            Executable::parse(&other_version),
+            Err(ExecutableError::UnsupportedVersion(2))
+        );
+        assert_eq!(
+            Executable::parse(&bytes[..bytes.len() - 1]),
+            Err(ExecutableError::Truncated)
+        );
+        assert_eq!(
+            Executable::parse(&bytes[..7]),
+            Err(ExecutableError::Truncated)
+        );
+    }
+
+    #[test]
+    fn load_checks_executable_files() {
+        let dir = std::env::temp_dir().join(format!("vm-executable-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let load = |name: &str, data: &[u8]| {
+            let path = dir.join(name);
+            fs::write(&path, data).unwrap();
+            Executable::load(&path)
+        };
+        let exe = Executable::from_assembled(&assemble("_start: exit").unwrap());
+        let bytes = exe.to_bytes();
+        let mut other_version = bytes.clone();
+        other_version[6] = 2;
+
+        assert_eq!(load("good.vmx", &bytes).unwrap(), exe);
+        assert!(matches!(
+            load("magic.vmx", b"VMEXEX\x01\x00"),
+            Err(LoadError::Executable(ExecutableError::BadMagic))
+        ));
+        assert!(matches!(
+            load("version.vmx", &other_version),
+            Err(LoadError::Executable(ExecutableError::UnsupportedVersion(
+                2
+            )))
+        ));
+        assert!(matches!(
+            load("truncated.vmx", &bytes[..bytes.len() - 1]),
+            Err(LoadError::Executable(ExecutableError::Truncated))
+        ));
+        // Without the extension, anything else is a raw image.
+        assert_eq!(
+            load("raw.bin", b"VMEXEX").unwrap().segments,
+            [segment(0, b"VMEXEX")]
+        );
+        assert_eq!(load("source.s", b"_start: exit").unwrap(), exe);
+        assert!(matches!(load("bad.s", b"nope"), Err(LoadError::Asm(_))));
+        assert!(matches!(
+            Executable::load(dir.join("missing.vmx")),
+            Err(LoadError::Io(_))
+        ));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
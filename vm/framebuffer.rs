@@ -0,0 +1,191 @@
+//! Framebuffer device for the 8x8 LED matrix of `tp_led_matrix`.
+//!
+//! The first 192 bytes of the device are the pixels, laid out exactly like
+//! `Image::as_ref`: row-major, 3 bytes (red, green, blue) per pixel. Storing
+//! anything to the present register hands the completed image to a host
+//! callback, which may display it or forward it to the board. Loading from
+//! the present register returns the number of images presented so far.
+//!
+//! The device does not depend on `tp_led_matrix`: it works with any image
+//! type giving access to its 192 bytes, `tp_led_matrix::Image` included.
+//!
+//! ```text
+//!         .equ FB, 0xe00
+//!         loadimm r1, FB + 27       ; pixel (1, 1), at 3 * (8 * 1 + 1)
+//!         loadimm r2, 255
+//!         storeb  r1, r2            ; full red
+//!         loadimm r1, FB + 192
+//!         store   r1, r2            ; present
+//! ```
+
+use std::io;
+
+use crate::device::{invalid_state, Device};
+
+/// Offset of the present register.
+pub const PRESENT_OFFSET: u32 = 192;
+/// Number of bytes of address space used by a [Framebuffer].
+pub const FRAMEBUFFER_SIZE: u32 = PRESENT_OFFSET + 4;
+
+/// Check that an access to the pixels does not spill over the present
+/// register.
+fn pixel_offset(offset: u32, width: usize) -> io::Result<usize> {
+    let offset = offset as usize;
+    if offset + width > PRESENT_OFFSET as usize {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "access straddles the pixels and the present register",
+        ));
+    }
+    Ok(offset)
+}
+
+/// Memory-mapped 8x8 framebuffer drawing into an image of type `I`, and
+/// calling `F` with it whenever the program writes to the present register.
+pub struct Framebuffer<I, F> {
+    image: I,
+    present: F,
+    frames: u32,
+}
+
+impl<I, F> Framebuffer<I, F>
+where
+    I: AsRef<[u8; 192]> + AsMut<[u8; 192]> + Default,
+    F: FnMut(&I) -> io::Result<()>,
+{
+    pub fn new(present: F) -> Self {
+        Framebuffer {
+            image: I::default(),
+            present,
+            frames: 0,
+        }
+    }
+
+    /// Image being drawn, which may not have been presented yet.
+    pub fn image(&self) -> &I {
+        &self.image
+    }
+}
+
+impl<I, F> Device for Framebuffer<I, F>
+where
+    I: AsRef<[u8; 192]> + AsMut<[u8; 192]> + Default,
+    F: FnMut(&I) -> io::Result<()>,
+{
+    fn size(&self) -> u32 {
+        FRAMEBUFFER_SIZE
+    }
+
+    fn read(&mut self, offset: u32, width: usize) -> io::Result<u32> {
+        if offset >= PRESENT_OFFSET {
+            return Ok(self.frames);
+        }
+        let offset = pixel_offset(offset, width)?;
+        let mut word = [0; 4];
+        let pixels: &[u8; 192] = self.image.as_ref();
+        word[..width].copy_from_slice(&pixels[offset..offset + width]);
+        Ok(u32::from_le_bytes(word))
+    }
+
+    fn write(&mut self, offset: u32, width: usize, value: u32) -> io::Result<()> {
+        if offset >= PRESENT_OFFSET {
+            self.frames = self.frames.wrapping_add(1);
+            return (self.present)(&self.image);
+        }
+        let offset = pixel_offset(offset, width)?;
+        let pixels: &mut [u8; 192] = self.image.as_mut();
+        pixels[offset..offset + width].copy_from_slice(&value.to_le_bytes()[..width]);
+        Ok(())
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        let pixels: &[u8; 192] = self.image.as_ref();
+        let mut state = pixels.to_vec();
+        state.extend_from_slice(&self.frames.to_le_bytes());
+        state
+    }
+
+    fn restore_state(&mut self, state: &[u8]) -> io::Result<()> {
+        if state.len() != FRAMEBUFFER_SIZE as usize {
+            return Err(invalid_state());
+        }
+        let (pixels, frames) = state.split_at(PRESENT_OFFSET as usize);
+        let image: &mut [u8; 192] = self.image.as_mut();
+        image.copy_from_slice(pixels);
+        self.frames = u32::from_le_bytes(frames.try_into().map_err(|_| invalid_state())?);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, io, rc::Rc};
+
+    use super::*;
+    use crate::{assembler::assemble, machine::Machine};
+
+    /// Stand-in for `tp_led_matrix::Image`.
+    struct Pixels([u8; 192]);
+
+    impl Default for Pixels {
+        fn default() -> Self {
+            Pixels([0; 192])
+        }
+    }
+
+    impl AsRef<[u8; 192]> for Pixels {
+        fn as_ref(&self) -> &[u8; 192] {
+            &self.0
+        }
+    }
+
+    impl AsMut<[u8; 192]> for Pixels {
+        fn as_mut(&mut self) -> &mut [u8; 192] {
+            &mut self.0
+        }
+    }
+
+    #[test]
+    fn programs_draw_and_present() {
+        let source = "
+            .equ FB, 0xe00
+            loadimm r1, FB + 27
+            loadimm r2, 255
+            storeb  r1, r2
+            loadimm r1, FB + 192
+            store   r1, r2
+            load    r3, r1
+            exit
+        ";
+        let presented = Rc::new(RefCell::new(Vec::new()));
+        let frames = presented.clone();
+        let framebuffer = Framebuffer::new(move |image: &Pixels| {
+            frames.borrow_mut().push(image.0.to_vec());
+            Ok(())
+        });
+        let mut machine = Machine::try_new(&assemble(source).unwrap().image).unwrap();
+        machine.map_device(0xe00, framebuffer).unwrap();
+        machine.run_on(&mut io::sink()).unwrap();
+        assert_eq!(machine.regs()[3], 1);
+        let presented = presented.borrow();
+        assert_eq!(presented.len(), 1);
+        let lit: Vec<usize> = (0..192).filter(|&i| presented[0][i] != 0).collect();
+        assert_eq!(lit, [27]);
+        assert_eq!(presented[0][27], 255);
+    }
+
+    #[test]
+    fn accesses_cannot_straddle_the_present_register() {
+        let mut framebuffer = Framebuffer::new(|_: &Pixels| Ok(()));
+        assert!(framebuffer.write(190, 4, 0).is_err());
+        framebuffer.write(188, 4, 0x0403_0201).unwrap();
+        assert_eq!(framebuffer.read(188, 4).unwrap(), 0x0403_0201);
+        assert_eq!(&framebuffer.image().0[188..], [1, 2, 3, 4]);
+
+        let state = framebuffer.save_state();
+        let mut copy = Framebuffer::new(|_: &Pixels| Ok(()));
+        copy.restore_state(&state).unwrap();
+        assert_eq!(copy.image().0, framebuffer.image().0);
+        assert!(copy.restore_state(&state[1..]).is_err());
+    }
+}
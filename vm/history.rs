@@ -0,0 +1,191 @@
+//! Undo log allowing to run a [Machine] backward.
+//!
+//! When a history limit is set with
+//! [Machine::set_history_limit], every executed instruction, including a
+//! faulting one, and every interrupt entry records what it changed:
+//! registers, memory, input position, call stack, cycles spent, interrupt
+//! state and the state of the devices it accessed or ticked, saved only
+//! then. [Machine::step_back] undoes the last recorded instruction. Input
+//! bytes consumed by undone instructions are read again by the next input
+//! instructions, so that executing again gives the same results. Output
+//! cannot be taken back.
+
+use crate::{
+    machine::{Effects, Machine, MachineError},
+    trace::{MemWrite, RegWrite},
+};
+
+/// Changes made by one instruction.
+pub(crate) struct Undo {
+    ip: u32,
+    cycles: u64,
+    reg_writes: Vec<RegWrite>,
+    mem_writes: Vec<MemWrite>,
+    input_peek: Option<u8>,
+    input_eof: bool,
+    interrupts_enabled: bool,
+    pending_interrupts: u32,
+    /// Input bytes consumed by the instruction, except the read-ahead one.
+    taken: Vec<u8>,
+    call_stack_len: usize,
+    call_stack_top: Option<u32>,
+    pub(crate) devices: Vec<(u32, Vec<u8>)>,
+}
+
+impl Machine {
+    /// Capture the state not covered by [Effects] before executing the
+    /// instruction located at `ip`.
+    pub(crate) fn begin_undo(&self, ip: u32) -> Undo {
+        Undo {
+            ip,
+            cycles: self.cycles,
+            reg_writes: Vec::new(),
+            mem_writes: Vec::new(),
+            input_peek: self.input_peek,
+            input_eof: self.input_eof,
+            interrupts_enabled: self.interrupts_enabled,
+            pending_interrupts: self.pending_interrupts,
+            taken: Vec::new(),
+            call_stack_len: self.call_stack.len(),
+            call_stack_top: self.call_stack.last().copied(),
+            devices: Vec::new(),
+        }
+    }
+
+    /// Record the instruction started with `undo`, which had `effects`.
+    pub(crate) fn end_undo(&mut self, mut undo: Undo, effects: &mut Effects) {
+        undo.reg_writes = effects.reg_writes.clone();
+        undo.mem_writes = effects.mem_writes.clone();
+        undo.taken = effects.taken.clone();
+        undo.devices = effects.devices.take().unwrap_or_default();
+        if self.history.len() == self.history_limit {
+            self.history.pop_front();
+        }
+        self.history.push_back(undo);
+    }
+
+    /// Keep the changes of the last `limit` instructions to be able to
+    /// undo them. A limit of 0, the default, disables the history. The
+    /// machine of a [Multicore](crate::Multicore) cannot keep a history,
+    /// and raises [MachineError::HistoryUnavailable] for any other limit.
+    pub fn set_history_limit(&mut self, limit: usize) -> Result<(), MachineError> {
+        if self.multicore && limit > 0 {
+            return Err(MachineError::HistoryUnavailable);
+        }
+        self.history_limit = limit;
+        while self.history.len() > limit {
+            self.history.pop_front();
+        }
+        Ok(())
+    }
+
+    /// Number of instructions which can currently be undone.
+    pub fn history_len(&self) -> usize {
+        self.history.len()
+    }
+
+    /// Undo the last executed instruction, bringing the IP back to it.
+    /// Returns `false` if the history is empty.
+    ///
+    /// Registers and memory modified with [set_reg](Machine::set_reg) and
+    /// [set_memory](Machine::set_memory) are not part of the history.
+    pub fn step_back(&mut self) -> bool {
+        let Some(undo) = self.history.pop_back() else {
+            return false;
+        };
+        // Device writes are undone by restoring the device states.
+        for w in undo.mem_writes.iter().rev().filter(|w| !w.device) {
+            self.mach_mem.write(w.addr, &w.old);
+        }
+        for w in undo.reg_writes.iter().rev() {
+            self.regs[w.reg as usize] = w.old;
+        }
+        self.regs[0] = undo.ip;
+        self.cycles = undo.cycles;
+        for &b in undo.taken.iter().rev() {
+            self.input_replay.push_front(b);
+        }
+        self.input_peek = undo.input_peek;
+        self.input_eof = undo.input_eof;
+        self.interrupts_enabled = undo.interrupts_enabled;
+        self.pending_interrupts = undo.pending_interrupts;
+        self.call_stack
+            .truncate(undo.call_stack_len.saturating_sub(1));
+        self.call_stack.extend(undo.call_stack_top);
+        for (base, state) in undo.devices {
+            // Fails only for a device unmapped since, which is left alone.
+            let _ = self.bus.restore_state(base, &state);
+        }
+        true
+    }
+
+    /// Step back until the IP reaches `addr`, undoing at least one
+    /// instruction. Returns `false` if the history is exhausted first.
+    pub fn run_back_to(&mut self, addr: u32) -> bool {
+        while self.step_back() {
+            if self.regs[0] == addr {
+                return true;
+            }
+        }
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use crate::{
+        assembler::assemble,
+        device::{CycleCounter, Random},
+        machine::{Machine, MachineError},
+    };
+
+    #[test]
+    fn device_states_are_saved_when_accessed() {
+        let source = "
+            loadimm r1, 0x800
+            load    r2, r1
+            exit
+        ";
+        let mut machine = Machine::try_new(&assemble(source).unwrap().image).unwrap();
+        machine.map_device(0x800, Random::new(1)).unwrap();
+        machine.map_device(0x900, CycleCounter::new()).unwrap();
+        machine.set_history_limit(10).unwrap();
+        machine.step_on(&mut io::sink()).unwrap();
+        machine.step_on(&mut io::sink()).unwrap();
+        let first = machine.regs()[2];
+        // Only the ticking counter is saved for the first instruction.
+        let saved = |machine: &Machine, index: usize| -> Vec<u32> {
+            machine.history[index]
+                .devices
+                .iter()
+                .map(|&(base, _)| base)
+                .collect()
+        };
+        assert_eq!(saved(&machine, 0), [0x900]);
+        assert_eq!(saved(&machine, 1), [0x800, 0x900]);
+
+        assert!(machine.step_back());
+        machine.step_on(&mut io::sink()).unwrap();
+        assert_eq!(machine.regs()[2], first);
+        let mut counter = machine.unmap_device(0x900).unwrap();
+        assert_eq!(counter.read(0, 4).unwrap(), 2);
+    }
+
+    #[test]
+    fn cycles_are_rewound() {
+        // sub r1, r1, r0; exit
+        let mut machine = Machine::try_new(&[5, 1, 1, 0, 7]).unwrap();
+        machine.set_cost(5, 3).unwrap();
+        machine.set_history_limit(10).unwrap();
+        machine.step_on(&mut io::sink()).unwrap();
+        assert_eq!(machine.cycles(), 3);
+        assert!(machine.step_back());
+        assert_eq!(machine.cycles(), 0);
+        assert_eq!(
+            machine.set_cost(5, 0),
+            Err(MachineError::InvalidCost { opcode: 5 })
+        );
+    }
+}
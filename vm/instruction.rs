@@ -0,0 +1,651 @@
+use std::fmt;
+
+use crate::{config::DEFAULT_REGISTERS, machine::MachineError};
+
+const NOT: u8 = 26;
+const BZ: u8 = 32;
+const BNZ: u8 = 33;
+const CALL: u8 = 34;
+const RET: u8 = 35;
+const PUSH: u8 = 36;
+const POP: u8 = 37;
+const STOREB: u8 = 38;
+const STOREH: u8 = 39;
+const LOADB: u8 = 40;
+const LOADBS: u8 = 41;
+const LOADH: u8 = 42;
+const LOADHS: u8 = 43;
+const EI: u8 = 44;
+const DI: u8 = 45;
+const RETI: u8 = 46;
+const TRAP: u8 = 47;
+const CAS: u8 = 48;
+const FADD: u8 = 49;
+
+/// A decoded machine instruction. Register operands are register numbers,
+/// guaranteed to be lower than the number of registers when the instruction
+/// comes from [Instruction::decode].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Instruction {
+    /// `move if rA, rB, rC`: rA <- rB if rC is not zero.
+    MoveIf(u8, u8, u8),
+    /// `store rA, rB`: writes rB at the address contained in rA.
+    Store(u8, u8),
+    /// `load rA, rB`: rA <- the word at the address contained in rB.
+    Load(u8, u8),
+    /// `loadimm rA, imm`: rA <- imm, sign-extended.
+    LoadImm(u8, i16),
+    /// `sub rA, rB, rC`: rA <- rB - rC.
+    Sub(u8, u8, u8),
+    /// `out rA`: prints the character whose code is the low byte of rA.
+    Out(u8),
+    /// `exit`: terminates the program.
+    Exit,
+    /// `out number rA`: prints rA as a signed decimal number.
+    OutNumber(u8),
+    /// `in rA`: rA <- the next input byte, or -1 at the end of the input.
+    In(u8),
+    /// `in number rA`: rA <- the next signed decimal number of the input,
+    /// skipping leading whitespace, or 0 at the end of the input.
+    InNumber(u8),
+    /// `eof rA`: rA <- 1 if the last input instruction reached the end of
+    /// the input, 0 otherwise.
+    Eof(u8),
+    /// `op rA, rB, rC`: rA <- rB op rC, for every [ArithOp].
+    Arith(ArithOp, u8, u8, u8),
+    /// `not rA, rB`: rA <- bitwise complement of rB.
+    Not(u8, u8),
+    /// `bz rA, offset`: jumps if rA is zero. The offset is relative to the
+    /// address of the next instruction.
+    Bz(u8, i16),
+    /// `bnz rA, offset`: jumps if rA is not zero. The offset is relative to
+    /// the address of the next instruction.
+    Bnz(u8, i16),
+    /// `call offset`: pushes the address of the next instruction on the
+    /// stack and jumps to it plus `offset`.
+    Call(i16),
+    /// `ret`: pops an address from the stack and jumps to it.
+    Ret,
+    /// `push rA`: pushes rA on the stack.
+    Push(u8),
+    /// `pop rA`: pops the top of the stack into rA.
+    Pop(u8),
+    /// `storeb rA, rB`: writes the low byte of rB at the address in rA.
+    StoreB(u8, u8),
+    /// `storeh rA, rB`: writes the low halfword of rB at the address in rA.
+    StoreH(u8, u8),
+    /// `loadb rA, rB`: rA <- the byte at the address in rB, zero-extended.
+    LoadB(u8, u8),
+    /// `loadbs rA, rB`: rA <- the byte at the address in rB, sign-extended.
+    LoadBs(u8, u8),
+    /// `loadh rA, rB`: rA <- the halfword at the address in rB,
+    /// zero-extended.
+    LoadH(u8, u8),
+    /// `loadhs rA, rB`: rA <- the halfword at the address in rB,
+    /// sign-extended.
+    LoadHs(u8, u8),
+    /// `ei`: enables interrupts.
+    Ei,
+    /// `di`: disables interrupts.
+    Di,
+    /// `reti`: pops an address from the stack, jumps to it and enables
+    /// interrupts, returning from an interrupt handler.
+    Reti,
+    /// `trap n`: calls the host handler of trap number `n`.
+    Trap(u8),
+    /// `cas rA, rB, rC`: atomically, if the word at the address in rB
+    /// equals rA, writes rC there. In both cases rA <- the previous word.
+    Cas(u8, u8, u8),
+    /// `fadd rA, rB, rC`: atomically adds rC to the word at the address in
+    /// rB. rA <- the previous word.
+    Fadd(u8, u8, u8),
+}
+
+/// Arithmetic, bitwise and comparison operations sharing the three-register
+/// encoding of `sub`: `opcode rA rB rC`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArithOp {
+    Add,
+    Mul,
+    /// Unsigned division.
+    DivU,
+    /// Signed division, rounding toward zero.
+    DivS,
+    /// Unsigned remainder.
+    ModU,
+    /// Signed remainder, with the sign of the dividend.
+    ModS,
+    And,
+    Or,
+    Xor,
+    /// Logical left shift by rC modulo 32.
+    Shl,
+    /// Logical right shift by rC modulo 32.
+    Shr,
+    /// Arithmetic right shift by rC modulo 32.
+    Sar,
+    /// Left rotation by rC modulo 32.
+    Rol,
+    /// Right rotation by rC modulo 32.
+    Ror,
+    /// 1 if rB equals rC, 0 otherwise.
+    Seq,
+    /// 1 if rB is lower than rC as signed numbers, 0 otherwise.
+    Slt,
+    /// 1 if rB is lower than rC as unsigned numbers, 0 otherwise.
+    Sltu,
+    /// 1 if rB is lower than or equal to rC as signed numbers, 0 otherwise.
+    Sle,
+    /// 1 if rB is lower than or equal to rC as unsigned numbers, 0
+    /// otherwise.
+    Sleu,
+}
+
+impl ArithOp {
+    /// Every operation, in opcode order.
+    pub const ALL: [ArithOp; 19] = [
+        ArithOp::Add,
+        ArithOp::Mul,
+        ArithOp::DivU,
+        ArithOp::DivS,
+        ArithOp::ModU,
+        ArithOp::ModS,
+        ArithOp::And,
+        ArithOp::Or,
+        ArithOp::Xor,
+        ArithOp::Shl,
+        ArithOp::Shr,
+        ArithOp::Sar,
+        ArithOp::Rol,
+        ArithOp::Ror,
+        ArithOp::Seq,
+        ArithOp::Slt,
+        ArithOp::Sltu,
+        ArithOp::Sle,
+        ArithOp::Sleu,
+    ];
+
+    pub fn opcode(self) -> u8 {
+        match self as u8 {
+            // `not` sits between the rotations and the comparisons.
+            n @ 0..=13 => 12 + n,
+            n => 13 + n,
+        }
+    }
+
+    pub fn from_opcode(opcode: u8) -> Option<ArithOp> {
+        Self::ALL.into_iter().find(|op| op.opcode() == opcode)
+    }
+
+    pub fn mnemonic(self) -> &'static str {
+        match self {
+            ArithOp::Add => "add",
+            ArithOp::Mul => "mul",
+            ArithOp::DivU => "divu",
+            ArithOp::DivS => "divs",
+            ArithOp::ModU => "modu",
+            ArithOp::ModS => "mods",
+            ArithOp::And => "and",
+            ArithOp::Or => "or",
+            ArithOp::Xor => "xor",
+            ArithOp::Shl => "shl",
+            ArithOp::Shr => "shr",
+            ArithOp::Sar => "sar",
+            ArithOp::Rol => "rol",
+            ArithOp::Ror => "ror",
+            ArithOp::Seq => "seq",
+            ArithOp::Slt => "slt",
+            ArithOp::Sltu => "sltu",
+            ArithOp::Sle => "sle",
+            ArithOp::Sleu => "sleu",
+        }
+    }
+
+    pub fn from_mnemonic(mnemonic: &str) -> Option<ArithOp> {
+        Self::ALL.into_iter().find(|op| op.mnemonic() == mnemonic)
+    }
+
+    /// Compute `b op c`, or `None` for a division by zero. Overflows wrap
+    /// around.
+    pub fn apply(self, b: u32, c: u32) -> Option<u32> {
+        let (sb, sc) = (b as i32, c as i32);
+        Some(match self {
+            ArithOp::Add => b.wrapping_add(c),
+            ArithOp::Mul => b.wrapping_mul(c),
+            ArithOp::DivU => b.checked_div(c)?,
+            ArithOp::DivS if sc == 0 => return None,
+            ArithOp::DivS => sb.wrapping_div(sc) as u32,
+            ArithOp::ModU => b.checked_rem(c)?,
+            ArithOp::ModS if sc == 0 => return None,
+            ArithOp::ModS => sb.wrapping_rem(sc) as u32,
+            ArithOp::And => b & c,
+            ArithOp::Or => b | c,
+            ArithOp::Xor => b ^ c,
+            ArithOp::Shl => b << (c % 32),
+            ArithOp::Shr => b >> (c % 32),
+            ArithOp::Sar => (sb >> (c % 32)) as u32,
+            ArithOp::Rol => b.rotate_left(c % 32),
+            ArithOp::Ror => b.rotate_right(c % 32),
+            ArithOp::Seq => (b == c) as u32,
+            ArithOp::Slt => (sb < sc) as u32,
+            ArithOp::Sltu => (b < c) as u32,
+            ArithOp::Sle => (sb <= sc) as u32,
+            ArithOp::Sleu => (b <= c) as u32,
+        })
+    }
+}
+
+impl Instruction {
+    /// Decode the instruction located at `addr` in `memory`, for a machine
+    /// with the default number of registers. On success, the instruction
+    /// and its size in bytes are returned.
+    pub fn decode(memory: &[u8], addr: usize) -> Result<(Instruction, usize), MachineError> {
+        let bytes = memory.get(addr..).unwrap_or_default();
+        Self::decode_at(bytes, addr as u32, DEFAULT_REGISTERS)
+    }
+
+    /// Decode the instruction starting at the beginning of `bytes`, which
+    /// are located at address `ip` in a machine having `registers`
+    /// registers. `bytes` may be longer than the instruction, and ends
+    /// where the memory ends.
+    pub fn decode_at(
+        bytes: &[u8],
+        ip: u32,
+        registers: usize,
+    ) -> Result<(Instruction, usize), MachineError> {
+        let opcode = *bytes
+            .first()
+            .ok_or(MachineError::NoEquivalentInstrAddress { ip })?;
+        let size = Self::size_of(opcode).ok_or(MachineError::NoEquivalentOpcode { ip, opcode })?;
+        let bytes = bytes
+            .get(..size)
+            .ok_or(MachineError::InstrReachEndOfMemory { ip, opcode })?;
+        let reg = |i: usize| -> Result<u8, MachineError> {
+            let reg = bytes[i];
+            if (reg as usize) < registers {
+                Ok(reg)
+            } else {
+                Err(MachineError::RegisterDoesntExist { ip, opcode, reg })
+            }
+        };
+        let instr = match opcode {
+            1 => Instruction::MoveIf(reg(1)?, reg(2)?, reg(3)?),
+            2 => Instruction::Store(reg(1)?, reg(2)?),
+            3 => Instruction::Load(reg(1)?, reg(2)?),
+            4 => Instruction::LoadImm(reg(1)?, i16::from_le_bytes([bytes[2], bytes[3]])),
+            5 => Instruction::Sub(reg(1)?, reg(2)?, reg(3)?),
+            6 => Instruction::Out(reg(1)?),
+            7 => Instruction::Exit,
+            8 => Instruction::OutNumber(reg(1)?),
+            9 => Instruction::In(reg(1)?),
+            10 => Instruction::InNumber(reg(1)?),
+            11 => Instruction::Eof(reg(1)?),
+            NOT => Instruction::Not(reg(1)?, reg(2)?),
+            BZ => Instruction::Bz(reg(1)?, i16::from_le_bytes([bytes[2], bytes[3]])),
+            BNZ => Instruction::Bnz(reg(1)?, i16::from_le_bytes([bytes[2], bytes[3]])),
+            CALL => Instruction::Call(i16::from_le_bytes([bytes[1], bytes[2]])),
+            RET => Instruction::Ret,
+            EI => Instruction::Ei,
+            DI => Instruction::Di,
+            RETI => Instruction::Reti,
+            TRAP => Instruction::Trap(bytes[1]),
+            CAS => Instruction::Cas(reg(1)?, reg(2)?, reg(3)?),
+            FADD => Instruction::Fadd(reg(1)?, reg(2)?, reg(3)?),
+            PUSH => Instruction::Push(reg(1)?),
+            POP => Instruction::Pop(reg(1)?),
+            STOREB => Instruction::StoreB(reg(1)?, reg(2)?),
+            STOREH => Instruction::StoreH(reg(1)?, reg(2)?),
+            LOADB => Instruction::LoadB(reg(1)?, reg(2)?),
+            LOADBS => Instruction::LoadBs(reg(1)?, reg(2)?),
+            LOADH => Instruction::LoadH(reg(1)?, reg(2)?),
+            LOADHS => Instruction::LoadHs(reg(1)?, reg(2)?),
+            _ => match ArithOp::from_opcode(opcode) {
+                Some(op) => Instruction::Arith(op, reg(1)?, reg(2)?, reg(3)?),
+                None => return Err(MachineError::NoEquivalentOpcode { ip, opcode }),
+            },
+        };
+        Ok((instr, size))
+    }
+
+    /// Size in bytes of the instructions starting with `opcode`, or `None`
+    /// if the opcode does not exist.
+    fn size_of(opcode: u8) -> Option<usize> {
+        match opcode {
+            1 | 4 | 5 | BZ | BNZ | CAS | FADD => Some(4),
+            _ if ArithOp::from_opcode(opcode).is_some() => Some(4),
+            2 | 3 | NOT | CALL | STOREB..=LOADHS => Some(3),
+            6 | 8..=11 | PUSH | POP | TRAP => Some(2),
+            7 | RET | EI..=RETI => Some(1),
+            _ => None,
+        }
+    }
+
+    /// Encode the instruction into its binary representation.
+    pub fn encode(&self) -> Vec<u8> {
+        match *self {
+            Instruction::MoveIf(a, b, c) | Instruction::Sub(a, b, c) => {
+                vec![self.opcode(), a, b, c]
+            }
+            Instruction::Arith(_, a, b, c)
+            | Instruction::Cas(a, b, c)
+            | Instruction::Fadd(a, b, c) => vec![self.opcode(), a, b, c],
+            Instruction::Store(a, b)
+            | Instruction::Load(a, b)
+            | Instruction::Not(a, b)
+            | Instruction::StoreB(a, b)
+            | Instruction::StoreH(a, b)
+            | Instruction::LoadB(a, b)
+            | Instruction::LoadBs(a, b)
+            | Instruction::LoadH(a, b)
+            | Instruction::LoadHs(a, b) => vec![self.opcode(), a, b],
+            Instruction::Trap(n) => vec![TRAP, n],
+            Instruction::LoadImm(a, imm) => {
+                let [l, h] = imm.to_le_bytes();
+                vec![self.opcode(), a, l, h]
+            }
+            Instruction::Out(a)
+            | Instruction::OutNumber(a)
+            | Instruction::In(a)
+            | Instruction::InNumber(a)
+            | Instruction::Eof(a) => vec![self.opcode(), a],
+            Instruction::Exit
+            | Instruction::Ret
+            | Instruction::Ei
+            | Instruction::Di
+            | Instruction::Reti => vec![self.opcode()],
+            Instruction::Bz(a, offset) | Instruction::Bnz(a, offset) => {
+                let [l, h] = offset.to_le_bytes();
+                vec![self.opcode(), a, l, h]
+            }
+            Instruction::Call(offset) => {
+                let [l, h] = offset.to_le_bytes();
+                vec![self.opcode(), l, h]
+            }
+            Instruction::Push(a) | Instruction::Pop(a) => vec![self.opcode(), a],
+        }
+    }
+
+    /// Opcode of the instruction.
+    pub fn opcode(&self) -> u8 {
+        match self {
+            Instruction::MoveIf(..) => 1,
+            Instruction::Store(..) => 2,
+            Instruction::Load(..) => 3,
+            Instruction::LoadImm(..) => 4,
+            Instruction::Sub(..) => 5,
+            Instruction::Out(_) => 6,
+            Instruction::Exit => 7,
+            Instruction::OutNumber(_) => 8,
+            Instruction::In(_) => 9,
+            Instruction::InNumber(_) => 10,
+            Instruction::Eof(_) => 11,
+            Instruction::Arith(op, ..) => op.opcode(),
+            Instruction::Not(..) => NOT,
+            Instruction::Bz(..) => BZ,
+            Instruction::Bnz(..) => BNZ,
+            Instruction::Call(_) => CALL,
+            Instruction::Ret => RET,
+            Instruction::Ei => EI,
+            Instruction::Di => DI,
+            Instruction::Reti => RETI,
+            Instruction::Trap(_) => TRAP,
+            Instruction::Cas(..) => CAS,
+            Instruction::Fadd(..) => FADD,
+            Instruction::Push(_) => PUSH,
+            Instruction::Pop(_) => POP,
+            Instruction::StoreB(..) => STOREB,
+            Instruction::StoreH(..) => STOREH,
+            Instruction::LoadB(..) => LOADB,
+            Instruction::LoadBs(..) => LOADBS,
+            Instruction::LoadH(..) => LOADH,
+            Instruction::LoadHs(..) => LOADHS,
+        }
+    }
+
+    /// Size in bytes of the encoded instruction.
+    pub fn size(&self) -> usize {
+        self.encode().len()
+    }
+
+    /// Assembler mnemonic of the instruction.
+    pub fn mnemonic(&self) -> &'static str {
+        match self {
+            Instruction::MoveIf(..) => "move if",
+            Instruction::Store(..) => "store",
+            Instruction::Load(..) => "load",
+            Instruction::LoadImm(..) => "loadimm",
+            Instruction::Sub(..) => "sub",
+            Instruction::Out(_) => "out",
+            Instruction::Exit => "exit",
+            Instruction::OutNumber(_) => "out number",
+            Instruction::In(_) => "in",
+            Instruction::InNumber(_) => "in number",
+            Instruction::Eof(_) => "eof",
+            Instruction::Arith(op, ..) => op.mnemonic(),
+            Instruction::Not(..) => "not",
+            Instruction::Bz(..) => "bz",
+            Instruction::Bnz(..) => "bnz",
+            Instruction::Call(_) => "call",
+            Instruction::Ret => "ret",
+            Instruction::Ei => "ei",
+            Instruction::Di => "di",
+            Instruction::Reti => "reti",
+            Instruction::Trap(_) => "trap",
+            Instruction::Cas(..) => "cas",
+            Instruction::Fadd(..) => "fadd",
+            Instruction::Push(_) => "push",
+            Instruction::Pop(_) => "pop",
+            Instruction::StoreB(..) => "storeb",
+            Instruction::StoreH(..) => "storeh",
+            Instruction::LoadB(..) => "loadb",
+            Instruction::LoadBs(..) => "loadbs",
+            Instruction::LoadH(..) => "loadh",
+            Instruction::LoadHs(..) => "loadhs",
+        }
+    }
+
+    /// Registers read or written by the instruction, in operand order.
+    pub fn registers(&self) -> Vec<u8> {
+        match *self {
+            Instruction::MoveIf(a, b, c) | Instruction::Sub(a, b, c) => vec![a, b, c],
+            Instruction::Arith(_, a, b, c)
+            | Instruction::Cas(a, b, c)
+            | Instruction::Fadd(a, b, c) => vec![a, b, c],
+            Instruction::Store(a, b)
+            | Instruction::Load(a, b)
+            | Instruction::Not(a, b)
+            | Instruction::StoreB(a, b)
+            | Instruction::StoreH(a, b)
+            | Instruction::LoadB(a, b)
+            | Instruction::LoadBs(a, b)
+            | Instruction::LoadH(a, b)
+            | Instruction::LoadHs(a, b) => vec![a, b],
+            Instruction::LoadImm(a, _)
+            | Instruction::Out(a)
+            | Instruction::OutNumber(a)
+            | Instruction::In(a)
+            | Instruction::InNumber(a)
+            | Instruction::Eof(a)
+            | Instruction::Bz(a, _)
+            | Instruction::Bnz(a, _)
+            | Instruction::Push(a)
+            | Instruction::Pop(a) => vec![a],
+            Instruction::Exit
+            | Instruction::Call(_)
+            | Instruction::Ret
+            | Instruction::Ei
+            | Instruction::Di
+            | Instruction::Reti
+            | Instruction::Trap(_) => Vec::new(),
+        }
+    }
+
+    /// Immediate operand of the instruction, if any. For branches and
+    /// calls, this is the offset relative to the next instruction.
+    pub fn immediate(&self) -> Option<i32> {
+        match *self {
+            Instruction::LoadImm(_, imm) => Some(imm as i32),
+            Instruction::Trap(n) => Some(n as i32),
+            _ => self.branch_offset().map(i32::from),
+        }
+    }
+
+    /// Offset of the target of a branch or call, relative to the address
+    /// of the next instruction.
+    pub fn branch_offset(&self) -> Option<i16> {
+        match *self {
+            Instruction::Bz(_, offset)
+            | Instruction::Bnz(_, offset)
+            | Instruction::Call(offset) => Some(offset),
+            _ => None,
+        }
+    }
+}
+
+/// Instructions are displayed using the assembler syntax.
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.mnemonic())?;
+        let regs = self.registers();
+        for (i, r) in regs.iter().enumerate() {
+            write!(f, "{}r{r}", if i == 0 { " " } else { ", " })?;
+        }
+        if let Some(imm) = self.immediate() {
+            write!(f, "{}{imm}", if regs.is_empty() { " " } else { ", " })?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// One instruction of every kind, using `r` for every register operand
+    /// and `imm` for every immediate.
+    fn every_kind(r: u8, imm: i16) -> Vec<Instruction> {
+        let mut all = vec![
+            Instruction::MoveIf(r, r, r),
+            Instruction::Store(r, r),
+            Instruction::Load(r, r),
+            Instruction::LoadImm(r, imm),
+            Instruction::Sub(r, r, r),
+            Instruction::Out(r),
+            Instruction::Exit,
+            Instruction::OutNumber(r),
+            Instruction::In(r),
+            Instruction::InNumber(r),
+            Instruction::Eof(r),
+            Instruction::Not(r, r),
+            Instruction::Bz(r, imm),
+            Instruction::Bnz(r, imm),
+            Instruction::Call(imm),
+            Instruction::Ret,
+            Instruction::Push(r),
+            Instruction::Pop(r),
+            Instruction::StoreB(r, r),
+            Instruction::StoreH(r, r),
+            Instruction::LoadB(r, r),
+            Instruction::LoadBs(r, r),
+            Instruction::LoadH(r, r),
+            Instruction::LoadHs(r, r),
+            Instruction::Ei,
+            Instruction::Di,
+            Instruction::Reti,
+            Instruction::Trap(imm as u8),
+            Instruction::Cas(r, r, r),
+            Instruction::Fadd(r, r, r),
+        ];
+        all.extend(ArithOp::ALL.map(|op| Instruction::Arith(op, r, r, r)));
+        all
+    }
+
+    #[test]
+    fn every_opcode_is_covered() {
+        let mut opcodes: Vec<u8> = every_kind(0, 0).iter().map(|i| i.opcode()).collect();
+        opcodes.sort_unstable();
+        let valid: Vec<u8> = (0..=255)
+            .filter(|&op| Instruction::size_of(op).is_some())
+            .collect();
+        assert_eq!(opcodes, valid);
+    }
+
+    #[test]
+    fn encode_decode_round_trip() {
+        for registers in [1, DEFAULT_REGISTERS, 32, 256] {
+            for r in [0, registers / 2, registers - 1] {
+                for imm in [i16::MIN, -1, 0, 1, i16::MAX] {
+                    for instr in every_kind(r as u8, imm) {
+                        let bytes = instr.encode();
+                        assert_eq!(bytes.len(), instr.size());
+                        assert_eq!(Instruction::size_of(bytes[0]), Some(bytes.len()));
+                        let decoded = Instruction::decode_at(&bytes, 0, registers);
+                        assert_eq!(decoded, Ok((instr, bytes.len())), "{instr}");
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn register_limit_comes_from_the_config() {
+        for registers in [1, DEFAULT_REGISTERS, 32, 255] {
+            for instr in every_kind(registers as u8, 0) {
+                let bytes = instr.encode();
+                let decoded = Instruction::decode_at(&bytes, 8, registers);
+                if instr.registers().is_empty() {
+                    assert!(decoded.is_ok(), "{instr}");
+                } else {
+                    let expected = MachineError::RegisterDoesntExist {
+                        ip: 8,
+                        opcode: bytes[0],
+                        reg: registers as u8,
+                    };
+                    assert_eq!(decoded, Err(expected), "{instr}");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn decode_encode_round_trip() {
+        // Every opcode with a sample of operand bytes: whatever decodes
+        // encodes back to the same bytes.
+        let samples = [0, 1, 7, 15, 16, 31, 128, 255];
+        for opcode in 0..=255u8 {
+            for &a in &samples {
+                for &b in &samples {
+                    for &c in &samples {
+                        let bytes = [opcode, a, b, c];
+                        match Instruction::decode_at(&bytes, 0, DEFAULT_REGISTERS) {
+                            Ok((instr, size)) => assert_eq!(instr.encode(), &bytes[..size]),
+                            Err(MachineError::NoEquivalentOpcode { .. }) => {
+                                assert_eq!(Instruction::size_of(opcode), None)
+                            }
+                            Err(MachineError::RegisterDoesntExist { reg, .. }) => {
+                                assert!(reg as usize >= DEFAULT_REGISTERS)
+                            }
+                            Err(e) => panic!("{bytes:?}: {e}"),
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn cut_instructions_are_rejected() {
+        for instr in every_kind(1, 1) {
+            let bytes = instr.encode();
+            let decoded = Instruction::decode_at(&bytes[..bytes.len() - 1], 4, DEFAULT_REGISTERS);
+            let expected = match bytes.len() {
+                1 => MachineError::NoEquivalentInstrAddress { ip: 4 },
+                _ => MachineError::InstrReachEndOfMemory {
+                    ip: 4,
+                    opcode: bytes[0],
+                },
+            };
+            assert_eq!(decoded, Err(expected), "{instr}");
+        }
+    }
+}
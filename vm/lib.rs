@@ -1,3 +1,35 @@
+mod assembler;
+mod breakpoint;
+mod compiler;
+mod config;
+mod device;
+mod disassembler;
+mod executable;
+mod framebuffer;
+mod history;
+mod instruction;
 mod machine;
+mod memory;
+mod multicore;
+mod observer;
+mod profile;
+mod snapshot;
+mod trace;
+mod trap;
 
+pub use assembler::*;
+pub use breakpoint::*;
+pub use compiler::*;
+pub use config::*;
+pub use device::*;
+pub use disassembler::*;
+pub use executable::*;
+pub use framebuffer::*;
+pub use instruction::*;
 pub use machine::*;
+pub use multicore::*;
+pub use observer::*;
+pub use profile::*;
+pub use snapshot::*;
+pub use trace::*;
+pub use trap::*;
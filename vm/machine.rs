@@ -1,47 +1,487 @@
-use std::{io::{self, Write}, thread::panicking};
+use std::{
+    collections::VecDeque,
+    error::Error,
+    fmt,
+    io::{self, Read, Write},
+};
 
-const MEMORY_SIZE: usize = 4096;
-const NREGS: usize = 16;
+use crate::{
+    breakpoint::{BreakHit, Breakpoints},
+    config::{MachineConfig, MAX_DENSE_MEMORY_SIZE, MAX_MEMORY_SIZE},
+    device::{Bus, Device},
+    history::Undo,
+    instruction::Instruction,
+    memory::Memory,
+    observer::Observer,
+    trace::{MemWrite, RegWrite, TraceRecord, Tracer},
+    trap::{SyscallHandler, TrapAction, TrapContext},
+};
 
 const IP: usize = 0;
+/// Stack pointer used by `call`, `ret`, `push` and `pop`. It points to the
+/// last pushed word, the stack growing down. Like every register, it is 0
+/// when the machine is created, so programs set it before using the stack,
+/// usually to the end of the memory. Loading an executable sets it to
+/// [MachineConfig::stack_top].
+pub(crate) const SP: usize = 15;
 
 pub struct Machine {
-    // Implement me!
-    mach_mem : [u8;MEMORY_SIZE],
-    regs: [u32;NREGS],
+    pub(crate) config: MachineConfig,
+    pub(crate) mach_mem: Memory,
+    pub(crate) regs: Vec<u32>,
+    tracer: Option<Box<dyn Tracer>>,
+    /// Effects of the instruction being executed, only recorded when
+    /// somebody needs them.
+    effects: Option<Effects>,
+    /// Input byte read ahead while parsing a number.
+    pub(crate) input_peek: Option<u8>,
+    /// Input bytes given back by [step_back](Machine::step_back), read
+    /// before the actual input.
+    pub(crate) input_replay: VecDeque<u8>,
+    /// Whether the last input instruction reached the end of the input.
+    pub(crate) input_eof: bool,
+    /// Lowest address the stack may grow down to.
+    pub(crate) stack_limit: u32,
+    /// Return addresses of the active calls, innermost last.
+    pub(crate) call_stack: Vec<u32>,
+    /// Whether halfword and word accesses must be naturally aligned.
+    pub(crate) alignment_check: bool,
+    /// Devices mapped in the address space.
+    pub(crate) bus: Bus,
+    /// Changes of the last executed instructions, most recent last.
+    pub(crate) history: VecDeque<Undo>,
+    pub(crate) history_limit: usize,
+    /// Whether the machine is shared by the cores of a
+    /// [Multicore](crate::Multicore), which forbids the history.
+    pub(crate) multicore: bool,
+    /// Cost in cycles of the instructions, indexed by opcode.
+    pub(crate) costs: Vec<u32>,
+    /// Cycles spent since the machine was created.
+    pub(crate) cycles: u64,
+    /// Whether pending interrupts may be taken.
+    pub(crate) interrupts_enabled: bool,
+    /// Mask of the interrupt lines waiting to be taken.
+    pub(crate) pending_interrupts: u32,
+    /// Address of the interrupt vector table.
+    pub(crate) interrupt_vector: u32,
+    trap_handler: Option<Box<dyn SyscallHandler>>,
+    observers: Vec<Box<dyn Observer>>,
+    pub(crate) breakpoints: Breakpoints,
+}
+
+/// Outcome of [Machine::run_for_io] and its variants.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RunStatus {
+    /// The budget was spent before the program terminated. Running again
+    /// resumes the execution.
+    Paused,
+    /// The program executed an `exit` instruction.
+    Exited,
+    /// The program raised an error.
+    Faulted(MachineError),
+    /// An instruction met the condition of a breakpoint. Running again
+    /// resumes the execution.
+    Break(BreakHit),
+}
+
+#[derive(Default)]
+pub(crate) struct Effects {
+    pub(crate) reg_writes: Vec<RegWrite>,
+    pub(crate) mem_writes: Vec<MemWrite>,
+    pub(crate) input: Vec<u8>,
+    /// Input bytes not coming from the read-ahead byte.
+    pub(crate) taken: Vec<u8>,
+    pub(crate) output: Vec<u8>,
+    /// States of the devices before the instruction changed them, only
+    /// recorded for the undo history.
+    pub(crate) devices: Option<Vec<(u32, Vec<u8>)>>,
+}
 
+impl Effects {
+    fn new(undo: bool) -> Self {
+        Effects {
+            devices: undo.then(Vec::new),
+            ..Effects::default()
+        }
+    }
+
+    /// Save the state of the device mapped at `base` unless it is already
+    /// saved or the undo history is not recorded.
+    pub(crate) fn save_device(&mut self, base: u32, device: &dyn Device) {
+        if let Some(devices) = &mut self.devices {
+            if devices.iter().all(|&(b, _)| b != base) {
+                devices.push((base, device.save_state()));
+            }
+        }
+    }
 }
 
-#[derive(Debug)]
+/// Error raised by the machine. Errors raised while running a program
+/// carry the address of the faulting instruction (`ip`) and its opcode.
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum MachineError {
-    // Add some entries to represent errors!
-    RegisterDoesntExist,
-    ErrWritingToFd,
-    NoEquivalentOpcode,
-    NoEquivalentInstrAddress,
-    StoreReachEndOfMemory,
-    LoadReachEndOfMemory,
+    /// An instruction operand names a register that does not exist.
+    RegisterDoesntExist { ip: u32, opcode: u8, reg: u8 },
+    /// Writing the output of `out` or `out number` failed.
+    ErrWritingToFd { ip: u32, opcode: u8 },
+    /// Reading the input of `in` or `in number` failed.
+    ErrReadingFromFd { ip: u32, opcode: u8 },
+    /// `in number` found something else than a decimal number fitting in
+    /// 32 bits.
+    InvalidInputNumber { ip: u32, opcode: u8 },
+    /// The tracer failed to record the execution of an instruction.
+    ErrWritingTrace { ip: u32, opcode: u8 },
+    /// No instruction has this opcode.
+    NoEquivalentOpcode { ip: u32, opcode: u8 },
+    /// The IP points outside of the memory.
+    NoEquivalentInstrAddress { ip: u32 },
+    /// A `store` would write past the end of the memory.
+    StoreReachEndOfMemory { ip: u32, opcode: u8, addr: u32 },
+    /// A `load` would read past the end of the memory.
+    LoadReachEndOfMemory { ip: u32, opcode: u8, addr: u32 },
+    /// The instruction operands are cut by the end of the memory.
+    InstrReachEndOfMemory { ip: u32, opcode: u8 },
+    /// [Machine::set_reg] was given a register that does not exist.
+    UnknownRegister { reg: usize },
+    /// [Machine::raise_interrupt] was given a line above 31.
+    UnknownInterrupt { line: u8 },
+    /// [Machine::set_memory] or [Machine::read_memory] was asked to access
+    /// bytes outside of the memory.
+    UnknownAddress { addr: usize },
+    /// A division or remainder instruction was given a zero divisor.
+    DivisionByZero { ip: u32, opcode: u8 },
+    /// A halfword or word access is not naturally aligned while alignment
+    /// checking is enabled.
+    MisalignedAccess { ip: u32, opcode: u8, addr: u32 },
+    /// Pushing would move the stack pointer below the stack limit.
+    StackOverflow { ip: u32, opcode: u8, sp: u32 },
+    /// Popping would move the stack pointer past the end of the memory.
+    StackUnderflow { ip: u32, opcode: u8, sp: u32 },
+    /// A device failed to handle an access, or the access crosses the
+    /// boundary of a device range.
+    DeviceFault { ip: u32, opcode: u8, addr: u32 },
+    /// [Machine::map_device] was given a range overlapping another device
+    /// or going past the end of the address space.
+    InvalidDeviceRange { base: u32, size: u32 },
+    /// A `trap` was executed without a handler attached.
+    UnhandledTrap { ip: u32, opcode: u8, number: u8 },
+    /// The handler of a `trap` reported an error.
+    TrapFailed {
+        ip: u32,
+        opcode: u8,
+        number: u8,
+        message: String,
+    },
+    /// Taking interrupt `line` failed: its vector is outside of the memory,
+    /// or the return address cannot be pushed.
+    InterruptEntryFailed { ip: u32, line: u8 },
+    /// The initial memory image is larger than the machine memory.
+    ProgramTooLarge { size: usize, memory_size: u64 },
+    /// [MachineConfig::with_memory_size] was given 0 or more than the
+    /// 32-bit address space.
+    InvalidMemorySize { size: u64 },
+    /// [MachineConfig::with_registers] was given a count outside of
+    /// `16..=256`.
+    InvalidRegisterCount { count: usize },
+    /// A machine with a dense memory larger than
+    /// [MAX_DENSE_MEMORY_SIZE](crate::MAX_DENSE_MEMORY_SIZE) was requested.
+    DenseMemoryTooLarge { size: u64 },
+    /// [Machine::set_cost] was given a cost of 0 for `opcode`.
+    InvalidCost { opcode: u8 },
+    /// A [Multicore](crate::Multicore) was asked for `count` cores, which
+    /// must be at least 1.
+    InvalidCoreCount { count: usize },
+    /// A [Multicore](crate::Multicore) has no core numbered `core`.
+    UnknownCore { core: usize },
+    /// The undo history was enabled on the machine of a
+    /// [Multicore](crate::Multicore), which it cannot follow.
+    HistoryUnavailable,
+}
 
+impl MachineError {
+    /// Address of the faulting instruction, if the error was raised by one.
+    pub fn ip(&self) -> Option<u32> {
+        match *self {
+            MachineError::RegisterDoesntExist { ip, .. }
+            | MachineError::ErrWritingToFd { ip, .. }
+            | MachineError::ErrReadingFromFd { ip, .. }
+            | MachineError::InvalidInputNumber { ip, .. }
+            | MachineError::ErrWritingTrace { ip, .. }
+            | MachineError::NoEquivalentOpcode { ip, .. }
+            | MachineError::NoEquivalentInstrAddress { ip }
+            | MachineError::StoreReachEndOfMemory { ip, .. }
+            | MachineError::LoadReachEndOfMemory { ip, .. }
+            | MachineError::InstrReachEndOfMemory { ip, .. }
+            | MachineError::DivisionByZero { ip, .. }
+            | MachineError::MisalignedAccess { ip, .. }
+            | MachineError::StackOverflow { ip, .. }
+            | MachineError::StackUnderflow { ip, .. }
+            | MachineError::DeviceFault { ip, .. }
+            | MachineError::UnhandledTrap { ip, .. }
+            | MachineError::TrapFailed { ip, .. }
+            | MachineError::InterruptEntryFailed { ip, .. } => Some(ip),
+            MachineError::UnknownRegister { .. }
+            | MachineError::UnknownInterrupt { .. }
+            | MachineError::InvalidDeviceRange { .. }
+            | MachineError::UnknownAddress { .. }
+            | MachineError::ProgramTooLarge { .. }
+            | MachineError::InvalidMemorySize { .. }
+            | MachineError::InvalidRegisterCount { .. }
+            | MachineError::DenseMemoryTooLarge { .. }
+            | MachineError::InvalidCost { .. }
+            | MachineError::InvalidCoreCount { .. }
+            | MachineError::UnknownCore { .. }
+            | MachineError::HistoryUnavailable => None,
+        }
+    }
+
+    /// Opcode of the faulting instruction, if it could be fetched.
+    pub fn opcode(&self) -> Option<u8> {
+        match *self {
+            MachineError::RegisterDoesntExist { opcode, .. }
+            | MachineError::ErrWritingToFd { opcode, .. }
+            | MachineError::ErrReadingFromFd { opcode, .. }
+            | MachineError::InvalidInputNumber { opcode, .. }
+            | MachineError::ErrWritingTrace { opcode, .. }
+            | MachineError::NoEquivalentOpcode { opcode, .. }
+            | MachineError::StoreReachEndOfMemory { opcode, .. }
+            | MachineError::LoadReachEndOfMemory { opcode, .. }
+            | MachineError::InstrReachEndOfMemory { opcode, .. }
+            | MachineError::DivisionByZero { opcode, .. }
+            | MachineError::MisalignedAccess { opcode, .. }
+            | MachineError::StackOverflow { opcode, .. }
+            | MachineError::StackUnderflow { opcode, .. }
+            | MachineError::DeviceFault { opcode, .. }
+            | MachineError::UnhandledTrap { opcode, .. }
+            | MachineError::TrapFailed { opcode, .. } => Some(opcode),
+            MachineError::NoEquivalentInstrAddress { .. }
+            | MachineError::InterruptEntryFailed { .. }
+            | MachineError::InvalidDeviceRange { .. }
+            | MachineError::UnknownRegister { .. }
+            | MachineError::UnknownInterrupt { .. }
+            | MachineError::UnknownAddress { .. }
+            | MachineError::ProgramTooLarge { .. }
+            | MachineError::InvalidMemorySize { .. }
+            | MachineError::InvalidRegisterCount { .. }
+            | MachineError::DenseMemoryTooLarge { .. }
+            | MachineError::InvalidCost { .. }
+            | MachineError::InvalidCoreCount { .. }
+            | MachineError::UnknownCore { .. }
+            | MachineError::HistoryUnavailable => None,
+        }
+    }
 }
 
+impl fmt::Display for MachineError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            MachineError::RegisterDoesntExist { ip, opcode, reg } => write!(
+                f,
+                "{ip:#06x}: opcode {opcode} uses register r{reg} which does not exist"
+            ),
+            MachineError::ErrWritingToFd { ip, opcode } => {
+                write!(f, "{ip:#06x}: opcode {opcode} failed to write its output")
+            }
+            MachineError::ErrReadingFromFd { ip, opcode } => {
+                write!(f, "{ip:#06x}: opcode {opcode} failed to read its input")
+            }
+            MachineError::InvalidInputNumber { ip, opcode } => write!(
+                f,
+                "{ip:#06x}: opcode {opcode} did not find a valid number in its input"
+            ),
+            MachineError::ErrWritingTrace { ip, opcode } => {
+                write!(f, "{ip:#06x}: failed to trace opcode {opcode}")
+            }
+            MachineError::NoEquivalentOpcode { ip, opcode } => {
+                write!(f, "{ip:#06x}: unknown opcode {opcode}")
+            }
+            MachineError::NoEquivalentInstrAddress { ip } => {
+                write!(f, "{ip:#06x}: instruction address is outside of the memory")
+            }
+            MachineError::StoreReachEndOfMemory { ip, opcode, addr } => write!(
+                f,
+                "{ip:#06x}: opcode {opcode} stores past the end of memory at {addr:#x}"
+            ),
+            MachineError::LoadReachEndOfMemory { ip, opcode, addr } => write!(
+                f,
+                "{ip:#06x}: opcode {opcode} loads past the end of memory at {addr:#x}"
+            ),
+            MachineError::InstrReachEndOfMemory { ip, opcode } => write!(
+                f,
+                "{ip:#06x}: opcode {opcode} is truncated by the end of memory"
+            ),
+            MachineError::DivisionByZero { ip, opcode } => {
+                write!(f, "{ip:#06x}: opcode {opcode} divides by zero")
+            }
+            MachineError::MisalignedAccess { ip, opcode, addr } => write!(
+                f,
+                "{ip:#06x}: opcode {opcode} accesses misaligned address {addr:#x}"
+            ),
+            MachineError::StackOverflow { ip, opcode, sp } => write!(
+                f,
+                "{ip:#06x}: opcode {opcode} overflows the stack (sp = {sp:#x})"
+            ),
+            MachineError::StackUnderflow { ip, opcode, sp } => write!(
+                f,
+                "{ip:#06x}: opcode {opcode} underflows the stack (sp = {sp:#x})"
+            ),
+            MachineError::DeviceFault { ip, opcode, addr } => write!(
+                f,
+                "{ip:#06x}: opcode {opcode} failed to access the device at {addr:#x}"
+            ),
+            MachineError::InvalidDeviceRange { base, size } => write!(
+                f,
+                "cannot map a device of {size} bytes at {base:#x}: \
+                 the range overlaps another device or leaves the address space"
+            ),
+            MachineError::UnhandledTrap { ip, opcode, number } => write!(
+                f,
+                "{ip:#06x}: opcode {opcode} raised trap {number} without a handler"
+            ),
+            MachineError::TrapFailed {
+                ip,
+                opcode,
+                number,
+                ref message,
+            } => write!(
+                f,
+                "{ip:#06x}: opcode {opcode} trap {number} failed: {message}"
+            ),
+            MachineError::InterruptEntryFailed { ip, line } => {
+                write!(f, "{ip:#06x}: cannot take interrupt {line}")
+            }
+            MachineError::UnknownRegister { reg } => write!(f, "register r{reg} does not exist"),
+            MachineError::UnknownInterrupt { line } => {
+                write!(f, "interrupt line {line} does not exist")
+            }
+            MachineError::UnknownAddress { addr } => {
+                write!(f, "memory access at {addr:#x} goes outside of the memory")
+            }
+            MachineError::ProgramTooLarge { size, memory_size } => write!(
+                f,
+                "program of {size} bytes does not fit in the {memory_size} bytes of memory"
+            ),
+            MachineError::InvalidMemorySize { size } => write!(
+                f,
+                "memory size {size} is not between 1 and {MAX_MEMORY_SIZE} bytes"
+            ),
+            MachineError::DenseMemoryTooLarge { size } => write!(
+                f,
+                "a dense memory of {size} bytes is larger than {MAX_DENSE_MEMORY_SIZE} bytes"
+            ),
+            MachineError::InvalidRegisterCount { count } => write!(
+                f,
+                "register count {count} is not between {} and 256",
+                SP + 1
+            ),
+            MachineError::InvalidCost { opcode } => {
+                write!(f, "opcode {opcode} cannot cost 0 cycles")
+            }
+            MachineError::InvalidCoreCount { count } => {
+                write!(f, "{count} cores requested, at least 1 is needed")
+            }
+            MachineError::UnknownCore { core } => write!(f, "unknown core {core}"),
+            MachineError::HistoryUnavailable => {
+                write!(f, "the undo history is unavailable with several cores")
+            }
+        }
+    }
+}
+
+impl Error for MachineError {}
+
 impl Machine {
-    /// Create a new machine in its reset state. The `memory` parameter will
-    /// be copied at the beginning of the machine memory.
+    /// Create a new machine with the default configuration in its reset
+    /// state. The `memory` parameter will be copied at the beginning of the
+    /// machine memory. An error is returned when `memory` is larger than
+    /// the machine memory.
+    pub fn try_new(memory: &[u8]) -> Result<Self, MachineError> {
+        Self::with_config(MachineConfig::default(), memory)
+    }
+
+    /// Shorthand for [try_new](Machine::try_new) when `memory` is known to
+    /// fit, such as a constant program.
     ///
     /// # Panics
     /// This function panics when `memory` is larger than the machine memory.
+    #[deprecated(note = "use `Machine::try_new`, which returns an error instead of panicking")]
     pub fn new(memory: &[u8]) -> Self {
-        // unimplemented!()  // Implement me!
-        if memory.len() > MEMORY_SIZE { panic!("memory is larger than the machine memory");}
-        let mut new_mach : Machine = Machine {mach_mem : [0;MEMORY_SIZE], regs: [0;NREGS]};
-        new_mach.mach_mem[..memory.len()].copy_from_slice(&memory[..]);
-        new_mach
+        match Self::try_new(memory) {
+            Ok(machine) => machine,
+            Err(e) => panic!("{e}"),
+        }
+    }
+
+    /// Create a new machine described by `config`, see
+    /// [MachineConfig::build].
+    pub fn with_config(config: MachineConfig, memory: &[u8]) -> Result<Self, MachineError> {
+        if !config.is_sparse() && config.memory_size() > MAX_DENSE_MEMORY_SIZE {
+            return Err(MachineError::DenseMemoryTooLarge {
+                size: config.memory_size(),
+            });
+        }
+        if memory.len() as u64 > config.memory_size() {
+            return Err(MachineError::ProgramTooLarge {
+                size: memory.len(),
+                memory_size: config.memory_size(),
+            });
+        }
+        let mut new_mach = Machine {
+            config,
+            mach_mem: Memory::new(config.memory_size(), config.is_sparse()),
+            regs: vec![0; config.registers()],
+            tracer: None,
+            effects: None,
+            input_peek: None,
+            input_eof: false,
+            stack_limit: 0,
+            call_stack: Vec::new(),
+            alignment_check: false,
+            bus: Bus::default(),
+            input_replay: VecDeque::new(),
+            history: VecDeque::new(),
+            history_limit: 0,
+            multicore: false,
+            costs: vec![1; 256],
+            cycles: 0,
+            interrupts_enabled: false,
+            pending_interrupts: 0,
+            interrupt_vector: 0,
+            trap_handler: None,
+            observers: Vec::new(),
+            breakpoints: Breakpoints::default(),
+        };
+        new_mach.mach_mem.write(0, memory);
+        Ok(new_mach)
+    }
+
+    /// Configuration the machine was created with.
+    pub fn config(&self) -> &MachineConfig {
+        &self.config
     }
 
     /// Run until the program terminates or until an error happens.
-    /// If output instructions are run, they print on `fd`.
+    /// If output instructions are run, they print on `fd`. Input
+    /// instructions find an empty input.
     pub fn run_on<T: Write>(&mut self, fd: &mut T) -> Result<(), MachineError> {
-        while !self.step_on(fd)? {}
+        self.run_io(&mut io::empty(), fd)
+    }
+
+    /// Run until the program terminates, until an error happens or until
+    /// a [breakpoint](Machine::add_breakpoint) is met, which
+    /// [last_break](Machine::last_break) then reports.
+    /// Input instructions read from `input`, output instructions print
+    /// on `output`.
+    pub fn run_io<R: Read, W: Write>(
+        &mut self,
+        input: &mut R,
+        output: &mut W,
+    ) -> Result<(), MachineError> {
+        while !self.step_io(input, output)? {
+            if self.last_break().is_some() {
+                break;
+            }
+        }
         Ok(())
     }
 
@@ -51,6 +491,65 @@ impl Machine {
         self.run_on(&mut io::stdout().lock())
     }
 
+    /// Run until the program terminates, an error happens, a breakpoint is
+    /// met or `budget` cycles have been spent. Instructions cost 1 cycle unless changed
+    /// with [set_cost](Machine::set_cost), so the budget is by default a
+    /// number of instructions. An instruction is started as long as the
+    /// budget is not exhausted, so the last one may overrun it.
+    ///
+    /// Input instructions read from `input`, output instructions print on
+    /// `output`.
+    pub fn run_for_io<R: Read, W: Write>(
+        &mut self,
+        budget: u64,
+        input: &mut R,
+        output: &mut W,
+    ) -> RunStatus {
+        let end = self.cycles.saturating_add(budget);
+        while self.cycles < end {
+            match self.step_io(input, output) {
+                Ok(false) => {
+                    if let Some(hit) = self.last_break() {
+                        return RunStatus::Break(hit);
+                    }
+                }
+                Ok(true) => return RunStatus::Exited,
+                Err(e) => return RunStatus::Faulted(e),
+            }
+        }
+        RunStatus::Paused
+    }
+
+    /// Similar to [run_for_io](Machine::run_for_io), with input
+    /// instructions finding an empty input.
+    pub fn run_for_on<T: Write>(&mut self, budget: u64, fd: &mut T) -> RunStatus {
+        self.run_for_io(budget, &mut io::empty(), fd)
+    }
+
+    /// Similar to [run_for_io](Machine::run_for_io), with input
+    /// instructions finding an empty input and output instructions
+    /// printing on standard output.
+    pub fn run_for(&mut self, budget: u64) -> RunStatus {
+        self.run_for_on(budget, &mut io::stdout().lock())
+    }
+
+    /// Set the number of cycles spent by the instructions having `opcode`.
+    /// A cost of 0 is rejected with [MachineError::InvalidCost], as
+    /// [run_for](Machine::run_for) would never exhaust its budget.
+    pub fn set_cost(&mut self, opcode: u8, cost: u32) -> Result<(), MachineError> {
+        if cost == 0 {
+            return Err(MachineError::InvalidCost { opcode });
+        }
+        self.costs[opcode as usize] = cost;
+        Ok(())
+    }
+
+    /// Cycles spent by the instructions executed so far, including the
+    /// ones which raised an error.
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
     /// Execute the next instruction by doing the following steps:
     ///   - decode the instruction located at IP (register 0)
     ///   - increment the IP by the size of the instruction
@@ -58,136 +557,635 @@ impl Machine {
     ///
     /// If output instructions are run, they print on `fd`.
     /// If an error happens at either of those steps, an error is
-    /// returned.
+    /// returned. When the instruction cannot be decoded, the IP is left
+    /// unchanged.
     ///
     /// In case of success, `true` is returned if the program is
     /// terminated (upon encountering an exit instruction), or
     /// `false` if the execution must continue.
     pub fn step_on<T: Write>(&mut self, fd: &mut T) -> Result<bool, MachineError> {
-        // unimplemented!()  // Implement me!
-        let instr_addr: usize = self.regs[IP] as usize;
-        if instr_addr >= MEMORY_SIZE {
-            return Err(MachineError::NoEquivalentInstrAddress)
-        }
-        let decoded_instr = self.mach_mem[instr_addr];
-        match decoded_instr {
-            //move if
-            1 => {
-                let reg_a = self.mach_mem[instr_addr+1];
-                let reg_b = self.mach_mem[instr_addr+2];
-                let reg_c = self.mach_mem[instr_addr+3];
-                self.regs[IP]+=4;
-                if reg_a > 15 || reg_b > 15 || reg_c >15 {
-                    return Err(MachineError::RegisterDoesntExist)
-                }
-                if self.regs[reg_c as usize]!=0 {
-                    self.regs[reg_a as usize] = self.regs[reg_b as usize];
+        self.step_io(&mut io::empty(), fd)
+    }
+
+    /// Similar to [step_on](Machine::step_on), with input instructions
+    /// reading from `input`.
+    pub fn step_io<R: Read, W: Write>(
+        &mut self,
+        input: &mut R,
+        fd: &mut W,
+    ) -> Result<bool, MachineError> {
+        let ip = self.regs[IP];
+        self.breakpoints.begin_step();
+        let result = self.step_instruction(input, fd);
+        match &result {
+            Ok(false) if !self.breakpoints.is_empty() => self.check_breakpoints(ip),
+            Ok(_) => {}
+            Err(e) => {
+                for observer in &mut self.observers {
+                    observer.fault(e);
                 }
-                Ok(false)
-            }
-            //store
-            2 => {
-                let reg_a = self.mach_mem[instr_addr+1];
-                let reg_b = self.mach_mem[instr_addr+2];
-                self.regs[IP]+=3;
-                if reg_a > 15 || reg_b >15 {
-                    return Err(MachineError::RegisterDoesntExist)
+            }
+        }
+        result
+    }
+
+    fn step_instruction<R: Read, W: Write>(
+        &mut self,
+        input: &mut R,
+        fd: &mut W,
+    ) -> Result<bool, MachineError> {
+        if self.interrupts_enabled && self.pending_interrupts != 0 {
+            self.enter_interrupt()?;
+            return Ok(false);
+        }
+        let ip = self.regs[IP];
+        let (instr, size) = self.fetch(ip)?;
+        for observer in &mut self.observers {
+            observer.before_step(ip, &instr);
+        }
+        // An instruction ending at the very end of a 4 GiB memory leaves
+        // no address for the next one.
+        let next = ip
+            .checked_add(size as u32)
+            .ok_or(MachineError::InstrReachEndOfMemory {
+                ip,
+                opcode: instr.opcode(),
+            })?;
+        let undo = (self.history_limit > 0).then(|| self.begin_undo(ip));
+        self.regs[IP] = next;
+        self.cycles += self.costs[instr.opcode() as usize] as u64;
+        if self.tracer.is_none() && undo.is_none() {
+            let done = self.execute_observed(instr, ip, input, fd)?;
+            self.pending_interrupts |= self.bus.tick(None)?;
+            return Ok(done);
+        }
+        self.effects = Some(Effects::new(undo.is_some()));
+        let result = self
+            .execute_observed(instr, ip, input, fd)
+            .and_then(|done| {
+                self.pending_interrupts |= self.bus.tick(self.effects.as_mut())?;
+                Ok(done)
+            });
+        let mut effects = self.effects.take().unwrap_or_default();
+        if let Some(undo) = undo {
+            self.end_undo(undo, &mut effects);
+        }
+        if let Some(tracer) = &mut self.tracer {
+            let record = TraceRecord {
+                ip,
+                instruction: Some(instr),
+                interrupt: None,
+                reg_writes: effects.reg_writes,
+                mem_writes: effects.mem_writes,
+                input: effects.input,
+                output: effects.output,
+                fault: result.as_ref().err().cloned(),
+            };
+            let opcode = instr.opcode();
+            tracer
+                .trace(&record)
+                .map_err(|_| MachineError::ErrWritingTrace { ip, opcode })?;
+        }
+        result
+    }
+
+    /// Attach a tracer receiving a record for every executed instruction,
+    /// replacing the previous one.
+    pub fn set_tracer(&mut self, tracer: impl Tracer + 'static) {
+        self.tracer = Some(Box::new(tracer));
+    }
+
+    /// Detach the current tracer, if any, and return it.
+    pub fn take_tracer(&mut self) -> Option<Box<dyn Tracer>> {
+        self.tracer.take()
+    }
+
+    /// Attach an observer called as the machine runs, after the ones
+    /// already attached.
+    pub fn add_observer(&mut self, observer: impl Observer + 'static) {
+        self.observers.push(Box::new(observer));
+    }
+
+    /// Detach all the observers and return them.
+    pub fn take_observers(&mut self) -> Vec<Box<dyn Observer>> {
+        std::mem::take(&mut self.observers)
+    }
+
+    /// Attach the handler called by `trap` instructions, replacing the
+    /// previous one.
+    pub fn set_trap_handler(&mut self, handler: impl SyscallHandler + 'static) {
+        self.trap_handler = Some(Box::new(handler));
+    }
+
+    /// Detach the current trap handler, if any, and return it.
+    pub fn take_trap_handler(&mut self) -> Option<Box<dyn SyscallHandler>> {
+        self.trap_handler.take()
+    }
+
+    pub(crate) fn write_reg(&mut self, reg: u8, value: u32) {
+        let old = self.regs[reg as usize];
+        if let Some(effects) = &mut self.effects {
+            effects.reg_writes.push(RegWrite {
+                reg,
+                old,
+                new: value,
+            });
+        }
+        for observer in &mut self.observers {
+            observer.reg_write(reg, old, value);
+        }
+        self.breakpoints.reg_write(reg, value);
+        self.regs[reg as usize] = value;
+    }
+
+    /// Decode the instruction located at `addr`.
+    fn fetch(&self, addr: u32) -> Result<(Instruction, usize), MachineError> {
+        let available = self.config.memory_size().saturating_sub(addr as u64).min(4) as usize;
+        let mut bytes = [0; 4];
+        self.mach_mem.read(addr, &mut bytes[..available]);
+        Instruction::decode_at(&bytes[..available], addr, self.regs.len())
+    }
+
+    /// `true` if the `width` bytes starting at `addr` are all in memory.
+    fn in_bounds(&self, addr: u32, width: usize) -> bool {
+        addr as u64 + width as u64 <= self.config.memory_size()
+    }
+
+    /// Write `data` at `addr`, whose bounds must have been checked.
+    pub(crate) fn write_mem(&mut self, addr: u32, data: &[u8]) {
+        if let Some(effects) = &mut self.effects {
+            let mut old = vec![0; data.len()];
+            self.mach_mem.read(addr, &mut old);
+            effects.mem_writes.push(MemWrite {
+                addr,
+                old,
+                new: data.to_vec(),
+                device: false,
+            });
+        }
+        for observer in &mut self.observers {
+            observer.mem_write(addr, data);
+        }
+        self.breakpoints.access(addr, data.len(), true);
+        self.mach_mem.write(addr, data);
+    }
+
+    /// Take the lowest pending interrupt: push the IP, disable interrupts
+    /// and jump to the handler found in the vector table.
+    fn enter_interrupt(&mut self) -> Result<(), MachineError> {
+        let ip = self.regs[IP];
+        let line = self.pending_interrupts.trailing_zeros() as u8;
+        let undo = (self.history_limit > 0).then(|| self.begin_undo(ip));
+        if self.tracer.is_none() && undo.is_none() {
+            return self.dispatch_interrupt(ip, line);
+        }
+        self.effects = Some(Effects::new(undo.is_some()));
+        let result = self.dispatch_interrupt(ip, line);
+        let mut effects = self.effects.take().unwrap_or_default();
+        if let Some(undo) = undo {
+            self.end_undo(undo, &mut effects);
+        }
+        if let Some(tracer) = &mut self.tracer {
+            let record = TraceRecord {
+                ip,
+                instruction: None,
+                interrupt: Some(line),
+                reg_writes: effects.reg_writes,
+                mem_writes: effects.mem_writes,
+                input: effects.input,
+                output: effects.output,
+                fault: result.as_ref().err().cloned(),
+            };
+            tracer
+                .trace(&record)
+                .map_err(|_| MachineError::ErrWritingTrace { ip, opcode: 0 })?;
+        }
+        result
+    }
+
+    fn dispatch_interrupt(&mut self, ip: u32, line: u8) -> Result<(), MachineError> {
+        let fault = MachineError::InterruptEntryFailed { ip, line };
+        let entry = self.interrupt_vector as u64 + 4 * line as u64;
+        if entry + 4 > self.config.memory_size() {
+            return Err(fault);
+        }
+        let handler = self.read_mem(entry as u32, 4);
+        self.push(ip, ip, 0).map_err(|_| fault)?;
+        self.call_stack.push(ip);
+        self.pending_interrupts &= !(1 << line);
+        self.interrupts_enabled = false;
+        self.write_reg(IP as u8, handler);
+        Ok(())
+    }
+
+    /// Read a little-endian value of `width` bytes at `addr`, whose bounds
+    /// must have been checked.
+    fn read_mem(&mut self, addr: u32, width: usize) -> u32 {
+        let mut word = [0; 4];
+        self.read_mem_into(addr, &mut word[..width]);
+        u32::from_le_bytes(word)
+    }
+
+    /// Fill `data` with the bytes at `addr`, whose bounds must have been
+    /// checked.
+    pub(crate) fn read_mem_into(&mut self, addr: u32, data: &mut [u8]) {
+        self.mach_mem.read(addr, data);
+        for observer in &mut self.observers {
+            observer.mem_read(addr, data);
+        }
+        self.breakpoints.access(addr, data.len(), false);
+    }
+
+    fn output<T: Write>(
+        &mut self,
+        fd: &mut T,
+        text: &str,
+        ip: u32,
+        opcode: u8,
+    ) -> Result<(), MachineError> {
+        if let Some(effects) = &mut self.effects {
+            effects.output.extend_from_slice(text.as_bytes());
+        }
+        for observer in &mut self.observers {
+            observer.output(text.as_bytes());
+        }
+        fd.write_all(text.as_bytes())
+            .map_err(|_| MachineError::ErrWritingToFd { ip, opcode })
+    }
+
+    /// `true` if an access of `width` bytes at `addr` must be rejected as
+    /// misaligned.
+    fn misaligned(&self, addr: u32, width: usize) -> bool {
+        self.alignment_check && !(addr as usize).is_multiple_of(width)
+    }
+
+    /// Store the `width` low bytes of register `src` at the address
+    /// contained in register `dst`.
+    fn store(
+        &mut self,
+        dst: u8,
+        src: u8,
+        width: usize,
+        ip: u32,
+        opcode: u8,
+    ) -> Result<(), MachineError> {
+        let addr = self.regs[dst as usize];
+        let value = self.regs[src as usize];
+        self.store_at(addr, value, width, ip, opcode)
+    }
+
+    /// Store the `width` low bytes of `value` at `addr`.
+    fn store_at(
+        &mut self,
+        addr: u32,
+        value: u32,
+        width: usize,
+        ip: u32,
+        opcode: u8,
+    ) -> Result<(), MachineError> {
+        if self.misaligned(addr, width) {
+            return Err(MachineError::MisalignedAccess { ip, opcode, addr });
+        }
+        if let Some((device, offset, left)) = self.bus.find(addr) {
+            let fault = MachineError::DeviceFault { ip, opcode, addr };
+            if (left as usize) < width {
+                return Err(fault);
+            }
+            if let Some(effects) = &mut self.effects {
+                effects.save_device(addr - offset, device.as_ref());
+            }
+            device.write(offset, width, value).map_err(|_| fault)?;
+            if let Some(effects) = &mut self.effects {
+                effects.mem_writes.push(MemWrite {
+                    addr,
+                    old: Vec::new(),
+                    new: value.to_le_bytes()[..width].to_vec(),
+                    device: true,
+                });
+            }
+            for observer in &mut self.observers {
+                observer.mem_write(addr, &value.to_le_bytes()[..width]);
+            }
+            self.breakpoints.access(addr, width, true);
+            return Ok(());
+        }
+        if !self.in_bounds(addr, width) {
+            return Err(MachineError::StoreReachEndOfMemory { ip, opcode, addr });
+        }
+        // The access starts in memory but ends in a device.
+        if self.bus.overlaps(addr, width as u64) {
+            return Err(MachineError::DeviceFault { ip, opcode, addr });
+        }
+        self.write_mem(addr, &value.to_le_bytes()[..width]);
+        Ok(())
+    }
+
+    /// Load `width` bytes, zero-extended, from the address contained in
+    /// register `src`.
+    fn load(&mut self, src: u8, width: usize, ip: u32, opcode: u8) -> Result<u32, MachineError> {
+        self.load_at(self.regs[src as usize], width, ip, opcode)
+    }
+
+    /// Load `width` bytes, zero-extended, from `addr`.
+    fn load_at(
+        &mut self,
+        addr: u32,
+        width: usize,
+        ip: u32,
+        opcode: u8,
+    ) -> Result<u32, MachineError> {
+        if self.misaligned(addr, width) {
+            return Err(MachineError::MisalignedAccess { ip, opcode, addr });
+        }
+        if let Some((device, offset, left)) = self.bus.find(addr) {
+            let fault = MachineError::DeviceFault { ip, opcode, addr };
+            if (left as usize) < width {
+                return Err(fault);
+            }
+            if let Some(effects) = &mut self.effects {
+                effects.save_device(addr - offset, device.as_ref());
+            }
+            let value = device.read(offset, width).map_err(|_| fault)?;
+            for observer in &mut self.observers {
+                observer.mem_read(addr, &value.to_le_bytes()[..width]);
+            }
+            self.breakpoints.access(addr, width, false);
+            return Ok(value);
+        }
+        if !self.in_bounds(addr, width) {
+            return Err(MachineError::LoadReachEndOfMemory { ip, opcode, addr });
+        }
+        // The access starts in memory but ends in a device.
+        if self.bus.overlaps(addr, width as u64) {
+            return Err(MachineError::DeviceFault { ip, opcode, addr });
+        }
+        Ok(self.read_mem(addr, width))
+    }
+
+    fn jump_relative(&mut self, offset: i16) {
+        self.write_reg(IP as u8, self.regs[IP].wrapping_add(offset as i32 as u32));
+    }
+
+    fn push(&mut self, value: u32, ip: u32, opcode: u8) -> Result<(), MachineError> {
+        let sp = self.regs[SP];
+        match sp.checked_sub(4) {
+            Some(new_sp) if new_sp >= self.stack_limit && self.in_bounds(new_sp, 4) => {
+                self.write_mem(new_sp, &value.to_le_bytes());
+                self.write_reg(SP as u8, new_sp);
+                Ok(())
+            }
+            _ => Err(MachineError::StackOverflow { ip, opcode, sp }),
+        }
+    }
+
+    fn pop(&mut self, ip: u32, opcode: u8) -> Result<u32, MachineError> {
+        let sp = self.regs[SP];
+        match sp.checked_add(4) {
+            Some(new_sp) if sp >= self.stack_limit && self.in_bounds(sp, 4) => {
+                let value = self.read_mem(sp, 4);
+                self.write_reg(SP as u8, new_sp);
+                Ok(value)
+            }
+            _ => Err(MachineError::StackUnderflow { ip, opcode, sp }),
+        }
+    }
+
+    /// Read the next input byte, `None` at the end of the input.
+    fn read_byte<R: Read>(
+        &mut self,
+        input: &mut R,
+        ip: u32,
+        opcode: u8,
+    ) -> Result<Option<u8>, MachineError> {
+        let byte = match self.input_peek.take() {
+            Some(b) => Some(b),
+            None => {
+                let mut buf = [0];
+                let byte = match self.input_replay.pop_front() {
+                    Some(b) => Some(b),
+                    None => loop {
+                        match input.read(&mut buf) {
+                            Ok(0) => break None,
+                            Ok(_) => break Some(buf[0]),
+                            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                            Err(_) => return Err(MachineError::ErrReadingFromFd { ip, opcode }),
+                        }
+                    },
+                };
+                if let (Some(b), Some(effects)) = (byte, &mut self.effects) {
+                    effects.taken.push(b);
                 }
-                let reg_b_cont = self.regs[reg_b as usize].to_le_bytes();
-                let adr : usize = self.regs[reg_a as usize] as usize;
-                if adr > MEMORY_SIZE-4{
-                    return Err(MachineError::StoreReachEndOfMemory);
-                } 
-                self.mach_mem[adr] = reg_b_cont[0];
-                self.mach_mem[adr+1] = reg_b_cont[1];
-                self.mach_mem[adr+2] = reg_b_cont[2];
-                self.mach_mem[adr+3] = reg_b_cont[3];
-                Ok(false)
-            }
-            //load
-            3 => {
-                let reg_a = self.mach_mem[instr_addr+1];
-                let reg_b = self.mach_mem[instr_addr+2];
-                self.regs[IP]+=3;
-                if reg_a > 15 || reg_b >15 {
-                    return Err(MachineError::RegisterDoesntExist)
+                byte
+            }
+        };
+        if let (Some(b), Some(effects)) = (byte, &mut self.effects) {
+            effects.input.push(b);
+        }
+        Ok(byte)
+    }
+
+    /// Parse a signed decimal number from the input, `None` if the input
+    /// ends before any digit.
+    fn read_number<R: Read>(
+        &mut self,
+        input: &mut R,
+        ip: u32,
+        opcode: u8,
+    ) -> Result<Option<i32>, MachineError> {
+        let invalid = MachineError::InvalidInputNumber { ip, opcode };
+        let mut next = self.read_byte(input, ip, opcode)?;
+        while next.is_some_and(|b| b.is_ascii_whitespace()) {
+            next = self.read_byte(input, ip, opcode)?;
+        }
+        let negative = next == Some(b'-');
+        if negative {
+            next = self.read_byte(input, ip, opcode)?;
+        }
+        let mut value: i64 = 0;
+        let mut digits = 0;
+        while let Some(b) = next {
+            if !b.is_ascii_digit() {
+                // Leave the terminating byte for the next input instruction.
+                self.input_peek = Some(b);
+                if let Some(effects) = &mut self.effects {
+                    effects.input.pop();
                 }
-                let adr : usize = self.regs[reg_b as usize] as usize;
-                if adr > MEMORY_SIZE-4{
-                    return Err(MachineError::LoadReachEndOfMemory);
-                } 
-                let mem_cont = [self.mach_mem[adr],self.mach_mem[adr+1],self.mach_mem[adr+2],self.mach_mem[adr+3]];
-                let value = u32::from_le_bytes(mem_cont);
-                self.regs[reg_a as usize] = value;
-                Ok(false)
-            }
-            //loadimm
-            4 => {
-                let reg_a = self.mach_mem[instr_addr+1];
-                let l = self.mach_mem[instr_addr+2];
-                let h = self.mach_mem[instr_addr+3];
-                self.regs[IP]+=4;
-                if reg_a > 15 {
-                    return Err(MachineError::RegisterDoesntExist)
+                break;
+            }
+            value = value * 10 + (b - b'0') as i64;
+            if value > i32::MAX as i64 + 1 {
+                return Err(invalid);
+            }
+            digits += 1;
+            next = self.read_byte(input, ip, opcode)?;
+        }
+        match (digits, next) {
+            (0, None) if !negative => Ok(None),
+            (0, _) => Err(invalid),
+            _ => {
+                let value = if negative { -value } else { value };
+                i32::try_from(value).map(Some).map_err(|_| invalid)
+            }
+        }
+    }
+
+    /// Similar to [execute](Machine::execute), notifying the observers of
+    /// the success of the instruction.
+    fn execute_observed<R: Read, W: Write>(
+        &mut self,
+        instr: Instruction,
+        ip: u32,
+        input: &mut R,
+        fd: &mut W,
+    ) -> Result<bool, MachineError> {
+        let result = self.execute(instr, ip, input, fd);
+        if result.is_ok() {
+            for observer in &mut self.observers {
+                observer.after_step(ip, &instr);
+            }
+        }
+        result
+    }
+
+    /// Execute an already decoded instruction located at `ip`, the IP having
+    /// already been moved past it.
+    fn execute<R: Read, W: Write>(
+        &mut self,
+        instr: Instruction,
+        ip: u32,
+        input: &mut R,
+        fd: &mut W,
+    ) -> Result<bool, MachineError> {
+        let opcode = instr.opcode();
+        match instr {
+            Instruction::MoveIf(a, b, c) => {
+                if self.regs[c as usize] != 0 {
+                    self.write_reg(a, self.regs[b as usize]);
                 }
-                let signed_val = i16::from_le_bytes([l,h]) as i32;
-                self.regs[reg_a as usize] = signed_val as u32;
-                Ok(false)
-            }
-            //sub
-            5 => {
-                let reg_a = self.mach_mem[instr_addr+1];
-                let reg_b = self.mach_mem[instr_addr+2];
-                let reg_c = self.mach_mem[instr_addr+3];
-                self.regs[IP]+=4;
-                if reg_a > 15 || reg_b > 15 || reg_c > 15 {
-                    return Err(MachineError::RegisterDoesntExist)
+            }
+            Instruction::Store(a, b) => self.store(a, b, 4, ip, opcode)?,
+            Instruction::StoreH(a, b) => self.store(a, b, 2, ip, opcode)?,
+            Instruction::StoreB(a, b) => self.store(a, b, 1, ip, opcode)?,
+            Instruction::Load(a, b) => {
+                let value = self.load(b, 4, ip, opcode)?;
+                self.write_reg(a, value);
+            }
+            Instruction::LoadH(a, b) => {
+                let value = self.load(b, 2, ip, opcode)?;
+                self.write_reg(a, value);
+            }
+            Instruction::LoadHs(a, b) => {
+                let value = self.load(b, 2, ip, opcode)?;
+                self.write_reg(a, value as u16 as i16 as u32);
+            }
+            Instruction::LoadB(a, b) => {
+                let value = self.load(b, 1, ip, opcode)?;
+                self.write_reg(a, value);
+            }
+            Instruction::LoadBs(a, b) => {
+                let value = self.load(b, 1, ip, opcode)?;
+                self.write_reg(a, value as u8 as i8 as u32);
+            }
+            Instruction::LoadImm(a, imm) => self.write_reg(a, imm as i32 as u32),
+            Instruction::Sub(a, b, c) => {
+                self.write_reg(a, self.regs[b as usize].wrapping_sub(self.regs[c as usize]));
+            }
+            Instruction::Out(a) => {
+                let chr = self.regs[a as usize] as u8 as char;
+                self.output(fd, &chr.to_string(), ip, opcode)?;
+            }
+            Instruction::Exit => return Ok(true),
+            Instruction::OutNumber(a) => {
+                let value = self.regs[a as usize] as i32;
+                self.output(fd, &value.to_string(), ip, opcode)?;
+            }
+            Instruction::In(a) => {
+                let byte = self.read_byte(input, ip, opcode)?;
+                self.input_eof = byte.is_none();
+                self.write_reg(a, byte.map_or(u32::MAX, u32::from));
+            }
+            Instruction::InNumber(a) => {
+                let value = self.read_number(input, ip, opcode)?;
+                self.input_eof = value.is_none();
+                self.write_reg(a, value.unwrap_or(0) as u32);
+            }
+            Instruction::Eof(a) => self.write_reg(a, self.input_eof as u32),
+            Instruction::Arith(op, a, b, c) => {
+                let value = op
+                    .apply(self.regs[b as usize], self.regs[c as usize])
+                    .ok_or(MachineError::DivisionByZero { ip, opcode })?;
+                self.write_reg(a, value);
+            }
+            Instruction::Not(a, b) => self.write_reg(a, !self.regs[b as usize]),
+            Instruction::Bz(a, offset) => {
+                if self.regs[a as usize] == 0 {
+                    self.jump_relative(offset);
                 }
-                self.regs[reg_a as usize] = self.regs[reg_b as usize].wrapping_sub(self.regs[reg_c as usize]);
-                Ok(false)    
-            }
-            //out
-            6 => {
-                let reg_a = self.mach_mem[instr_addr+1];
-                self.regs[IP]+=2;
-                if reg_a > 15 {
-                    return Err(MachineError::RegisterDoesntExist)
+            }
+            Instruction::Bnz(a, offset) => {
+                if self.regs[a as usize] != 0 {
+                    self.jump_relative(offset);
                 }
-                let reg_cont = self.regs[reg_a as usize].to_le_bytes();
-                let chr = reg_cont[0] as char;
-                match write!(fd,"{}",chr) {
-                     Err(_) => return Err(MachineError::ErrWritingToFd),
-                     Ok(_) => return Ok(false)
+            }
+            Instruction::Call(offset) => {
+                let ret = self.regs[IP];
+                self.push(ret, ip, opcode)?;
+                self.call_stack.push(ret);
+                self.jump_relative(offset);
+            }
+            Instruction::Ret => {
+                let target = self.pop(ip, opcode)?;
+                self.call_stack.pop();
+                self.write_reg(IP as u8, target);
+            }
+            Instruction::Trap(number) => {
+                let Some(mut handler) = self.trap_handler.take() else {
+                    return Err(MachineError::UnhandledTrap { ip, opcode, number });
                 };
+                let result = handler.trap(number, &mut TrapContext { machine: self });
+                self.trap_handler = Some(handler);
+                match result {
+                    Ok(TrapAction::Continue) => {}
+                    Ok(TrapAction::Exit) => return Ok(true),
+                    Err(message) => {
+                        return Err(MachineError::TrapFailed {
+                            ip,
+                            opcode,
+                            number,
+                            message,
+                        })
+                    }
+                }
             }
-            //exit
-            7 => {
-                self.regs[IP]+=1;
-                Ok(true)
-            }
-            //out number
-            8 => {
-                let reg_a = self.mach_mem[instr_addr+1];
-                self.regs[IP]+=2;
-                if reg_a > 15 {
-                    return Err(MachineError::RegisterDoesntExist)
+            Instruction::Cas(a, b, c) => {
+                let addr = self.regs[b as usize];
+                let old = self.load_at(addr, 4, ip, opcode)?;
+                if old == self.regs[a as usize] {
+                    self.store_at(addr, self.regs[c as usize], 4, ip, opcode)?;
                 }
-                let reg_cont = self.regs[reg_a as usize] as i32;
-                match write!(fd,"{}",reg_cont) {
-                     Err(_) => return Err(MachineError::ErrWritingToFd),
-                     Ok(_) => return Ok(false)
-                };
+                self.write_reg(a, old);
             }
-            _ => {
-                self.regs[IP]+=1;
-                Err(MachineError::NoEquivalentOpcode)
+            Instruction::Fadd(a, b, c) => {
+                let addr = self.regs[b as usize];
+                let old = self.load_at(addr, 4, ip, opcode)?;
+                self.store_at(addr, old.wrapping_add(self.regs[c as usize]), 4, ip, opcode)?;
+                self.write_reg(a, old);
+            }
+            Instruction::Ei => self.interrupts_enabled = true,
+            Instruction::Di => self.interrupts_enabled = false,
+            Instruction::Reti => {
+                let target = self.pop(ip, opcode)?;
+                self.call_stack.pop();
+                self.write_reg(IP as u8, target);
+                self.interrupts_enabled = true;
+            }
+            Instruction::Push(a) => self.push(self.regs[a as usize], ip, opcode)?,
+            Instruction::Pop(a) => {
+                let value = self.pop(ip, opcode)?;
+                self.write_reg(a, value);
             }
-
-            
-            
-           
-
         }
+        Ok(false)
     }
 
     /// Similar to [step_on](Machine::step_on).
@@ -198,23 +1196,522 @@ impl Machine {
 
     /// Reference onto the machine current set of registers.
     pub fn regs(&self) -> &[u32] {
-        // unimplemented!()  // Implement me!
         &self.regs
     }
 
     /// Sets a register to the given value.
     pub fn set_reg(&mut self, reg: usize, value: u32) -> Result<(), MachineError> {
-        // unimplemented!()  // Implement me!
-        if reg > 15 {
-            return Err(MachineError::RegisterDoesntExist);
+        if reg >= self.regs.len() {
+            return Err(MachineError::UnknownRegister { reg });
         }
         self.regs[reg] = value;
         Ok(())
     }
 
-    /// Reference onto the machine current memory.
+    /// Request the interrupt `line`, from 0 to 31. Once interrupts are
+    /// enabled by `ei`, the lowest pending line is taken before the next
+    /// instruction: the IP is pushed on the stack, interrupts are disabled
+    /// and the execution goes on at the handler address read in the
+    /// interrupt vector table, whose entry `line` is at offset `4 * line`.
+    /// `reti` returns from the handler.
+    pub fn raise_interrupt(&mut self, line: u8) -> Result<(), MachineError> {
+        if line >= 32 {
+            return Err(MachineError::UnknownInterrupt { line });
+        }
+        self.pending_interrupts |= 1 << line;
+        Ok(())
+    }
+
+    /// Set the address of the interrupt vector table, 0 by default.
+    pub fn set_interrupt_vector(&mut self, base: u32) {
+        self.interrupt_vector = base;
+    }
+
+    /// `true` if interrupts are enabled, which they are not by default.
+    pub fn interrupts_enabled(&self) -> bool {
+        self.interrupts_enabled
+    }
+
+    /// Return addresses of the calls currently active, innermost last.
+    /// After a fault, this is the backtrace of the faulting instruction.
+    pub fn backtrace(&self) -> &[u32] {
+        &self.call_stack
+    }
+
+    /// Set the lowest address the stack may grow down to. Pushing below it
+    /// raises [MachineError::StackOverflow]. The limit is 0 by default.
+    pub fn set_stack_limit(&mut self, limit: u32) {
+        self.stack_limit = limit;
+    }
+
+    /// Enable or disable alignment checking. When enabled, word accesses
+    /// must be at an address multiple of 4 and halfword accesses at an
+    /// address multiple of 2, or [MachineError::MisalignedAccess] is
+    /// raised. Alignment checking is disabled by default.
+    pub fn set_alignment_check(&mut self, enabled: bool) {
+        self.alignment_check = enabled;
+    }
+
+    /// Map `device` in the address space starting at `base`. Loads and
+    /// stores in its range then reach the device instead of the memory,
+    /// even if the range is also covered by the memory. A device requesting
+    /// an interrupt line above 31 is rejected with
+    /// [MachineError::UnknownInterrupt].
+    pub fn map_device(
+        &mut self,
+        base: u32,
+        device: impl Device + 'static,
+    ) -> Result<(), MachineError> {
+        if let Some(line) = device.interrupt_line().filter(|&line| line >= 32) {
+            return Err(MachineError::UnknownInterrupt { line });
+        }
+        let size = device.size();
+        self.bus
+            .map(base, Box::new(device))
+            .map_err(|_| MachineError::InvalidDeviceRange { base, size })
+    }
+
+    /// Unmap the device mapped at `base`, and return it.
+    pub fn unmap_device(&mut self, base: u32) -> Option<Box<dyn Device>> {
+        self.bus.unmap(base)
+    }
+
+    /// Reference onto the machine current memory. The slice is empty when
+    /// the memory is [sparse](MachineConfig::with_sparse_memory).
     pub fn memory(&self) -> &[u8] {
-        // unimplemented!()  // Implement me!
-        &self.mach_mem
+        self.mach_mem.as_slice()
+    }
+
+    /// Copy `len` bytes of the machine memory starting at `addr`.
+    pub fn read_memory(&self, addr: usize, len: usize) -> Result<Vec<u8>, MachineError> {
+        if (addr as u64).saturating_add(len as u64) > self.config.memory_size() {
+            return Err(MachineError::UnknownAddress { addr });
+        }
+        let mut bytes = vec![0; len];
+        self.mach_mem.read(addr as u32, &mut bytes);
+        Ok(bytes)
+    }
+
+    /// Copies `data` into the machine memory starting at `addr`.
+    pub fn set_memory(&mut self, addr: usize, data: &[u8]) -> Result<(), MachineError> {
+        if (addr as u64).saturating_add(data.len() as u64) > self.config.memory_size() {
+            return Err(MachineError::UnknownAddress { addr });
+        }
+        self.mach_mem.write(addr as u32, data);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{assembler::assemble, device::Timer, instruction::ArithOp};
+
+    fn machine(source: &str) -> Machine {
+        Machine::try_new(&assemble(source).unwrap().image).unwrap()
+    }
+
+    /// Run `source` with `input`, returning the machine, its output and how
+    /// it stopped.
+    fn run(source: &str, input: &str) -> (Machine, String, Result<(), MachineError>) {
+        let mut machine = machine(source);
+        let mut output = Vec::new();
+        let result = machine.run_io(&mut input.as_bytes(), &mut output);
+        (machine, String::from_utf8(output).unwrap(), result)
+    }
+
+    #[test]
+    fn invalid_configurations_are_errors() {
+        let config = MachineConfig::new();
+        assert_eq!(
+            config.with_memory_size(0),
+            Err(MachineError::InvalidMemorySize { size: 0 })
+        );
+        assert_eq!(
+            config.with_memory_size(MAX_MEMORY_SIZE + 1),
+            Err(MachineError::InvalidMemorySize {
+                size: MAX_MEMORY_SIZE + 1
+            })
+        );
+        assert_eq!(
+            config.with_registers(8),
+            Err(MachineError::InvalidRegisterCount { count: 8 })
+        );
+        assert_eq!(
+            config.with_registers(257),
+            Err(MachineError::InvalidRegisterCount { count: 257 })
+        );
+        assert!(config.with_registers(256).is_ok());
+        let huge = config.with_memory_size(MAX_MEMORY_SIZE).unwrap();
+        assert!(matches!(
+            huge.build(&[]),
+            Err(MachineError::DenseMemoryTooLarge {
+                size: MAX_MEMORY_SIZE
+            })
+        ));
+        assert!(huge.with_sparse_memory(true).build(&[]).is_ok());
+    }
+
+    #[test]
+    fn oversized_programs_are_errors() {
+        assert_eq!(
+            Machine::try_new(&[0; 4097]).err(),
+            Some(MachineError::ProgramTooLarge {
+                size: 4097,
+                memory_size: 4096
+            })
+        );
+        let config = MachineConfig::new().with_memory_size(4).unwrap();
+        assert!(config.build(&[7; 5]).is_err());
+        assert!(config.build(&[7; 4]).is_ok());
+    }
+
+    #[test]
+    fn truncated_instruction_at_the_end_of_memory() {
+        let mut machine = Machine::try_new(&[]).unwrap();
+        machine.set_memory(4094, &[4, 1]).unwrap();
+        machine.set_reg(IP, 4094).unwrap();
+        assert_eq!(
+            machine.step_on(&mut io::sink()),
+            Err(MachineError::InstrReachEndOfMemory {
+                ip: 4094,
+                opcode: 4
+            })
+        );
+    }
+
+    #[test]
+    fn faults_report_ip_opcode_and_operand() {
+        let mut machine = Machine::try_new(&[4, 1, 0xff, 0x7f, 2, 1, 1]).unwrap();
+        let error = machine.run_on(&mut io::sink()).unwrap_err();
+        assert_eq!(
+            error,
+            MachineError::StoreReachEndOfMemory {
+                ip: 4,
+                opcode: 2,
+                addr: 0x7fff
+            }
+        );
+        assert_eq!((error.ip(), error.opcode()), (Some(4), Some(2)));
+        assert_eq!(
+            error.to_string(),
+            "0x0004: opcode 2 stores past the end of memory at 0x7fff"
+        );
+        let mut machine = Machine::try_new(&[6, 16]).unwrap();
+        assert_eq!(
+            machine.step_on(&mut io::sink()),
+            Err(MachineError::RegisterDoesntExist {
+                ip: 0,
+                opcode: 6,
+                reg: 16
+            })
+        );
+        assert_eq!(
+            machine.set_reg(16, 0),
+            Err(MachineError::UnknownRegister { reg: 16 })
+        );
+    }
+
+    #[test]
+    fn input_bytes_numbers_and_eof() {
+        let source = "
+            eof       r1
+            in number r2
+            in number r3
+            in        r4
+            eof       r5
+            in        r6
+            eof       r7
+            in number r8
+            eof       r9
+            exit
+        ";
+        let (machine, _, result) = run(source, "  -12\n\t 34x");
+        result.unwrap();
+        let regs = machine.regs();
+        assert_eq!(regs[1], 0);
+        assert_eq!(regs[2] as i32, -12);
+        assert_eq!(regs[3], 34);
+        assert_eq!(regs[4], b'x' as u32);
+        assert_eq!(regs[5], 0);
+        assert_eq!(regs[6], u32::MAX);
+        assert_eq!(regs[7], 1);
+        assert_eq!(regs[8], 0);
+        assert_eq!(regs[9], 1);
+
+        let (machine, _, result) = run("in number r1\neof r2\nexit", "-2147483648");
+        result.unwrap();
+        assert_eq!((machine.regs()[1], machine.regs()[2]), (0x8000_0000, 0));
+        for input in ["-", "+5", "2147483648", "-x"] {
+            let (_, _, result) = run("in number r1\nexit", input);
+            assert_eq!(
+                result,
+                Err(MachineError::InvalidInputNumber { ip: 0, opcode: 10 }),
+                "{input:?}"
+            );
+        }
+    }
+
+    /// Result of `mnemonic r1, r2, r3` with r2 = `b` and r3 = `c`.
+    fn arith(mnemonic: &str, b: u32, c: u32) -> Result<u32, MachineError> {
+        let mut machine = machine(&format!("{mnemonic} r1, r2, r3\nexit"));
+        machine.set_reg(2, b)?;
+        machine.set_reg(3, c)?;
+        machine.run_on(&mut io::sink())?;
+        Ok(machine.regs()[1])
+    }
+
+    #[test]
+    fn arithmetic_and_bitwise_operations() {
+        let cases: [(&str, u32, u32, u32); 14] = [
+            ("add", u32::MAX, 2, 1),
+            ("mul", 0x1_0001, 0x1_0001, 0x2_0001),
+            ("divu", 0xffff_fffe, 2, 0x7fff_ffff),
+            ("divs", -7i32 as u32, 2, -3i32 as u32),
+            ("divs", 7, -2i32 as u32, -3i32 as u32),
+            ("divs", i32::MIN as u32, -1i32 as u32, i32::MIN as u32),
+            ("modu", u32::MAX, 10, 5),
+            ("mods", -7i32 as u32, 2, -1i32 as u32),
+            ("mods", 7, -2i32 as u32, 1),
+            ("mods", i32::MIN as u32, -1i32 as u32, 0),
+            ("and", 0b1100, 0b1010, 0b1000),
+            ("or", 0b1100, 0b1010, 0b1110),
+            ("xor", 0b1100, 0b1010, 0b0110),
+            ("sub", 1, 2, u32::MAX),
+        ];
+        for (mnemonic, b, c, expected) in cases {
+            assert_eq!(
+                arith(mnemonic, b, c),
+                Ok(expected),
+                "{mnemonic} {b:#x}, {c:#x}"
+            );
+        }
+        let mut machine = machine("not r1, r2\nexit");
+        machine.set_reg(2, 0x0f0f_0000).unwrap();
+        machine.run_on(&mut io::sink()).unwrap();
+        assert_eq!(machine.regs()[1], 0xf0f0_ffff);
+    }
+
+    #[test]
+    fn shifts_and_rotations() {
+        let cases: [(&str, u32, u32, u32); 12] = [
+            ("shl", 1, 31, 0x8000_0000),
+            ("shl", 1, 33, 2),
+            ("shr", 0x8000_0000, 31, 1),
+            ("shr", 0x8000_0000, 63, 1),
+            ("sar", 0x8000_0000, 31, u32::MAX),
+            ("sar", 0x8000_0000, 32, 0x8000_0000),
+            ("sar", 0x4000_0000, 30, 1),
+            ("rol", 0x8000_0001, 1, 3),
+            ("rol", 0x1234_5678, 32, 0x1234_5678),
+            ("ror", 1, 1, 0x8000_0000),
+            ("ror", 0x1234_5678, 36, 0x8123_4567),
+            ("rol", 0x1234_5678, 100, 0x2345_6781),
+        ];
+        for (mnemonic, b, c, expected) in cases {
+            assert_eq!(
+                arith(mnemonic, b, c),
+                Ok(expected),
+                "{mnemonic} {b:#x}, {c:#x}"
+            );
+        }
+    }
+
+    #[test]
+    fn division_by_zero() {
+        for mnemonic in ["divu", "divs", "modu", "mods"] {
+            let opcode = ArithOp::ALL
+                .into_iter()
+                .find(|op| op.mnemonic() == mnemonic)
+                .unwrap()
+                .opcode();
+            assert_eq!(
+                arith(mnemonic, 7, 0),
+                Err(MachineError::DivisionByZero { ip: 0, opcode }),
+                "{mnemonic}"
+            );
+        }
+    }
+
+    #[test]
+    fn stack_pointer_is_set_by_the_program() {
+        // Every register, the stack pointer included, starts at 0.
+        assert!(machine("exit").regs().iter().all(|&r| r == 0));
+        let (_, _, result) = run("call f\nexit\nf: ret", "");
+        assert_eq!(
+            result,
+            Err(MachineError::StackOverflow {
+                ip: 0,
+                opcode: 34,
+                sp: 0
+            })
+        );
+
+        let (machine, _, result) = run("loadimm sp, 4096\ncall f\nexit\nf: ret", "");
+        result.unwrap();
+        assert_eq!(machine.regs()[SP], 4096);
+        assert_eq!(machine.memory()[4092..], [7, 0, 0, 0]);
+
+        let config = MachineConfig::new().with_memory_size(4097).unwrap();
+        assert_eq!(config.stack_top(), 4096);
+        let config = MachineConfig::new().with_memory_size(1 << 32).unwrap();
+        assert_eq!(config.stack_top(), 0xffff_fffc);
+    }
+
+    #[test]
+    fn interrupt_lines_are_checked() {
+        // Requests line 32, declaring it or not.
+        struct Rogue(Option<u8>);
+        impl Device for Rogue {
+            fn size(&self) -> u32 {
+                4
+            }
+            fn read(&mut self, _offset: u32, _width: usize) -> io::Result<u32> {
+                Ok(0)
+            }
+            fn write(&mut self, _offset: u32, _width: usize, _value: u32) -> io::Result<()> {
+                Ok(())
+            }
+            fn take_interrupt(&mut self) -> Option<u8> {
+                Some(32)
+            }
+            fn interrupt_line(&self) -> Option<u8> {
+                self.0
+            }
+        }
+
+        assert!(matches!(
+            Timer::new(32, 1),
+            Err(MachineError::UnknownInterrupt { line: 32 })
+        ));
+        let mut machine = Machine::try_new(&[7]).unwrap();
+        machine
+            .map_device(0x800, Timer::new(31, 1).unwrap())
+            .unwrap();
+        assert_eq!(
+            machine.map_device(0x900, Rogue(Some(32))),
+            Err(MachineError::UnknownInterrupt { line: 32 })
+        );
+        machine.map_device(0x900, Rogue(None)).unwrap();
+        assert_eq!(
+            machine.step_on(&mut io::sink()),
+            Err(MachineError::UnknownInterrupt { line: 32 })
+        );
+    }
+
+    #[test]
+    fn narrow_loads_extend_and_narrow_stores_truncate() {
+        let (machine, _, result) = run(
+            "loadimm r1, 0x100\nloadb r2, r1\nloadbs r3, r1\nloadh r4, r1\nloadhs r5, r1\n\
+             loadimm r6, -1\nloadimm r7, 0x200\nstoreb r7, r6\nloadimm r7, 0x204\n\
+             storeh r7, r6\nexit\n.org 0x100\n.half 0x8080",
+            "",
+        );
+        result.unwrap();
+        assert_eq!(
+            machine.regs()[2..6],
+            [0x80, 0xffff_ff80, 0x8080, 0xffff_8080]
+        );
+        assert_eq!(
+            machine.memory()[0x200..0x208],
+            [0xff, 0, 0, 0, 0xff, 0xff, 0, 0]
+        );
+    }
+
+    #[test]
+    fn unaligned_accesses_fault_only_when_checked() {
+        let run_checked = |source, check| {
+            let mut machine = machine(source);
+            machine.set_alignment_check(check);
+            machine.run_on(&mut io::sink())
+        };
+        let halfword = "loadimm r1, 0x101\nloadb r2, r1\nloadh r2, r1\nexit";
+        let word = "loadimm r1, 0x102\nloadh r2, r1\nload r2, r1\nexit";
+        assert_eq!(run_checked(halfword, false), Ok(()));
+        assert_eq!(run_checked(word, false), Ok(()));
+        assert_eq!(
+            run_checked(halfword, true),
+            Err(MachineError::MisalignedAccess {
+                ip: 7,
+                opcode: 42,
+                addr: 0x101
+            })
+        );
+        assert_eq!(
+            run_checked(word, true),
+            Err(MachineError::MisalignedAccess {
+                ip: 7,
+                opcode: 3,
+                addr: 0x102
+            })
+        );
+    }
+
+    #[test]
+    fn end_of_the_address_space() {
+        let config = MachineConfig::new()
+            .with_memory_size(1 << 32)
+            .unwrap()
+            .with_sparse_memory(true);
+        // exit as the very last byte of the memory
+        let mut machine = Machine::with_config(config, &[]).unwrap();
+        machine.set_memory(0xffff_ffff, &[7]).unwrap();
+        machine.set_reg(IP, 0xffff_ffff).unwrap();
+        assert_eq!(
+            machine.step_on(&mut io::sink()),
+            Err(MachineError::InstrReachEndOfMemory {
+                ip: 0xffff_ffff,
+                opcode: 7
+            })
+        );
+        assert_eq!(machine.regs()[IP], 0xffff_ffff);
+
+        // ret with the stack pointer on the last word of the memory
+        let mut machine = Machine::with_config(config, &[35]).unwrap();
+        machine.set_reg(SP, 0xffff_fffc).unwrap();
+        assert_eq!(
+            machine.step_on(&mut io::sink()),
+            Err(MachineError::StackUnderflow {
+                ip: 0,
+                opcode: 35,
+                sp: 0xffff_fffc
+            })
+        );
+    }
+
+    #[test]
+    fn run_for_pauses_when_the_budget_is_spent() {
+        let mut machine = machine("loadimm r1, 1\nloadimm r2, 2\nloadimm r3, 3\nexit");
+        assert_eq!(machine.run_for_on(2, &mut io::sink()), RunStatus::Paused);
+        assert_eq!(machine.cycles(), 2);
+        assert_eq!(machine.regs()[1..4], [1, 2, 0]);
+        assert_eq!(machine.run_for_on(1, &mut io::sink()), RunStatus::Paused);
+        assert_eq!(machine.regs()[3], 3);
+        assert_eq!(machine.run_for_on(10, &mut io::sink()), RunStatus::Exited);
+        assert_eq!(machine.cycles(), 4);
+    }
+
+    #[test]
+    fn run_for_counts_the_cost_of_each_opcode() {
+        let mut machine = machine("loadimm r1, 0\ndivu r2, r1, r1\nexit");
+        let divu = ArithOp::ALL
+            .into_iter()
+            .find(|op| op.mnemonic() == "divu")
+            .unwrap()
+            .opcode();
+        machine.set_cost(4, 5).unwrap(); // loadimm
+        machine.set_cost(divu, 20).unwrap();
+        // The budget is checked between instructions, so the first one
+        // runs to completion.
+        assert_eq!(machine.run_for_on(3, &mut io::sink()), RunStatus::Paused);
+        assert_eq!(machine.cycles(), 5);
+        assert_eq!(
+            machine.run_for_on(100, &mut io::sink()),
+            RunStatus::Faulted(MachineError::DivisionByZero {
+                ip: 4,
+                opcode: divu
+            })
+        );
+        assert_eq!(machine.cycles(), 25);
     }
 }
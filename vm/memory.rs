@@ -0,0 +1,93 @@
+//! Storage backends of the machine memory.
+//!
+//! Callers are responsible for bounds checking: every access must lie
+//! within the memory size given at creation.
+
+use std::collections::HashMap;
+
+/// Size of the pages allocated on demand by the sparse backend.
+const PAGE_SIZE: usize = 4096;
+
+pub(crate) enum Memory {
+    /// Every byte is allocated up front.
+    Dense(Vec<u8>),
+    /// Pages are allocated on their first write; unwritten bytes read as 0.
+    Sparse(HashMap<u32, Box<[u8; PAGE_SIZE]>>),
+}
+
+impl Memory {
+    pub(crate) fn new(size: u64, sparse: bool) -> Self {
+        if sparse {
+            Memory::Sparse(HashMap::new())
+        } else {
+            Memory::Dense(vec![0; size as usize])
+        }
+    }
+
+    /// Fill `buf` with the bytes starting at `addr`.
+    pub(crate) fn read(&self, addr: u32, buf: &mut [u8]) {
+        match self {
+            Memory::Dense(bytes) => {
+                let addr = addr as usize;
+                buf.copy_from_slice(&bytes[addr..addr + buf.len()]);
+            }
+            Memory::Sparse(pages) => {
+                for (i, b) in buf.iter_mut().enumerate() {
+                    let addr = addr as usize + i;
+                    *b = pages
+                        .get(&((addr / PAGE_SIZE) as u32))
+                        .map_or(0, |page| page[addr % PAGE_SIZE]);
+                }
+            }
+        }
+    }
+
+    /// Copy `data` at `addr`.
+    pub(crate) fn write(&mut self, addr: u32, data: &[u8]) {
+        match self {
+            Memory::Dense(bytes) => {
+                let addr = addr as usize;
+                bytes[addr..addr + data.len()].copy_from_slice(data);
+            }
+            Memory::Sparse(pages) => {
+                for (i, &b) in data.iter().enumerate() {
+                    let addr = addr as usize + i;
+                    let page = pages
+                        .entry((addr / PAGE_SIZE) as u32)
+                        .or_insert_with(|| Box::new([0; PAGE_SIZE]));
+                    page[addr % PAGE_SIZE] = b;
+                }
+            }
+        }
+    }
+
+    /// Pages of a memory of `size` bytes holding non-zero bytes, as
+    /// `(address, bytes)` pairs by increasing address.
+    pub(crate) fn pages(&self, size: u64) -> Vec<(u32, Vec<u8>)> {
+        let mut indexes: Vec<u64> = match self {
+            Memory::Dense(_) => (0..size.div_ceil(PAGE_SIZE as u64)).collect(),
+            Memory::Sparse(pages) => pages.keys().map(|&index| index as u64).collect(),
+        };
+        indexes.sort_unstable();
+        indexes
+            .into_iter()
+            .filter_map(|index| {
+                let addr = index * PAGE_SIZE as u64;
+                let mut bytes = vec![0; (size - addr).min(PAGE_SIZE as u64) as usize];
+                self.read(addr as u32, &mut bytes);
+                bytes
+                    .iter()
+                    .any(|&b| b != 0)
+                    .then_some((addr as u32, bytes))
+            })
+            .collect()
+    }
+
+    /// The whole memory as a slice, empty for the sparse backend.
+    pub(crate) fn as_slice(&self) -> &[u8] {
+        match self {
+            Memory::Dense(bytes) => bytes,
+            Memory::Sparse(_) => &[],
+        }
+    }
+}
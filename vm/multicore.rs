@@ -0,0 +1,459 @@
+//! Several cores running against the memory of one [Machine].
+//!
+//! Every core has its own registers, stack region, call stack and interrupt
+//! enable flag.
+//! The memory, the devices, the input, the tracer, the trap handler, the
+//! cycle counter and the pending interrupts belong to the shared machine:
+//! a pending interrupt is taken by the first core running with interrupts
+//! enabled. Cores execute one instruction at a time in an order chosen by
+//! a deterministic [Schedule], so that every interleaving, and thus every
+//! race, can be reproduced. `cas` and `fadd` provide the atomic operations
+//! needed to write locks.
+//!
+//! The undo history of the machine does not know about cores: it is
+//! disabled by [Multicore::new], and
+//! [Machine::set_history_limit] refuses to enable it until the machine is
+//! given back by [Multicore::into_machine].
+
+use std::{
+    io::{self, Read, Write},
+    mem,
+};
+
+use crate::{
+    device::{Device, Random},
+    machine::{Machine, MachineError, RunStatus, SP},
+};
+
+/// Bytes of stack given to each core by [Multicore::new].
+pub const CORE_STACK_SIZE: u32 = 1024;
+
+/// Order in which the cores execute their instructions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Schedule {
+    /// Every running core executes one instruction in turn, by increasing
+    /// core number.
+    RoundRobin,
+    /// Each instruction is executed by a running core drawn from a
+    /// pseudo-random sequence depending only on `seed`.
+    Random { seed: u32 },
+}
+
+/// State owned by each core.
+struct Core {
+    regs: Vec<u32>,
+    call_stack: Vec<u32>,
+    interrupts_enabled: bool,
+    stack_limit: u32,
+    exited: bool,
+}
+
+/// Cores sharing a [Machine].
+pub struct Multicore {
+    machine: Machine,
+    cores: Vec<Core>,
+    schedule: Schedule,
+    random: Random,
+    /// Core which executed the last instruction.
+    current: usize,
+    /// Lowest core number the round-robin schedule may pick next.
+    next: usize,
+}
+
+impl Multicore {
+    /// Run `cores` cores on `machine`, each starting with a copy of its
+    /// registers except for the stack pointer: core `i` gets the
+    /// [CORE_STACK_SIZE] bytes below `sp - i * CORE_STACK_SIZE`, `sp`
+    /// being the stack pointer of the machine, and overflows its stack
+    /// when pushing below them. Regions which would start below address 0
+    /// are left at 0, like the stack pointer of a machine whose program
+    /// sets it. Cores are told apart by setting a register with
+    /// [set_reg](Multicore::set_reg). Asking for 0 cores raises
+    /// [MachineError::InvalidCoreCount].
+    pub fn new(
+        mut machine: Machine,
+        cores: usize,
+        schedule: Schedule,
+    ) -> Result<Self, MachineError> {
+        if cores == 0 {
+            return Err(MachineError::InvalidCoreCount { count: cores });
+        }
+        machine.set_history_limit(0)?;
+        machine.multicore = true;
+        let sp = machine.regs[SP] as u64;
+        let cores = (0..cores as u64)
+            .map(|core| {
+                let top = sp.saturating_sub(core * CORE_STACK_SIZE as u64) as u32;
+                let mut regs = machine.regs.clone();
+                regs[SP] = top;
+                Core {
+                    regs,
+                    call_stack: machine.call_stack.clone(),
+                    interrupts_enabled: machine.interrupts_enabled,
+                    stack_limit: machine.stack_limit.max(top.saturating_sub(CORE_STACK_SIZE)),
+                    exited: false,
+                }
+            })
+            .collect();
+        let seed = match schedule {
+            Schedule::RoundRobin => 0,
+            Schedule::Random { seed } => seed,
+        };
+        Ok(Multicore {
+            machine,
+            cores,
+            schedule,
+            random: Random::new(seed),
+            current: 0,
+            next: 0,
+        })
+    }
+
+    /// The shared machine, whose registers are not the ones of any core.
+    pub fn machine(&self) -> &Machine {
+        &self.machine
+    }
+
+    /// The shared machine, to map devices or attach a tracer or a trap
+    /// handler.
+    pub fn machine_mut(&mut self) -> &mut Machine {
+        &mut self.machine
+    }
+
+    /// Give the shared machine back, which may keep a history again.
+    pub fn into_machine(mut self) -> Machine {
+        self.machine.multicore = false;
+        self.machine
+    }
+
+    /// Number of cores.
+    pub fn cores(&self) -> usize {
+        self.cores.len()
+    }
+
+    /// Core which executed the last instruction, and which raised the
+    /// error when a step failed.
+    pub fn current(&self) -> usize {
+        self.current
+    }
+
+    /// The state of `core`, or [MachineError::UnknownCore].
+    fn core(&self, core: usize) -> Result<&Core, MachineError> {
+        self.cores
+            .get(core)
+            .ok_or(MachineError::UnknownCore { core })
+    }
+
+    /// Registers of `core`.
+    pub fn regs(&self, core: usize) -> Result<&[u32], MachineError> {
+        Ok(&self.core(core)?.regs)
+    }
+
+    /// Set a register of `core` to the given value.
+    pub fn set_reg(&mut self, core: usize, reg: usize, value: u32) -> Result<(), MachineError> {
+        let regs = &mut self
+            .cores
+            .get_mut(core)
+            .ok_or(MachineError::UnknownCore { core })?
+            .regs;
+        *regs
+            .get_mut(reg)
+            .ok_or(MachineError::UnknownRegister { reg })? = value;
+        Ok(())
+    }
+
+    /// `true` if `core` has executed an `exit` instruction.
+    pub fn exited(&self, core: usize) -> Result<bool, MachineError> {
+        Ok(self.core(core)?.exited)
+    }
+
+    /// Pick the core executing the next instruction, `None` if they have
+    /// all exited.
+    fn next_core(&mut self) -> Option<usize> {
+        let running: Vec<usize> = (0..self.cores.len())
+            .filter(|&core| !self.cores[core].exited)
+            .collect();
+        if running.is_empty() {
+            return None;
+        }
+        Some(match self.schedule {
+            Schedule::RoundRobin => running
+                .iter()
+                .copied()
+                .find(|&core| core >= self.next)
+                .unwrap_or(running[0]),
+            Schedule::Random { .. } => {
+                // Reading the generator cannot fail.
+                let n = self.random.read(0, 4).unwrap_or(0);
+                running[n as usize % running.len()]
+            }
+        })
+    }
+
+    /// Exchange the state of `core` with the one held by the machine.
+    fn swap(&mut self, core: usize) {
+        let core = &mut self.cores[core];
+        mem::swap(&mut core.regs, &mut self.machine.regs);
+        mem::swap(&mut core.call_stack, &mut self.machine.call_stack);
+        mem::swap(
+            &mut core.interrupts_enabled,
+            &mut self.machine.interrupts_enabled,
+        );
+        mem::swap(&mut core.stack_limit, &mut self.machine.stack_limit);
+    }
+
+    /// Execute one instruction on the next core chosen by the schedule,
+    /// see [Machine::step_io]. Returns `true` once every core has exited,
+    /// a core being left alone once it has executed `exit`.
+    pub fn step_io<R: Read, W: Write>(
+        &mut self,
+        input: &mut R,
+        output: &mut W,
+    ) -> Result<bool, MachineError> {
+        let Some(core) = self.next_core() else {
+            return Ok(true);
+        };
+        self.current = core;
+        self.next = core + 1;
+        self.swap(core);
+        let result = self.machine.step_io(input, output);
+        self.swap(core);
+        if result? {
+            self.cores[core].exited = true;
+            return Ok(self.cores.iter().all(|core| core.exited));
+        }
+        Ok(false)
+    }
+
+    /// Similar to [step_io](Multicore::step_io), with input instructions
+    /// finding an empty input.
+    pub fn step_on<T: Write>(&mut self, fd: &mut T) -> Result<bool, MachineError> {
+        self.step_io(&mut io::empty(), fd)
+    }
+
+    /// Run until every core has exited, until an error happens or until a
+    /// breakpoint of the machine is met by the [current](Multicore::current)
+    /// core.
+    pub fn run_io<R: Read, W: Write>(
+        &mut self,
+        input: &mut R,
+        output: &mut W,
+    ) -> Result<(), MachineError> {
+        while !self.step_io(input, output)? {
+            if self.machine.last_break().is_some() {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// Similar to [run_io](Multicore::run_io), with input instructions
+    /// finding an empty input.
+    pub fn run_on<T: Write>(&mut self, fd: &mut T) -> Result<(), MachineError> {
+        self.run_io(&mut io::empty(), fd)
+    }
+
+    /// Run until every core has exited, an error happens, a breakpoint is
+    /// met or `budget` cycles have been spent by the cores together, see
+    /// [Machine::run_for_io].
+    pub fn run_for_io<R: Read, W: Write>(
+        &mut self,
+        budget: u64,
+        input: &mut R,
+        output: &mut W,
+    ) -> RunStatus {
+        let end = self.machine.cycles().saturating_add(budget);
+        while self.machine.cycles() < end {
+            match self.step_io(input, output) {
+                Ok(false) => {
+                    if let Some(hit) = self.machine.last_break() {
+                        return RunStatus::Break(hit);
+                    }
+                }
+                Ok(true) => return RunStatus::Exited,
+                Err(e) => return RunStatus::Faulted(e),
+            }
+        }
+        RunStatus::Paused
+    }
+
+    /// Similar to [run_for_io](Multicore::run_for_io), with input
+    /// instructions finding an empty input.
+    pub fn run_for_on<T: Write>(&mut self, budget: u64, fd: &mut T) -> RunStatus {
+        self.run_for_io(budget, &mut io::empty(), fd)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{assembler::assemble, executable::Executable};
+
+    fn counter_machine() -> (Machine, u32) {
+        let source = "
+                loadimm r1, counter
+                loadimm r2, 1
+                fadd    r3, r1, r2
+                exit
+            counter:
+                .word 0
+        ";
+        let assembled = assemble(source).unwrap();
+        let machine = Machine::try_new(&assembled.image).unwrap();
+        (machine, assembled.symbols["counter"])
+    }
+
+    #[test]
+    fn cores_share_the_memory() {
+        let (machine, counter) = counter_machine();
+        let mut multicore = Multicore::new(machine, 3, Schedule::Random { seed: 7 }).unwrap();
+        multicore.run_on(&mut io::sink()).unwrap();
+        for core in 0..3 {
+            assert!(multicore.exited(core).unwrap());
+        }
+        let machine = multicore.into_machine();
+        assert_eq!(
+            machine.read_memory(counter as usize, 4).unwrap(),
+            [3, 0, 0, 0]
+        );
+    }
+
+    #[test]
+    fn cores_are_checked() {
+        let (machine, _) = counter_machine();
+        assert!(matches!(
+            Multicore::new(machine, 0, Schedule::RoundRobin),
+            Err(MachineError::InvalidCoreCount { count: 0 })
+        ));
+        let (machine, _) = counter_machine();
+        let mut multicore = Multicore::new(machine, 2, Schedule::RoundRobin).unwrap();
+        assert_eq!(
+            multicore.regs(2).err(),
+            Some(MachineError::UnknownCore { core: 2 })
+        );
+        assert_eq!(
+            multicore.set_reg(2, 1, 0),
+            Err(MachineError::UnknownCore { core: 2 })
+        );
+        assert_eq!(
+            multicore.exited(2),
+            Err(MachineError::UnknownCore { core: 2 })
+        );
+        multicore.set_reg(1, 5, 9).unwrap();
+        assert_eq!(multicore.regs(1).unwrap()[5], 9);
+    }
+
+    #[test]
+    fn history_stays_disabled() {
+        let (mut machine, _) = counter_machine();
+        machine.set_history_limit(10).unwrap();
+        let mut multicore = Multicore::new(machine, 2, Schedule::RoundRobin).unwrap();
+        assert_eq!(
+            multicore.machine_mut().set_history_limit(10),
+            Err(MachineError::HistoryUnavailable)
+        );
+        multicore.step_on(&mut io::sink()).unwrap();
+        assert!(!multicore.machine_mut().step_back());
+        let mut machine = multicore.into_machine();
+        machine.set_history_limit(10).unwrap();
+    }
+
+    /// Machine loaded with `source` as an executable, its stack pointer at
+    /// the end of the memory, and the address of its `counter` symbol.
+    fn executable(source: &str) -> (Machine, u32) {
+        let exe = Executable::from_assembled(&assemble(source).unwrap());
+        let mut machine = Machine::try_new(&[]).unwrap();
+        machine.load_executable(&exe).unwrap();
+        (machine, exe.symbols.get("counter").copied().unwrap_or(0))
+    }
+
+    fn counter(multicore: &Multicore, addr: u32) -> u32 {
+        let bytes = multicore.machine().read_memory(addr as usize, 4).unwrap();
+        u32::from_le_bytes(bytes.try_into().unwrap())
+    }
+
+    #[test]
+    fn cores_have_their_own_stacks() {
+        let (machine, _) = executable("push sp\nexit");
+        let mut multicore = Multicore::new(machine, 3, Schedule::RoundRobin).unwrap();
+        multicore.run_on(&mut io::sink()).unwrap();
+        for (core, top) in [(0, 4096), (1, 3072), (2, 2048)] {
+            assert_eq!(multicore.regs(core).unwrap()[SP], top - 4);
+            let pushed = multicore
+                .machine()
+                .read_memory(top as usize - 4, 4)
+                .unwrap();
+            assert_eq!(pushed, top.to_le_bytes());
+        }
+
+        let (machine, _) = executable("loop: push r1\njmp loop");
+        let mut multicore = Multicore::new(machine, 2, Schedule::RoundRobin).unwrap();
+        assert_eq!(
+            multicore.run_on(&mut io::sink()),
+            Err(MachineError::StackOverflow {
+                ip: 0,
+                opcode: 36,
+                sp: 3072
+            })
+        );
+        assert_eq!(multicore.current(), 0);
+    }
+
+    /// Every core adds 1 to `counter` with a plain load and store, inside
+    /// a lock taken with `cas` when `locked`.
+    fn increment(locked: bool) -> String {
+        let (acquire, release) = if locked {
+            (
+                "acquire: loadimm r3, 0\ncas r3, r1, r4\nbnz r3, acquire",
+                "loadimm r3, 0\nstore r1, r3",
+            )
+        } else {
+            ("", "")
+        };
+        format!(
+            "loadimm r1, lock\nloadimm r2, counter\nloadimm r4, 1\n{acquire}\n\
+             load r5, r2\nadd r5, r5, r4\nstore r2, r5\n{release}\nexit\n\
+             .align 4\nlock: .word 0\ncounter: .word 0"
+        )
+    }
+
+    #[test]
+    fn plain_increments_lose_updates() {
+        let (machine, counter_addr) = executable(&increment(false));
+        let mut multicore = Multicore::new(machine, 2, Schedule::RoundRobin).unwrap();
+        multicore.run_on(&mut io::sink()).unwrap();
+        // Both cores load 0 before either stores.
+        assert_eq!(counter(&multicore, counter_addr), 1);
+    }
+
+    #[test]
+    fn cas_locks_protect_increments() {
+        for schedule in [
+            Schedule::RoundRobin,
+            Schedule::Random { seed: 1 },
+            Schedule::Random { seed: 7 },
+            Schedule::Random { seed: 42 },
+        ] {
+            let (machine, counter_addr) = executable(&increment(true));
+            let mut multicore = Multicore::new(machine, 4, schedule).unwrap();
+            multicore.run_on(&mut io::sink()).unwrap();
+            assert_eq!(counter(&multicore, counter_addr), 4, "{schedule:?}");
+            assert_eq!(counter(&multicore, counter_addr - 4), 0, "{schedule:?}");
+        }
+    }
+
+    #[test]
+    fn random_schedules_are_reproducible() {
+        let interleaving = |seed| {
+            let (machine, _) = counter_machine();
+            let mut multicore = Multicore::new(machine, 3, Schedule::Random { seed }).unwrap();
+            let mut cores = Vec::new();
+            while !multicore.step_on(&mut io::sink()).unwrap() {
+                cores.push(multicore.current());
+            }
+            cores
+        };
+        assert_eq!(interleaving(7), interleaving(7));
+        assert_ne!(interleaving(7), interleaving(8));
+    }
+}
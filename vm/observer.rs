@@ -0,0 +1,171 @@
+//! Execution observers.
+//!
+//! Observers attached with
+//! [Machine::add_observer](crate::Machine::add_observer) are called as the
+//! machine runs: before and after every instruction, on every data memory
+//! access, register write and output, and when an error is raised. Every
+//! callback does nothing by default, so that an observer only implements
+//! the ones it needs.
+//!
+//! For an instruction, the callbacks come in this order: [before_step],
+//! the accesses made by the instruction, then [after_step] if it succeeded
+//! or [fault] if it raised an error. Errors raised before the instruction
+//! is decoded only produce a [fault]. Interrupt entries produce the
+//! accesses they make, without step callbacks.
+//!
+//! Observers are not called by [Machine::step_back](crate::Machine::step_back)
+//! nor by the methods reading or writing the machine state from the host,
+//! such as [Machine::set_reg](crate::Machine::set_reg).
+//!
+//! [before_step]: Observer::before_step
+//! [after_step]: Observer::after_step
+//! [fault]: Observer::fault
+
+use std::{cell::RefCell, rc::Rc};
+
+use crate::{instruction::Instruction, machine::MachineError};
+
+/// Receiver of execution events.
+pub trait Observer {
+    /// The instruction located at `ip` is about to be executed.
+    fn before_step(&mut self, _ip: u32, _instruction: &Instruction) {}
+
+    /// The instruction located at `ip` has been executed successfully.
+    fn after_step(&mut self, _ip: u32, _instruction: &Instruction) {}
+
+    /// `bytes` were read from the memory or from a device at `addr`.
+    /// Instruction fetches are not reported.
+    fn mem_read(&mut self, _addr: u32, _bytes: &[u8]) {}
+
+    /// `bytes` were written to the memory or to a device at `addr`.
+    fn mem_write(&mut self, _addr: u32, _bytes: &[u8]) {}
+
+    /// Register `reg` went from `old` to `new`. The IP moving past the
+    /// executed instruction is not reported, jumps are.
+    fn reg_write(&mut self, _reg: u8, _old: u32, _new: u32) {}
+
+    /// `bytes` were printed.
+    fn output(&mut self, _bytes: &[u8]) {}
+
+    /// The machine raised `error`.
+    fn fault(&mut self, _error: &MachineError) {}
+}
+
+/// A shared observer, letting the host read what it collected while it is
+/// attached to a machine.
+impl<O: Observer> Observer for Rc<RefCell<O>> {
+    fn before_step(&mut self, ip: u32, instruction: &Instruction) {
+        self.borrow_mut().before_step(ip, instruction);
+    }
+
+    fn after_step(&mut self, ip: u32, instruction: &Instruction) {
+        self.borrow_mut().after_step(ip, instruction);
+    }
+
+    fn mem_read(&mut self, addr: u32, bytes: &[u8]) {
+        self.borrow_mut().mem_read(addr, bytes);
+    }
+
+    fn mem_write(&mut self, addr: u32, bytes: &[u8]) {
+        self.borrow_mut().mem_write(addr, bytes);
+    }
+
+    fn reg_write(&mut self, reg: u8, old: u32, new: u32) {
+        self.borrow_mut().reg_write(reg, old, new);
+    }
+
+    fn output(&mut self, bytes: &[u8]) {
+        self.borrow_mut().output(bytes);
+    }
+
+    fn fault(&mut self, error: &MachineError) {
+        self.borrow_mut().fault(error);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use super::*;
+    use crate::{assembler::assemble, machine::Machine};
+
+    /// Observer writing down every callback.
+    #[derive(Default)]
+    struct Recorder {
+        events: Vec<String>,
+    }
+
+    impl Observer for Recorder {
+        fn before_step(&mut self, ip: u32, _instruction: &Instruction) {
+            self.events.push(format!("before {ip}"));
+        }
+
+        fn after_step(&mut self, ip: u32, _instruction: &Instruction) {
+            self.events.push(format!("after {ip}"));
+        }
+
+        fn mem_read(&mut self, addr: u32, bytes: &[u8]) {
+            self.events.push(format!("read {addr:#x} {bytes:?}"));
+        }
+
+        fn mem_write(&mut self, addr: u32, bytes: &[u8]) {
+            self.events.push(format!("write {addr:#x} {bytes:?}"));
+        }
+
+        fn reg_write(&mut self, reg: u8, old: u32, new: u32) {
+            self.events.push(format!("r{reg} {old:#x} -> {new:#x}"));
+        }
+
+        fn output(&mut self, bytes: &[u8]) {
+            self.events.push(format!("output {bytes:?}"));
+        }
+
+        fn fault(&mut self, error: &MachineError) {
+            self.events.push(format!("fault {error:?}"));
+        }
+    }
+
+    #[test]
+    fn callbacks_come_in_order() {
+        let source = "
+            loadimm r1, 0x100
+            store   r1, r1
+            load    r2, r1
+            out     r1
+            loadimm r3, -2
+            load    r4, r3
+        ";
+        let mut machine = Machine::try_new(&assemble(source).unwrap().image).unwrap();
+        let recorder = Rc::new(RefCell::new(Recorder::default()));
+        machine.add_observer(recorder.clone());
+        let error = machine.run_on(&mut io::sink()).unwrap_err();
+        assert_eq!(
+            recorder.borrow().events,
+            [
+                "before 0".to_string(),
+                "r1 0x0 -> 0x100".to_string(),
+                "after 0".to_string(),
+                "before 4".to_string(),
+                "write 0x100 [0, 1, 0, 0]".to_string(),
+                "after 4".to_string(),
+                "before 7".to_string(),
+                "read 0x100 [0, 1, 0, 0]".to_string(),
+                "r2 0x0 -> 0x100".to_string(),
+                "after 7".to_string(),
+                "before 10".to_string(),
+                "output [0]".to_string(),
+                "after 10".to_string(),
+                "before 12".to_string(),
+                "r3 0x0 -> 0xfffffffe".to_string(),
+                "after 12".to_string(),
+                "before 16".to_string(),
+                format!("fault {error:?}"),
+            ]
+        );
+        assert!(matches!(
+            error,
+            MachineError::LoadReachEndOfMemory { ip: 16, .. }
+        ));
+    }
+}
@@ -0,0 +1,328 @@
+//! Execution profiling and code coverage.
+//!
+//! A [Profile] is an [Observer] counting how many times every address is
+//! executed, how many times every opcode is executed and which way every
+//! conditional jump goes. It is attached to a machine behind an
+//! `Rc<RefCell<_>>`, so that it can be read once the program has run:
+//!
+//! ```no_run
+//! use std::{cell::RefCell, rc::Rc};
+//! use vm::{assemble, Executable, Machine, Profile};
+//!
+//! let exe = Executable::from_assembled(&assemble("exit").unwrap());
+//! let mut machine = Machine::try_new(&[]).unwrap();
+//! machine.load_executable(&exe).unwrap();
+//! let profile = Rc::new(RefCell::new(Profile::new()));
+//! machine.add_observer(profile.clone());
+//! machine.run().unwrap();
+//! profile
+//!     .borrow()
+//!     .write_lcov(&mut std::io::stdout(), "prog.s", &exe)
+//!     .unwrap();
+//! ```
+//!
+//! The reports listing never executed instructions and untaken branches
+//! need the line table of an [Executable], which gives the address of every
+//! instruction.
+
+use std::{
+    collections::BTreeMap,
+    io::{self, Write},
+};
+
+use crate::{
+    executable::Executable, instruction::Instruction, machine::MachineError, observer::Observer,
+};
+
+/// Outcomes of a conditional jump.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BranchCount {
+    pub taken: u64,
+    pub not_taken: u64,
+}
+
+/// Execution counts collected while a machine runs.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Profile {
+    executions: BTreeMap<u32, u64>,
+    opcodes: BTreeMap<&'static str, u64>,
+    branches: BTreeMap<u32, BranchCount>,
+    /// Conditional jump being executed and whether it jumped.
+    pending: Option<(u32, bool)>,
+}
+
+/// `true` for the instructions which may or may not jump: `bz`, `bnz` and
+/// `move if` into the IP, which `jnz` expands to.
+fn is_conditional(instruction: &Instruction) -> bool {
+    matches!(
+        instruction,
+        Instruction::Bz(..) | Instruction::Bnz(..) | Instruction::MoveIf(0, ..)
+    )
+}
+
+/// Instruction located at `addr` in the segments of `exe`.
+fn instruction_at(exe: &Executable, addr: u32) -> Option<Instruction> {
+    let segment = exe.segments.iter().find(|segment| {
+        segment.addr <= addr && ((addr - segment.addr) as usize) < segment.data.len()
+    })?;
+    let bytes = &segment.data[(addr - segment.addr) as usize..];
+    // The register count of the machine is unknown, accept them all.
+    Instruction::decode_at(bytes, addr, 256)
+        .ok()
+        .map(|(instruction, _)| instruction)
+}
+
+impl Profile {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Number of executions of every executed address.
+    pub fn executions(&self) -> &BTreeMap<u32, u64> {
+        &self.executions
+    }
+
+    /// Number of executions of the instruction located at `addr`.
+    pub fn count(&self, addr: u32) -> u64 {
+        self.executions.get(&addr).copied().unwrap_or(0)
+    }
+
+    /// Number of executions of every executed opcode, by mnemonic.
+    pub fn opcodes(&self) -> &BTreeMap<&'static str, u64> {
+        &self.opcodes
+    }
+
+    /// Outcomes of every executed conditional jump, by address.
+    pub fn branches(&self) -> &BTreeMap<u32, BranchCount> {
+        &self.branches
+    }
+
+    /// Address and source line of the instructions of `exe` which were
+    /// never executed.
+    pub fn never_executed(&self, exe: &Executable) -> Vec<(u32, usize)> {
+        exe.lines
+            .iter()
+            .copied()
+            .filter(|&(addr, _)| self.count(addr) == 0)
+            .collect()
+    }
+
+    /// Write a human-readable report: executions by address, opcode
+    /// histogram, conditional jumps and, if `exe` has a line table, never
+    /// executed instructions.
+    pub fn write_text<W: Write>(&self, out: &mut W, exe: &Executable) -> io::Result<()> {
+        let lines: BTreeMap<u32, usize> = exe.lines.iter().copied().collect();
+        let line_of = |addr: &u32| {
+            lines
+                .get(addr)
+                .map(|line| format!("  line {line}"))
+                .unwrap_or_default()
+        };
+        writeln!(out, "executions by address:")?;
+        for (addr, count) in &self.executions {
+            writeln!(out, "  {addr:#06x} {count:>10}{}", line_of(addr))?;
+        }
+        writeln!(out, "executions by opcode:")?;
+        for (mnemonic, count) in &self.opcodes {
+            writeln!(out, "  {mnemonic:<12} {count:>10}")?;
+        }
+        writeln!(out, "conditional jumps:")?;
+        for (addr, branch) in &self.branches {
+            writeln!(
+                out,
+                "  {addr:#06x} taken {} not taken {}{}",
+                branch.taken,
+                branch.not_taken,
+                line_of(addr)
+            )?;
+        }
+        if !exe.lines.is_empty() {
+            writeln!(out, "never executed:")?;
+            for (addr, line) in self.never_executed(exe) {
+                writeln!(out, "  {addr:#06x}  line {line}")?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Write the coverage of the lines of `exe` in the lcov tracefile
+    /// format, `source` being the path of the assembler source. A line is
+    /// counted as many times as its first instruction. Every conditional
+    /// jump gives two branches, taken and not taken, its address being the
+    /// block number.
+    pub fn write_lcov<W: Write>(
+        &self,
+        out: &mut W,
+        source: &str,
+        exe: &Executable,
+    ) -> io::Result<()> {
+        let mut lines: BTreeMap<usize, u64> = BTreeMap::new();
+        let mut branches = Vec::new();
+        for &(addr, line) in &exe.lines {
+            lines.entry(line).or_insert_with(|| self.count(addr));
+            if instruction_at(exe, addr).is_some_and(|i| is_conditional(&i)) {
+                branches.push((line, addr, self.branches.get(&addr).copied()));
+            }
+        }
+        writeln!(out, "TN:")?;
+        writeln!(out, "SF:{source}")?;
+        let mut hit = 0;
+        for (line, addr, count) in &branches {
+            let (taken, not_taken) = match count {
+                Some(count) => (count.taken.to_string(), count.not_taken.to_string()),
+                None => ("-".to_string(), "-".to_string()),
+            };
+            writeln!(out, "BRDA:{line},{addr},0,{taken}")?;
+            writeln!(out, "BRDA:{line},{addr},1,{not_taken}")?;
+            if let Some(count) = count {
+                hit += (count.taken > 0) as usize + (count.not_taken > 0) as usize;
+            }
+        }
+        writeln!(out, "BRF:{}", 2 * branches.len())?;
+        writeln!(out, "BRH:{hit}")?;
+        for (line, count) in &lines {
+            writeln!(out, "DA:{line},{count}")?;
+        }
+        writeln!(out, "LF:{}", lines.len())?;
+        writeln!(
+            out,
+            "LH:{}",
+            lines.values().filter(|&&count| count > 0).count()
+        )?;
+        writeln!(out, "end_of_record")
+    }
+}
+
+impl Observer for Profile {
+    fn before_step(&mut self, ip: u32, instruction: &Instruction) {
+        *self.executions.entry(ip).or_default() += 1;
+        *self.opcodes.entry(instruction.mnemonic()).or_default() += 1;
+        self.pending = is_conditional(instruction).then_some((ip, false));
+    }
+
+    fn reg_write(&mut self, reg: u8, _old: u32, _new: u32) {
+        if let (0, Some((_, taken))) = (reg, &mut self.pending) {
+            *taken = true;
+        }
+    }
+
+    fn after_step(&mut self, _ip: u32, _instruction: &Instruction) {
+        if let Some((addr, taken)) = self.pending.take() {
+            let branch = self.branches.entry(addr).or_default();
+            if taken {
+                branch.taken += 1;
+            } else {
+                branch.not_taken += 1;
+            }
+        }
+    }
+
+    fn fault(&mut self, _error: &MachineError) {
+        self.pending = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, rc::Rc};
+
+    use super::*;
+    use crate::{assembler::assemble, machine::Machine};
+
+    /// The loop branch is taken once and not taken once, the `bz` always
+    /// jumps over the `bnz`, which never runs.
+    const SOURCE: &str = "loadimm r1, 2
+loop: loadimm r2, 1
+sub r1, r1, r2
+bnz r1, loop
+bz r1, done
+bnz r1, done
+done: exit
+";
+
+    fn profile() -> (Profile, Executable) {
+        let exe = Executable::from_assembled(&assemble(SOURCE).unwrap());
+        let mut machine = Machine::try_new(&[]).unwrap();
+        machine.load_executable(&exe).unwrap();
+        let profile = Rc::new(RefCell::new(Profile::new()));
+        machine.add_observer(profile.clone());
+        machine.run_on(&mut io::sink()).unwrap();
+        let profile = profile.borrow().clone();
+        (profile, exe)
+    }
+
+    #[test]
+    fn counts_executions_opcodes_and_branches() {
+        let (profile, exe) = profile();
+        let executions: Vec<(u32, u64)> =
+            profile.executions().iter().map(|(&a, &c)| (a, c)).collect();
+        assert_eq!(
+            executions,
+            [(0, 1), (4, 2), (8, 2), (12, 2), (16, 1), (24, 1)]
+        );
+        assert_eq!(profile.count(20), 0);
+        let opcodes: Vec<(&str, u64)> = profile.opcodes().iter().map(|(&m, &c)| (m, c)).collect();
+        assert_eq!(
+            opcodes,
+            [
+                ("bnz", 2),
+                ("bz", 1),
+                ("exit", 1),
+                ("loadimm", 3),
+                ("sub", 2)
+            ]
+        );
+        let branches: Vec<(u32, BranchCount)> =
+            profile.branches().iter().map(|(&a, &c)| (a, c)).collect();
+        assert_eq!(
+            branches,
+            [
+                (
+                    12,
+                    BranchCount {
+                        taken: 1,
+                        not_taken: 1
+                    }
+                ),
+                (
+                    16,
+                    BranchCount {
+                        taken: 1,
+                        not_taken: 0
+                    }
+                ),
+            ]
+        );
+        assert_eq!(profile.never_executed(&exe), [(20, 6)]);
+    }
+
+    #[test]
+    fn writes_lcov_tracefiles() {
+        let (profile, exe) = profile();
+        let mut lcov = Vec::new();
+        profile.write_lcov(&mut lcov, "prog.s", &exe).unwrap();
+        let expected = "\
+TN:
+SF:prog.s
+BRDA:4,12,0,1
+BRDA:4,12,1,1
+BRDA:5,16,0,1
+BRDA:5,16,1,0
+BRDA:6,20,0,-
+BRDA:6,20,1,-
+BRF:6
+BRH:3
+DA:1,1
+DA:2,2
+DA:3,2
+DA:4,2
+DA:5,1
+DA:6,0
+DA:7,1
+LF:7
+LH:6
+end_of_record
+";
+        assert_eq!(String::from_utf8(lcov).unwrap(), expected);
+    }
+}
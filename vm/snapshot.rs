@@ -0,0 +1,442 @@
+//! Snapshots of the whole state of a [Machine].
+//!
+//! A snapshot is made of a header (the `VMSNAP` magic, a 16-bit format
+//! version and the 64-bit length of the payload), the payload and a CRC-32
+//! of the payload. All numbers are little-endian. The payload holds, in this
+//! order:
+//!   - the configuration: memory size (`u64`), register count (`u32`),
+//!     sparse flag (`u8`)
+//!   - the alignment check flag (`u8`), the end of input flag (`u8`), the
+//!     input byte read ahead (`u8` presence flag and `u8` value) and the
+//!     stack limit (`u32`)
+//!   - the interrupt enable flag (`u8`), the mask of pending interrupts
+//!     (`u32`) and the address of the vector table (`u32`)
+//!   - the number of cycles spent (`u64`) and the instruction costs
+//!     differing from 1 (`u32` count, then for each one its opcode (`u8`)
+//!     and its cost (`u32`))
+//!   - the registers (`u32` each)
+//!   - the call stack (`u32` count, then the return addresses)
+//!   - the memory pages holding non-zero bytes (`u32` count, then for each
+//!     page its address, its length and its bytes)
+//!   - the mapped devices (`u32` count, then for each device its base
+//!     address, its size, the length of its state and its state)
+//!
+//! The tracer and the undo history are not part of the snapshot, and
+//! neither the configuration nor the devices are recreated by
+//! [Machine::restore]: the machine must have the configuration of the
+//! snapshot and the same devices mapped at the same addresses, and only
+//! their state is restored. If a device rejects its state, the devices
+//! restored before it get their previous state back.
+
+use std::{error::Error, fmt};
+
+use crate::{machine::Machine, memory::Memory};
+
+const MAGIC: &[u8; 6] = b"VMSNAP";
+const VERSION: u16 = 1;
+/// Size of the magic, version and payload length.
+const HEADER_SIZE: usize = 16;
+
+/// Error raised by [Machine::restore].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SnapshotError {
+    /// The data does not start with the snapshot magic.
+    BadMagic,
+    /// The snapshot was written in a format version this crate does not
+    /// know.
+    UnsupportedVersion(u16),
+    /// The data is shorter than announced by the snapshot.
+    Truncated,
+    /// The checksum does not match the payload.
+    BadChecksum,
+    /// The payload passed the checksum but describes an impossible state.
+    Corrupted(&'static str),
+    /// The snapshot was taken on a machine with another memory size,
+    /// register count or kind of memory.
+    ConfigMismatch,
+    /// The snapshot holds the state of a device which is not mapped at
+    /// `base` in the restored machine, or which rejected its state.
+    DeviceMismatch { base: u32 },
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            SnapshotError::BadMagic => write!(f, "not a machine snapshot"),
+            SnapshotError::UnsupportedVersion(version) => {
+                write!(f, "unsupported snapshot version {version}")
+            }
+            SnapshotError::Truncated => write!(f, "truncated snapshot"),
+            SnapshotError::BadChecksum => write!(f, "snapshot checksum mismatch"),
+            SnapshotError::Corrupted(what) => write!(f, "corrupted snapshot: {what}"),
+            SnapshotError::ConfigMismatch => {
+                write!(
+                    f,
+                    "the snapshot was taken with another machine configuration"
+                )
+            }
+            SnapshotError::DeviceMismatch { base } => write!(
+                f,
+                "the device of the snapshot at {base:#x} cannot be restored"
+            ),
+        }
+    }
+}
+
+impl Error for SnapshotError {}
+
+/// CRC-32 (IEEE 802.3) of `data`.
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+/// Little-endian reader over a payload.
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], SnapshotError> {
+        if len > self.data.len() {
+            return Err(SnapshotError::Truncated);
+        }
+        let (bytes, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, SnapshotError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn bool(&mut self) -> Result<bool, SnapshotError> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(SnapshotError::Corrupted("invalid flag")),
+        }
+    }
+
+    fn u32(&mut self) -> Result<u32, SnapshotError> {
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(self.bytes(4)?);
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    fn u64(&mut self) -> Result<u64, SnapshotError> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.bytes(8)?);
+        Ok(u64::from_le_bytes(bytes))
+    }
+}
+
+impl Machine {
+    /// Serialize the state of the machine and of its devices.
+    pub fn snapshot(&self) -> Vec<u8> {
+        let mut payload = Vec::new();
+        payload.extend_from_slice(&self.config.memory_size().to_le_bytes());
+        payload.extend_from_slice(&(self.config.registers() as u32).to_le_bytes());
+        payload.push(self.config.is_sparse() as u8);
+        payload.push(self.alignment_check as u8);
+        payload.push(self.input_eof as u8);
+        payload.push(self.input_peek.is_some() as u8);
+        payload.push(self.input_peek.unwrap_or(0));
+        payload.extend_from_slice(&self.stack_limit.to_le_bytes());
+        payload.push(self.interrupts_enabled as u8);
+        payload.extend_from_slice(&self.pending_interrupts.to_le_bytes());
+        payload.extend_from_slice(&self.interrupt_vector.to_le_bytes());
+        payload.extend_from_slice(&self.cycles.to_le_bytes());
+        let costs: Vec<(usize, u32)> = self
+            .costs
+            .iter()
+            .copied()
+            .enumerate()
+            .filter(|&(_, cost)| cost != 1)
+            .collect();
+        payload.extend_from_slice(&(costs.len() as u32).to_le_bytes());
+        for (opcode, cost) in costs {
+            payload.push(opcode as u8);
+            payload.extend_from_slice(&cost.to_le_bytes());
+        }
+        for reg in &self.regs {
+            payload.extend_from_slice(&reg.to_le_bytes());
+        }
+        payload.extend_from_slice(&(self.call_stack.len() as u32).to_le_bytes());
+        for addr in &self.call_stack {
+            payload.extend_from_slice(&addr.to_le_bytes());
+        }
+        let pages = self.mach_mem.pages(self.config.memory_size());
+        payload.extend_from_slice(&(pages.len() as u32).to_le_bytes());
+        for (addr, bytes) in pages {
+            payload.extend_from_slice(&addr.to_le_bytes());
+            payload.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+            payload.extend_from_slice(&bytes);
+        }
+        let devices = self.bus.states();
+        payload.extend_from_slice(&(devices.len() as u32).to_le_bytes());
+        for (base, size, state) in devices {
+            payload.extend_from_slice(&base.to_le_bytes());
+            payload.extend_from_slice(&size.to_le_bytes());
+            payload.extend_from_slice(&(state.len() as u32).to_le_bytes());
+            payload.extend_from_slice(&state);
+        }
+
+        let mut snapshot = Vec::with_capacity(HEADER_SIZE + payload.len() + 4);
+        snapshot.extend_from_slice(MAGIC);
+        snapshot.extend_from_slice(&VERSION.to_le_bytes());
+        snapshot.extend_from_slice(&(payload.len() as u64).to_le_bytes());
+        snapshot.extend_from_slice(&payload);
+        snapshot.extend_from_slice(&crc32(&payload).to_le_bytes());
+        snapshot
+    }
+
+    /// Replace the state of the machine by the one saved in `snapshot` by
+    /// [snapshot](Machine::snapshot). This machine must have the
+    /// configuration of the snapshot, and its devices mapped at the same
+    /// addresses. The machine is left unchanged if the snapshot cannot
+    /// be decoded.
+    pub fn restore(&mut self, snapshot: &[u8]) -> Result<(), SnapshotError> {
+        let mut reader = Reader { data: snapshot };
+        if reader.bytes(MAGIC.len()).ok() != Some(MAGIC.as_slice()) {
+            return Err(SnapshotError::BadMagic);
+        }
+        let version = u16::from_le_bytes([reader.u8()?, reader.u8()?]);
+        if version != VERSION {
+            return Err(SnapshotError::UnsupportedVersion(version));
+        }
+        let len = usize::try_from(reader.u64()?).map_err(|_| SnapshotError::Truncated)?;
+        let payload = reader.bytes(len)?;
+        if reader.u32()? != crc32(payload) {
+            return Err(SnapshotError::BadChecksum);
+        }
+
+        let mut reader = Reader { data: payload };
+        let memory_size = reader.u64()?;
+        let registers = reader.u32()? as usize;
+        let sparse = reader.bool()?;
+        // Checked before anything is allocated for the snapshot.
+        if (memory_size, registers, sparse)
+            != (
+                self.config.memory_size(),
+                self.config.registers(),
+                self.config.is_sparse(),
+            )
+        {
+            return Err(SnapshotError::ConfigMismatch);
+        }
+        let alignment_check = reader.bool()?;
+        let input_eof = reader.bool()?;
+        let has_peek = reader.bool()?;
+        let peek = reader.u8()?;
+        let stack_limit = reader.u32()?;
+        let interrupts_enabled = reader.bool()?;
+        let pending_interrupts = reader.u32()?;
+        let interrupt_vector = reader.u32()?;
+        let cycles = reader.u64()?;
+        let mut costs = vec![1; 256];
+        for _ in 0..reader.u32()? {
+            let opcode = reader.u8()?;
+            costs[opcode as usize] = reader.u32()?;
+        }
+        if costs.contains(&0) {
+            return Err(SnapshotError::Corrupted("instruction cost of 0"));
+        }
+        let regs = (0..registers)
+            .map(|_| reader.u32())
+            .collect::<Result<Vec<u32>, SnapshotError>>()?;
+        let calls = reader.u32()?;
+        let call_stack = (0..calls)
+            .map(|_| reader.u32())
+            .collect::<Result<Vec<u32>, SnapshotError>>()?;
+        let pages = reader.u32()?;
+        let mut memory = Memory::new(memory_size, sparse);
+        for _ in 0..pages {
+            let addr = reader.u32()?;
+            let len = reader.u32()?;
+            if addr as u64 + len as u64 > memory_size {
+                return Err(SnapshotError::Corrupted("page outside of the memory"));
+            }
+            memory.write(addr, reader.bytes(len as usize)?);
+        }
+        let count = reader.u32()?;
+        let mut devices = Vec::new();
+        for _ in 0..count {
+            let base = reader.u32()?;
+            let size = reader.u32()?;
+            let len = reader.u32()?;
+            let state = reader.bytes(len as usize)?;
+            if !self.bus.is_mapped(base, size) {
+                return Err(SnapshotError::DeviceMismatch { base });
+            }
+            devices.push((base, state));
+        }
+        if !reader.data.is_empty() {
+            return Err(SnapshotError::Corrupted("trailing bytes in the payload"));
+        }
+
+        self.bus
+            .restore_states(&devices)
+            .map_err(|base| SnapshotError::DeviceMismatch { base })?;
+        self.mach_mem = memory;
+        self.regs = regs;
+        self.input_peek = has_peek.then_some(peek);
+        self.input_replay.clear();
+        self.history.clear();
+        self.input_eof = input_eof;
+        self.stack_limit = stack_limit;
+        self.interrupts_enabled = interrupts_enabled;
+        self.pending_interrupts = pending_interrupts;
+        self.interrupt_vector = interrupt_vector;
+        self.call_stack = call_stack;
+        self.alignment_check = alignment_check;
+        self.cycles = cycles;
+        self.costs = costs;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use super::*;
+    use crate::{
+        config::MachineConfig,
+        device::{Device, Timer},
+    };
+
+    /// Device of the size of a [Timer], without state.
+    struct Blank;
+
+    impl Device for Blank {
+        fn size(&self) -> u32 {
+            8
+        }
+        fn read(&mut self, _offset: u32, _width: usize) -> io::Result<u32> {
+            Ok(0)
+        }
+        fn write(&mut self, _offset: u32, _width: usize, _value: u32) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn assemble(source: &str) -> Machine {
+        Machine::try_new(&crate::assembler::assemble(source).unwrap().image).unwrap()
+    }
+
+    #[test]
+    fn registers_memory_and_call_stack_round_trip() {
+        let source = "
+            loadimm sp, 4096
+            call    f
+            exit
+        f:  loadimm r1, 0x400
+            loadimm r2, 42
+            store   r1, r2
+            out number r2
+            ret
+        ";
+        let mut machine = assemble(source);
+        for _ in 0..5 {
+            machine.step_on(&mut io::sink()).unwrap();
+        }
+        let snapshot = machine.snapshot();
+
+        let mut copy = Machine::try_new(&[]).unwrap();
+        copy.restore(&snapshot).unwrap();
+        assert_eq!(copy.regs(), machine.regs());
+        assert_eq!(copy.call_stack, [7]);
+        assert_eq!(copy.memory(), machine.memory());
+        assert_eq!(copy.read_memory(0x400, 4).unwrap(), [42, 0, 0, 0]);
+        let mut output = Vec::new();
+        copy.run_on(&mut output).unwrap();
+        assert_eq!(output, b"42");
+        assert!(copy.call_stack.is_empty());
+    }
+
+    #[test]
+    fn invalid_snapshots_are_errors() {
+        let mut machine = assemble("loadimm r1, 7\nexit");
+        machine.step_on(&mut io::sink()).unwrap();
+        let snapshot = machine.snapshot();
+        let mut target = Machine::try_new(&[1, 2, 3]).unwrap();
+        let mut restore = |data: &[u8]| target.restore(data);
+
+        assert_eq!(restore(b"VMSNAQ"), Err(SnapshotError::BadMagic));
+        let mut other_version = snapshot.clone();
+        other_version[6] = 2;
+        assert_eq!(
+            restore(&other_version),
+            Err(SnapshotError::UnsupportedVersion(2))
+        );
+        assert_eq!(
+            restore(&snapshot[..snapshot.len() - 1]),
+            Err(SnapshotError::Truncated)
+        );
+        assert_eq!(restore(&snapshot[..10]), Err(SnapshotError::Truncated));
+        let mut flipped = snapshot.clone();
+        flipped[HEADER_SIZE + 20] ^= 1;
+        assert_eq!(restore(&flipped), Err(SnapshotError::BadChecksum));
+        assert_eq!(target.memory()[..3], [1, 2, 3]);
+
+        let mut larger = MachineConfig::new()
+            .with_memory_size(8192)
+            .unwrap()
+            .build(&[])
+            .unwrap();
+        assert_eq!(
+            larger.restore(&snapshot),
+            Err(SnapshotError::ConfigMismatch)
+        );
+        assert_eq!(larger.config().memory_size(), 8192);
+    }
+
+    #[test]
+    fn cycles_and_costs_are_restored() {
+        // sub r1, r1, r0; exit
+        let mut machine = Machine::try_new(&[5, 1, 1, 0, 7]).unwrap();
+        machine.set_cost(5, 10).unwrap();
+        machine.step_on(&mut io::sink()).unwrap();
+        let snapshot = machine.snapshot();
+
+        let mut copy = Machine::try_new(&[]).unwrap();
+        copy.restore(&snapshot).unwrap();
+        assert_eq!(copy.cycles(), 10);
+        assert_eq!(copy.regs(), machine.regs());
+        copy.set_reg(0, 0).unwrap();
+        copy.step_on(&mut io::sink()).unwrap();
+        assert_eq!(copy.cycles(), 20);
+    }
+
+    #[test]
+    fn failed_device_restores_leave_the_devices_alone() {
+        let mut machine = Machine::try_new(&[]).unwrap();
+        machine
+            .map_device(0x800, Timer::new(0, 5).unwrap())
+            .unwrap();
+        machine.map_device(0x900, Blank).unwrap();
+        let snapshot = machine.snapshot();
+
+        let mut other = Machine::try_new(&[7]).unwrap();
+        other.map_device(0x800, Timer::new(0, 9).unwrap()).unwrap();
+        other.map_device(0x900, Timer::new(1, 3).unwrap()).unwrap();
+        assert_eq!(
+            other.restore(&snapshot),
+            Err(SnapshotError::DeviceMismatch { base: 0x900 })
+        );
+        let mut timer = other.unmap_device(0x800).unwrap();
+        assert_eq!(timer.read(0, 4).unwrap(), 9);
+        assert_eq!(other.memory()[0], 7);
+    }
+}
@@ -0,0 +1,311 @@
+//! Per-instruction execution tracing.
+//!
+//! When a [Tracer] is attached with
+//! [Machine::set_tracer](crate::Machine::set_tracer), every executed
+//! instruction and every interrupt entry produces a [TraceRecord]
+//! describing its effects. [WriteTracer]
+//! writes those records to any [Write], either as human-readable lines or as
+//! JSON Lines.
+
+use std::{
+    fmt::Write as _,
+    io::{self, Write},
+};
+
+use crate::{instruction::Instruction, machine::MachineError};
+
+/// A register written by an instruction.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RegWrite {
+    pub reg: u8,
+    pub old: u32,
+    pub new: u32,
+}
+
+/// Memory bytes written by an instruction, starting at `addr`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MemWrite {
+    pub addr: u32,
+    /// Previous bytes, empty for a device write: reading a device may have
+    /// side effects.
+    pub old: Vec<u8>,
+    pub new: Vec<u8>,
+    /// `true` if the bytes were written to a memory-mapped device.
+    pub device: bool,
+}
+
+/// Effects of one executed instruction or interrupt entry.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceRecord {
+    /// Address of the instruction, i.e. IP before its execution. For an
+    /// interrupt entry, address of the interrupted instruction.
+    pub ip: u32,
+    /// Executed instruction, `None` for an interrupt entry.
+    pub instruction: Option<Instruction>,
+    /// Interrupt line taken, for an interrupt entry.
+    pub interrupt: Option<u8>,
+    pub reg_writes: Vec<RegWrite>,
+    pub mem_writes: Vec<MemWrite>,
+    /// Bytes consumed from the input by the instruction.
+    pub input: Vec<u8>,
+    /// Bytes printed by the instruction.
+    pub output: Vec<u8>,
+    /// Error raised while executing the instruction, if any.
+    pub fault: Option<MachineError>,
+}
+
+/// Receiver of trace records.
+pub trait Tracer {
+    fn trace(&mut self, record: &TraceRecord) -> io::Result<()>;
+}
+
+impl<F: FnMut(&TraceRecord) -> io::Result<()>> Tracer for F {
+    fn trace(&mut self, record: &TraceRecord) -> io::Result<()> {
+        self(record)
+    }
+}
+
+/// Output format of a [WriteTracer].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TraceFormat {
+    /// One human-readable line per instruction.
+    Text,
+    /// One JSON object per line.
+    JsonLines,
+}
+
+/// Tracer writing records to `W` in the chosen format.
+pub struct WriteTracer<W: Write> {
+    out: W,
+    format: TraceFormat,
+}
+
+impl<W: Write> WriteTracer<W> {
+    pub fn new(out: W, format: TraceFormat) -> Self {
+        WriteTracer { out, format }
+    }
+
+    /// Give back the underlying writer.
+    pub fn into_inner(self) -> W {
+        self.out
+    }
+}
+
+impl<W: Write> Tracer for WriteTracer<W> {
+    fn trace(&mut self, record: &TraceRecord) -> io::Result<()> {
+        let line = match self.format {
+            TraceFormat::Text => text_line(record),
+            TraceFormat::JsonLines => json_line(record),
+        };
+        writeln!(self.out, "{line}")
+    }
+}
+
+fn hex_bytes(bytes: &[u8]) -> String {
+    let hex: Vec<String> = bytes.iter().map(|b| format!("{b:02x}")).collect();
+    hex.join(" ")
+}
+
+/// Executed instruction, or interrupt taken, in assembler syntax.
+fn event(record: &TraceRecord) -> String {
+    match (&record.instruction, record.interrupt) {
+        (Some(instruction), _) => instruction.to_string(),
+        (None, Some(line)) => format!("interrupt {line}"),
+        (None, None) => String::new(),
+    }
+}
+
+fn text_line(record: &TraceRecord) -> String {
+    let mut line = format!("{:#06x}  {:<20}", record.ip, event(record));
+    for w in &record.reg_writes {
+        let _ = write!(line, " r{}={:#010x} (was {:#010x})", w.reg, w.new, w.old);
+    }
+    for w in &record.mem_writes {
+        let _ = write!(line, " [{:#06x}]={}", w.addr, hex_bytes(&w.new));
+        if w.device {
+            line.push_str(" (device)");
+        } else {
+            let _ = write!(line, " (was {})", hex_bytes(&w.old));
+        }
+    }
+    if !record.input.is_empty() {
+        let _ = write!(line, " input={:?}", String::from_utf8_lossy(&record.input));
+    }
+    if !record.output.is_empty() {
+        let _ = write!(
+            line,
+            " output={:?}",
+            String::from_utf8_lossy(&record.output)
+        );
+    }
+    if let Some(fault) = &record.fault {
+        let _ = write!(line, " fault: {fault}");
+    }
+    line.trim_end().to_string()
+}
+
+/// Quote `s` as a JSON string.
+pub fn json_string(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+fn json_bytes(bytes: &[u8]) -> String {
+    let values: Vec<String> = bytes.iter().map(|b| b.to_string()).collect();
+    format!("[{}]", values.join(","))
+}
+
+fn json_line(record: &TraceRecord) -> String {
+    let regs: Vec<String> = record
+        .reg_writes
+        .iter()
+        .map(|w| format!("{{\"reg\":{},\"old\":{},\"new\":{}}}", w.reg, w.old, w.new))
+        .collect();
+    let mem: Vec<String> = record
+        .mem_writes
+        .iter()
+        .map(|w| {
+            format!(
+                "{{\"addr\":{},\"old\":{},\"new\":{},\"device\":{}}}",
+                w.addr,
+                json_bytes(&w.old),
+                json_bytes(&w.new),
+                w.device
+            )
+        })
+        .collect();
+    let event = match (&record.instruction, record.interrupt) {
+        (None, Some(line)) => format!("\"interrupt\":{line}"),
+        _ => format!("\"instr\":{}", json_string(&event(record))),
+    };
+    let mut line = format!(
+        "{{\"ip\":{},{event},\"regs\":[{}],\"mem\":[{}],\"input\":{},\"output\":{}",
+        record.ip,
+        regs.join(","),
+        mem.join(","),
+        json_string(&String::from_utf8_lossy(&record.input)),
+        json_string(&String::from_utf8_lossy(&record.output)),
+    );
+    if let Some(fault) = &record.fault {
+        let _ = write!(line, ",\"fault\":{}", json_string(&fault.to_string()));
+    }
+    line.push('}');
+    line
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, rc::Rc};
+
+    use super::*;
+    use crate::{assembler::assemble, device::Random, machine::Machine};
+
+    fn traced(source: &str, setup: impl FnOnce(&mut Machine)) -> Vec<TraceRecord> {
+        let mut machine = Machine::try_new(&assemble(source).unwrap().image).unwrap();
+        setup(&mut machine);
+        let records = Rc::new(RefCell::new(Vec::new()));
+        let sink = records.clone();
+        machine.set_tracer(move |record: &TraceRecord| {
+            sink.borrow_mut().push(record.clone());
+            Ok(())
+        });
+        machine.run_on(&mut io::sink()).unwrap();
+        let records = records.borrow().clone();
+        records
+    }
+
+    #[test]
+    fn records_memory_and_register_writes() {
+        let records = traced(
+            "loadimm r1, 0x100\nloadimm r2, 7\nstoreb r1, r2\nexit",
+            |_| {},
+        );
+        assert_eq!(records.len(), 4);
+        assert_eq!(records[2].instruction, Some(Instruction::StoreB(1, 2)));
+        assert_eq!(
+            records[2].mem_writes,
+            [MemWrite {
+                addr: 0x100,
+                old: vec![0],
+                new: vec![7],
+                device: false
+            }]
+        );
+        assert_eq!(
+            records[1].reg_writes,
+            [RegWrite {
+                reg: 2,
+                old: 0,
+                new: 7
+            }]
+        );
+        assert_eq!(
+            text_line(&records[2]),
+            "0x0008  storeb r1, r2        [0x0100]=07 (was 00)"
+        );
+    }
+
+    #[test]
+    fn records_device_writes() {
+        let records = traced(
+            "loadimm r1, 0x800\nloadimm r2, 5\nstore r1, r2\nexit",
+            |m| m.map_device(0x800, Random::new(1)).unwrap(),
+        );
+        let write = MemWrite {
+            addr: 0x800,
+            old: Vec::new(),
+            new: vec![5, 0, 0, 0],
+            device: true,
+        };
+        assert_eq!(records[2].mem_writes, [write]);
+        assert!(text_line(&records[2]).ends_with("[0x0800]=05 00 00 00 (device)"));
+        assert!(json_line(&records[2]).contains("\"old\":[],\"new\":[5,0,0,0],\"device\":true"));
+    }
+
+    #[test]
+    fn records_interrupt_entries() {
+        let source = "loadimm sp, 0x400\n\
+                      ei\n\
+                      exit\n\
+                      handler: exit\n\
+                      .org 0x100\n\
+                      .word 0, 0, handler";
+        let records = traced(source, |m| {
+            m.set_interrupt_vector(0x100);
+            m.raise_interrupt(2).unwrap();
+        });
+        let entry = &records[2];
+        assert_eq!(
+            (entry.ip, entry.instruction, entry.interrupt),
+            (5, None, Some(2))
+        );
+        assert_eq!(
+            entry.mem_writes,
+            [MemWrite {
+                addr: 0x3fc,
+                old: vec![0; 4],
+                new: vec![5, 0, 0, 0],
+                device: false
+            }]
+        );
+        let regs: Vec<(u8, u32)> = entry.reg_writes.iter().map(|w| (w.reg, w.new)).collect();
+        assert_eq!(regs, [(15, 0x3fc), (0, 6)]);
+        assert!(text_line(entry).starts_with("0x0005  interrupt 2"));
+        assert!(json_line(entry).starts_with("{\"ip\":5,\"interrupt\":2,"));
+        assert_eq!(records[3].instruction, Some(Instruction::Exit));
+    }
+}
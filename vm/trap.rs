@@ -0,0 +1,204 @@
+//! Host services reached through the `trap n` instruction.
+//!
+//! A [SyscallHandler] attached with
+//! [Machine::set_trap_handler](crate::Machine::set_trap_handler) is called
+//! by every `trap` instruction with the trap number and a [TrapContext]
+//! giving access to the registers and the memory. Its changes are traced
+//! and recorded in the undo history like the ones of any instruction.
+
+use crate::machine::{Machine, MachineError};
+
+/// What the machine does after a trap has been handled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrapAction {
+    /// Go on with the instruction following the `trap`.
+    Continue,
+    /// Terminate the program, like `exit`.
+    Exit,
+}
+
+/// Handler of the `trap` instructions. An error message makes the `trap`
+/// raise [MachineError::TrapFailed].
+pub trait SyscallHandler {
+    fn trap(&mut self, number: u8, context: &mut TrapContext<'_>) -> Result<TrapAction, String>;
+}
+
+impl<F: FnMut(u8, &mut TrapContext<'_>) -> Result<TrapAction, String>> SyscallHandler for F {
+    fn trap(&mut self, number: u8, context: &mut TrapContext<'_>) -> Result<TrapAction, String> {
+        self(number, context)
+    }
+}
+
+/// Registers and memory of the machine executing a `trap`.
+pub struct TrapContext<'a> {
+    pub(crate) machine: &'a mut Machine,
+}
+
+impl TrapContext<'_> {
+    /// Value of register `reg`.
+    pub fn reg(&self, reg: usize) -> Result<u32, MachineError> {
+        self.machine
+            .regs()
+            .get(reg)
+            .copied()
+            .ok_or(MachineError::UnknownRegister { reg })
+    }
+
+    /// Set register `reg` to `value`.
+    pub fn set_reg(&mut self, reg: usize, value: u32) -> Result<(), MachineError> {
+        if reg >= self.machine.regs().len() {
+            return Err(MachineError::UnknownRegister { reg });
+        }
+        self.machine.write_reg(reg as u8, value);
+        Ok(())
+    }
+
+    /// Copy `len` bytes of memory starting at `addr`. Like the accesses of
+    /// `load`, the read is seen by the observers and the watchpoints.
+    pub fn read_memory(&mut self, addr: usize, len: usize) -> Result<Vec<u8>, MachineError> {
+        if (addr as u64).saturating_add(len as u64) > self.machine.config().memory_size() {
+            return Err(MachineError::UnknownAddress { addr });
+        }
+        let mut bytes = vec![0; len];
+        self.machine.read_mem_into(addr as u32, &mut bytes);
+        Ok(bytes)
+    }
+
+    /// Copy `data` in memory starting at `addr`. Like the accesses of
+    /// `store`, the write is traced and seen by the observers and the
+    /// watchpoints.
+    pub fn write_memory(&mut self, addr: usize, data: &[u8]) -> Result<(), MachineError> {
+        if (addr as u64).saturating_add(data.len() as u64) > self.machine.config().memory_size() {
+            return Err(MachineError::UnknownAddress { addr });
+        }
+        self.machine.write_mem(addr as u32, data);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use super::*;
+    use crate::{assembler::assemble, breakpoint::Condition};
+
+    /// Machine loaded with [SOURCE].
+    fn program() -> Machine {
+        Machine::try_new(&assemble(SOURCE).unwrap().image).unwrap()
+    }
+
+    /// Trap 1 stores the sum of the `r2` bytes found at `r1` into `r3` and
+    /// after the bytes; trap 2 exits.
+    fn sum(number: u8, context: &mut TrapContext<'_>) -> Result<TrapAction, String> {
+        if number == 2 {
+            return Ok(TrapAction::Exit);
+        }
+        let addr = context.reg(1).unwrap() as usize;
+        let len = context.reg(2).unwrap() as usize;
+        let bytes = context.read_memory(addr, len).map_err(|e| e.to_string())?;
+        let sum = bytes.iter().map(|&b| b as u32).sum();
+        context.set_reg(3, sum).unwrap();
+        context
+            .write_memory(addr + len, &[sum as u8])
+            .map_err(|e| e.to_string())?;
+        Ok(TrapAction::Continue)
+    }
+
+    const SOURCE: &str = "
+        loadimm r1, 0x100
+        loadimm r2, 3
+        trap    1
+        out number r3
+        trap    2
+        out number r3
+        .org 0x100
+        .byte 1
+        .byte 2
+        .byte 3
+    ";
+
+    #[test]
+    fn handlers_access_registers_and_memory() {
+        let mut machine = program();
+        machine.set_trap_handler(sum);
+        let mut output = Vec::new();
+        machine.run_on(&mut output).unwrap();
+        assert_eq!(output, b"6");
+        assert_eq!(machine.regs()[3], 6);
+        assert_eq!(machine.read_memory(0x100, 4).unwrap(), [1, 2, 3, 6]);
+
+        let mut machine = program();
+        machine.set_trap_handler(|_: u8, context: &mut TrapContext<'_>| {
+            assert_eq!(
+                context.reg(16),
+                Err(MachineError::UnknownRegister { reg: 16 })
+            );
+            assert_eq!(
+                context.read_memory(4095, 2),
+                Err(MachineError::UnknownAddress { addr: 4095 })
+            );
+            assert_eq!(
+                context.write_memory(4096, &[1]),
+                Err(MachineError::UnknownAddress { addr: 4096 })
+            );
+            Ok(TrapAction::Exit)
+        });
+        machine.run_on(&mut io::sink()).unwrap();
+    }
+
+    #[test]
+    fn trap_memory_accesses_meet_watchpoints() {
+        for (condition, addr) in [
+            (
+                Condition::Read {
+                    addr: 0x102,
+                    len: 1,
+                },
+                0x100,
+            ),
+            (
+                Condition::Write {
+                    addr: 0x103,
+                    len: 1,
+                },
+                0x103,
+            ),
+        ] {
+            let mut machine = program();
+            machine.set_trap_handler(sum);
+            machine.add_breakpoint(condition);
+            machine.run_on(&mut io::sink()).unwrap();
+            let hit = machine.last_break().unwrap();
+            assert_eq!((hit.ip, hit.addr), (8, Some(addr)));
+        }
+    }
+
+    #[test]
+    fn failed_and_unhandled_traps_fault() {
+        let mut machine = program();
+        assert_eq!(
+            machine.run_on(&mut io::sink()),
+            Err(MachineError::UnhandledTrap {
+                ip: 8,
+                opcode: 47,
+                number: 1
+            })
+        );
+
+        let mut machine = program();
+        machine.set_trap_handler(|number: u8, _: &mut TrapContext<'_>| {
+            Err(format!("no service {number}"))
+        });
+        assert_eq!(
+            machine.run_on(&mut io::sink()),
+            Err(MachineError::TrapFailed {
+                ip: 8,
+                opcode: 47,
+                number: 1,
+                message: "no service 1".to_string()
+            })
+        );
+        assert_eq!(machine.regs()[0], 10);
+    }
+}